@@ -1,29 +1,199 @@
+use crate::approval::ApprovalPolicy;
+use crate::limits::{self, Usage};
+use crate::rules::{RuleItem, RuleKind, RuleMatch};
+use crate::sandbox::SandboxProfile;
+use crate::shell::{self, Invocation};
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::io::{Read, Write};
 use std::path::Path;
 
-const ACCESS_PATH: &str = ".notionSSH/access.json";
+pub const ACCESS_PATH: &str = ".notionSSH/access.json";
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PermRule {
-    pub allow: Vec<String>,
-    pub deny: Vec<String>,
+    #[serde(default)]
+    pub allow: Vec<RuleItem>,
+    #[serde(default)]
+    pub deny: Vec<RuleItem>,
+    /// Roles whose rules and settings this role inherits.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extends: Vec<String>,
+    /// Upper bound on the command timeout for this role, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_timeout_secs: Option<u64>,
+    /// Local account this role's commands run as.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_as: Option<RunAs>,
+    /// Name of the entry in `sandbox_profiles` this role's commands run in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<String>,
+    /// Directory this role's commands start in until the requester `cd`s.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    /// Name the deciding rule and its `reason` in denial messages posted to
+    /// Notion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reveal: Option<bool>,
+    /// IANA time zone (e.g. "Asia/Seoul") for `time_windows` and the day
+    /// boundary of `daily_quota`; the agent's local time when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// When this role may run commands; any time when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub time_windows: Vec<TimeWindow>,
+    /// Commands a requester may start per minute and per hour.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    /// Commands a requester may start per calendar day.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_quota: Option<u32>,
+}
+
+/// Days and hours in which commands may run, e.g. weekdays 09:00-18:00.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// "mon" ... "sun"; every day when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+    /// "HH:MM", inclusive.
+    pub start: String,
+    /// "HH:MM", exclusive. Earlier than `start` for windows past midnight,
+    /// which then belong to the day they start on.
+    pub end: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RateLimit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_minute: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_hour: Option<u32>,
+}
+
+/// Unix user (and optionally group) a command is run as.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RunAs {
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// One role name or several, as written in `emails` and `groups`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Roles {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Roles {
+    pub fn names(&self) -> &[String] {
+        match self {
+            Roles::One(name) => std::slice::from_ref(name),
+            Roles::Many(names) => names,
+        }
+    }
+}
+
+impl From<&str> for Roles {
+    fn from(s: &str) -> Self {
+        Roles::One(s.to_string())
+    }
+}
+
+/// What happens to a command block edited by someone other than its author.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EditedBlocks {
+    /// The command is refused.
+    #[default]
+    Reject,
+    /// The command runs only if the editor may run it too.
+    Stricter,
+}
+
+impl EditedBlocks {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Roles for requesters that have no email: Notion bots (integrations) and
+/// users whose email cannot be read, such as guests. "deny" refuses their
+/// commands. Bots and users listed by their Notion user ID are not covered.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Unidentified {
+    #[serde(default = "default_unidentified")]
+    pub bots: String,
+    #[serde(default = "default_unidentified")]
+    pub unknown: String,
+}
+
+/// Requester name of users that could not be identified.
+pub const UNKNOWN_REQUESTER: &str = "unknown";
+/// Requester names of bots start with this, followed by their user ID.
+pub const BOT_PREFIX: &str = "bot:";
+const DENY: &str = "deny";
+
+fn default_unidentified() -> String {
+    DENY.to_string()
+}
+
+impl Default for Unidentified {
+    fn default() -> Self {
+        Self { bots: default_unidentified(), unknown: default_unidentified() }
+    }
+}
+
+impl Unidentified {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Named set of requesters sharing roles.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Group {
+    pub members: Vec<String>,
+    pub roles: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccessFile {
-    /// Map of email -> role name (e.g., "perm_user", "default")
+    /// Map of email -> role name or list of role names (e.g., "perm_user", "default")
     #[serde(default)]
-    pub emails: HashMap<String, String>,
+    pub emails: HashMap<String, Roles>,
+    /// Groups by name; members get the group's roles after their own
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub groups: HashMap<String, Group>,
     /// List of manager emails who are always allowed
     #[serde(default)]
     pub perm_manager: Vec<String>,
     /// Permissions by role name. Must include "default"
     pub perms: HashMap<String, PermRule>,
+    /// Map of email -> Unix account, taking precedence over the role's `run_as`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub run_as: HashMap<String, RunAs>,
+    /// Sandbox profiles by name, referenced from a role's `sandbox`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sandbox_profiles: HashMap<String, SandboxProfile>,
+    /// Commands that wait for a second person's approval before running
+    #[serde(default, skip_serializing_if = "ApprovalPolicy::is_default")]
+    pub approval: ApprovalPolicy,
+    /// Commands whose block someone else edited after it was written
+    #[serde(default, skip_serializing_if = "EditedBlocks::is_default")]
+    pub edited_blocks: EditedBlocks,
+    /// What bots and unidentified users may do
+    #[serde(default, skip_serializing_if = "Unidentified::is_default")]
+    pub unidentified: Unidentified,
+    /// Exempt `perm_manager` from time windows, rate limits and quotas
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub managers_skip_limits: bool,
 }
 
 impl Default for AccessFile {
@@ -32,11 +202,32 @@ impl Default for AccessFile {
         perms.insert(
             "default".to_string(),
             PermRule {
-                allow: vec!["*".to_string()],
+                allow: vec!["*".into()],
                 deny: vec![],
+                extends: vec![],
+                max_timeout_secs: None,
+                run_as: None,
+                sandbox: None,
+                working_dir: None,
+                reveal: None,
+                timezone: None,
+                time_windows: vec![],
+                rate_limit: None,
+                daily_quota: None,
             },
         );
-        Self { emails: HashMap::new(), perm_manager: Vec::new(), perms }
+        Self {
+            emails: HashMap::new(),
+            groups: HashMap::new(),
+            perm_manager: Vec::new(),
+            perms,
+            run_as: HashMap::new(),
+            sandbox_profiles: HashMap::new(),
+            approval: ApprovalPolicy::default(),
+            edited_blocks: EditedBlocks::default(),
+            unidentified: Unidentified::default(),
+            managers_skip_limits: false,
+        }
     }
 }
 
@@ -56,60 +247,951 @@ pub fn load_or_create() -> Result<AccessFile> {
         return Ok(default);
     }
 
+    load_from_path(path)
+}
+
+/// Reads and validates an existing access file.
+pub fn load_from_path(path: &Path) -> Result<AccessFile> {
+    parse(&read(path)?)
+}
+
+fn read(path: &Path) -> Result<String> {
     let mut s = String::new();
     fs::File::open(path)?.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+fn parse(s: &str) -> Result<AccessFile> {
     if s.trim().is_empty() {
         return Err(anyhow!("access.json is empty"));
     }
-    let af: AccessFile = serde_json::from_str(&s)?;
+    let af: AccessFile = serde_json::from_str(s)?;
     // Ensure default exists
     if !af.perms.contains_key("default") {
         return Err(anyhow!("access.json missing required 'perms.default' rule"));
     }
+    validate(&af)?;
     Ok(af)
 }
 
-fn first_token(cmd: &str) -> &str {
-    cmd.split_whitespace().next().unwrap_or("")
+/// Checks every rule item so a broken regex or glob is reported at load
+/// instead of silently never matching.
+pub fn validate(af: &AccessFile) -> Result<()> {
+    for (role, rules) in &af.perms {
+        for item in rules.allow.iter().chain(&rules.deny) {
+            item.validate().map_err(|e| anyhow!("perms.{}: {}", role, e))?;
+        }
+        limits::validate(rules).map_err(|e| anyhow!("perms.{}: {}", role, e))?;
+    }
+    for item in &af.approval.commands {
+        item.validate().map_err(|e| anyhow!("approval.commands: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Something in an access file that loads but probably does not do what
+/// was meant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintWarning {
+    /// Where in the file, e.g. `emails.bob@example.com` or `perms.ops.allow`.
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Loads the access file at `path` and lints it, including emails listed
+/// twice, which parsing alone cannot see. Load errors are returned as `Err`.
+pub fn lint_file(path: &Path) -> Result<Vec<LintWarning>> {
+    let text = read(path)?;
+    let af = parse(&text)?;
+    let mut warnings = Vec::new();
+    let EmailKeys(keys) = serde_json::from_str::<RawEmails>(&text)?.emails;
+    let mut seen = HashSet::new();
+    for key in keys {
+        if !seen.insert(key.clone()) {
+            warn(&mut warnings, format!("emails.{}", key), "listed more than once; only the last entry is used");
+        }
+    }
+    warnings.extend(lint(&af));
+    Ok(warnings)
+}
+
+/// A Notion user ID, with or without dashes.
+fn is_user_id(s: &str) -> bool {
+    let hex: Vec<char> = s.chars().filter(|c| *c != '-').collect();
+    hex.len() == 32 && hex.iter().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Reports unknown roles and sandbox profiles, `extends` cycles, emails
+/// that differ only in case, managers also listed in `emails`, group
+/// members that look like Notion workspace group names, rules made
+/// pointless by `*`, approver and unidentified roles that do not exist,
+/// sandboxed roles left running as root because no `run_as` is set
+/// anywhere, and a `default` role that allows everything.
+pub fn lint(af: &AccessFile) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    let w = &mut warnings;
+
+    let mut emails: Vec<_> = af.emails.iter().collect();
+    emails.sort_by_key(|(e, _)| e.as_str());
+    for (i, (email, roles)) in emails.iter().enumerate() {
+        let at = format!("emails.{}", email);
+        for role in roles.names().iter().filter(|r| !af.perms.contains_key(*r)) {
+            warn(w, &at, format!("unknown role '{}' is ignored", role));
+        }
+        if roles.names().iter().all(|r| !af.perms.contains_key(r)) && roles_for(af, email) == ["default"] {
+            warn(w, &at, "no known role; falls back to 'default'");
+        }
+        if let Some((other, _)) = emails[..i].iter().find(|(o, _)| o.eq_ignore_ascii_case(email)) {
+            warn(w, &at, format!("conflicts with '{}'; emails are compared case-insensitively", other));
+        }
+        if is_manager(af, email) {
+            warn(w, &at, "is in perm_manager, so the allow/deny rules of its roles are never checked");
+        }
+    }
+
+    for (i, manager) in af.perm_manager.iter().enumerate() {
+        if af.perm_manager[..i].iter().any(|m| m.eq_ignore_ascii_case(manager)) {
+            warn(w, "perm_manager", format!("'{}' is listed more than once", manager));
+        }
+    }
+
+    let mut groups: Vec<_> = af.groups.iter().collect();
+    groups.sort_by_key(|(name, _)| name.as_str());
+    for (name, group) in groups {
+        for role in group.roles.iter().filter(|r| !af.perms.contains_key(*r)) {
+            warn(w, format!("groups.{}", name), format!("unknown role '{}' is ignored", role));
+        }
+        for member in group.members.iter().filter(|m| !m.contains('@') && !is_user_id(m)) {
+            warn(
+                w,
+                format!("groups.{}", name),
+                format!("'{}' is not an email or Notion user ID; Notion workspace groups are not resolved", member),
+            );
+        }
+    }
+
+    let mut perms: Vec<_> = af.perms.iter().collect();
+    perms.sort_by_key(|(name, _)| name.as_str());
+    for (name, rule) in perms {
+        let at = format!("perms.{}", name);
+        for parent in rule.extends.iter().filter(|p| !af.perms.contains_key(*p)) {
+            warn(w, &at, format!("extends unknown role '{}'", parent));
+        }
+        if extends_itself(af, name) {
+            warn(w, &at, "extends itself through a cycle");
+        }
+        if let Some(profile) = rule.sandbox.as_ref().filter(|p| !af.sandbox_profiles.contains_key(*p)) {
+            warn(w, &at, format!("unknown sandbox profile '{}'; its commands will be rejected", profile));
+        }
+        // With any run_as mapping, `run_as_for` refuses unmapped requesters
+        // instead of running them as the agent.
+        if rule.sandbox.is_some() && af.run_as.is_empty() && af.perms.values().all(|r| r.run_as.is_none()) {
+            warn(w, &at, "sandboxed commands run as the agent's user (root) because no run_as is set");
+        }
+        if rule.allow.iter().any(RuleItem::is_wildcard) {
+            for item in rule.allow.iter().filter(|i| !i.is_wildcard()) {
+                warn(w, format!("{}.allow", at), format!("'{}' is shadowed by '*'", item.pattern()));
+            }
+        }
+        if rule.deny.iter().any(RuleItem::is_wildcard) {
+            if !rule.allow.is_empty() {
+                warn(w, format!("{}.deny", at), "'*' denies every command, so allow rules never apply");
+            }
+            for item in rule.deny.iter().filter(|i| !i.is_wildcard()) {
+                warn(w, format!("{}.deny", at), format!("'{}' is shadowed by '*'", item.pattern()));
+            }
+        }
+    }
+
+    let approver_role = &af.approval.approver_role;
+    if !af.approval.commands.is_empty() && !af.perms.contains_key(approver_role) {
+        let who = if af.perm_manager.is_empty() { "nobody" } else { "only perm_manager" };
+        warn(w, "approval.approver_role", format!("unknown role '{}'; {} can approve", approver_role, who));
+    }
+
+    for (at, role) in [("unidentified.bots", &af.unidentified.bots), ("unidentified.unknown", &af.unidentified.unknown)] {
+        if role != DENY && !af.perms.contains_key(role) {
+            warn(w, at, format!("unknown role '{}'; their commands are denied", role));
+        }
+    }
+
+    let mut chain = Vec::new();
+    push_role(af, "default", &mut chain);
+    if is_unrestricted(&chain) {
+        warn(w, "perms.default", "allows every command to anyone without another role");
+    }
+    warnings
+}
+
+fn warn(warnings: &mut Vec<LintWarning>, location: impl Into<String>, message: impl Into<String>) {
+    warnings.push(LintWarning { location: location.into(), message: message.into() });
 }
 
-fn matches_rule_item(item: &str, cmd: &str) -> bool {
-    if item == "*" { return true; }
-    // If item has whitespace, treat as prefix of entire command
-    if item.contains(char::is_whitespace) {
-        return cmd.starts_with(item);
+fn extends_itself(af: &AccessFile, role: &str) -> bool {
+    let mut stack: Vec<&str> = match af.perms.get(role) {
+        Some(r) => r.extends.iter().map(|s| s.as_str()).collect(),
+        None => return false,
+    };
+    let mut seen = HashSet::new();
+    while let Some(name) = stack.pop() {
+        if name == role {
+            return true;
+        }
+        if seen.insert(name) {
+            if let Some(r) = af.perms.get(name) {
+                stack.extend(r.extends.iter().map(|s| s.as_str()));
+            }
+        }
+    }
+    false
+}
+
+/// Keys of the `emails` object in the order written, duplicates included;
+/// parsing into a map keeps only the last of each.
+#[derive(Default)]
+struct EmailKeys(Vec<String>);
+
+#[derive(Deserialize)]
+struct RawEmails {
+    #[serde(default)]
+    emails: EmailKeys,
+}
+
+impl<'de> Deserialize<'de> for EmailKeys {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        struct Keys;
+        impl<'de> serde::de::Visitor<'de> for Keys {
+            type Value = EmailKeys;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an object")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> std::result::Result<EmailKeys, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut keys = Vec::new();
+                while let Some((key, _)) = map.next_entry::<String, serde::de::IgnoredAny>()? {
+                    keys.push(key);
+                }
+                Ok(EmailKeys(keys))
+            }
+        }
+        d.deserialize_map(Keys)
+    }
+}
+
+/// Roles that allow everything and deny nothing do not depend on what the
+/// command line contains.
+fn is_unrestricted(chain: &[(&str, &PermRule)]) -> bool {
+    chain.iter().any(|(_, r)| r.allow.iter().any(RuleItem::is_wildcard))
+        && chain.iter().all(|(_, r)| r.deny.is_empty())
+}
+
+/// Whether the roles of `email` limit which commands it may run; managers
+/// and unrestricted roles do not.
+pub fn is_restricted(af: &AccessFile, email: &str) -> bool {
+    !is_manager(af, email) && !is_unrestricted(&role_chain(af, email))
+}
+
+pub fn is_manager(af: &AccessFile, email: &str) -> bool {
+    af.perm_manager.iter().any(|e| e.eq_ignore_ascii_case(email))
+}
+
+/// Roles that apply to `email`, highest precedence first: those listed for
+/// the email in order, then those of its groups by group name, each
+/// followed by the roles it `extends` (depth-first). Names that do not
+/// exist in `perms` are skipped; with none left the role is "default", or
+/// the `unidentified` role for bots and unknown users.
+/// Managers bypass rules entirely, so callers should check `is_manager`
+/// first.
+pub fn roles_for<'a>(af: &'a AccessFile, email: &str) -> Vec<&'a str> {
+    role_chain(af, email).into_iter().map(|(name, _)| name).collect()
+}
+
+pub(crate) fn role_chain<'a>(af: &'a AccessFile, email: &str) -> Vec<(&'a str, &'a PermRule)> {
+    let direct = af
+        .emails
+        .iter()
+        .filter(|(e, _)| e.eq_ignore_ascii_case(email))
+        .flat_map(|(_, roles)| roles.names());
+    let mut groups: Vec<_> = af
+        .groups
+        .iter()
+        .filter(|(_, g)| g.members.iter().any(|m| m.eq_ignore_ascii_case(email)))
+        .collect();
+    groups.sort_by_key(|(name, _)| name.as_str());
+    let mut chain = Vec::new();
+    for name in direct.chain(groups.into_iter().flat_map(|(_, g)| &g.roles)) {
+        push_role(af, name, &mut chain);
+    }
+    if chain.is_empty() {
+        match unidentified_policy(af, email) {
+            Some(DENY) => {}
+            Some(role) => push_role(af, role, &mut chain),
+            None => push_role(af, "default", &mut chain),
+        }
+    }
+    chain
+}
+
+/// The `unidentified` setting that applies to `requester` ("deny" or a
+/// role), or `None` for requesters identified by email or user ID.
+pub fn unidentified_policy<'a>(af: &'a AccessFile, requester: &str) -> Option<&'a str> {
+    if requester == UNKNOWN_REQUESTER {
+        Some(&af.unidentified.unknown)
+    } else if requester.starts_with(BOT_PREFIX) {
+        Some(&af.unidentified.bots)
+    } else {
+        None
+    }
+}
+
+/// Every name access.json lists a person under: keys of `emails` and
+/// `run_as`, `perm_manager` and group members.
+fn listed_names(af: &AccessFile) -> impl Iterator<Item = &String> {
+    af.emails
+        .keys()
+        .chain(&af.perm_manager)
+        .chain(af.groups.values().flat_map(|g| &g.members))
+        .chain(af.run_as.keys())
+}
+
+/// The key under which access.json lists the Notion user `id`, if any: in
+/// `emails`, `perm_manager`, group members or `run_as`. IDs match with or
+/// without dashes, in any case; keys that are not user IDs never match.
+pub fn listed_user_id<'a>(af: &'a AccessFile, id: &str) -> Option<&'a str> {
+    let normalize = |s: &str| s.replace('-', "").to_ascii_lowercase();
+    if !is_user_id(id) {
+        return None;
+    }
+    let id = normalize(id);
+    listed_names(af).find(|k| is_user_id(k) && normalize(k) == id).map(|k| k.as_str())
+}
+
+/// Whether access.json lists `email` anywhere `listed_user_id` looks.
+pub fn lists_email(af: &AccessFile, email: &str) -> bool {
+    listed_names(af).any(|k| k.eq_ignore_ascii_case(email))
+}
+
+/// Adds `name` and the roles it extends, each at most once.
+fn push_role<'a>(af: &'a AccessFile, name: &str, chain: &mut Vec<(&'a str, &'a PermRule)>) {
+    let Some((name, rule)) = af.perms.get_key_value(name) else { return };
+    if chain.iter().any(|(n, _)| *n == name) {
+        return;
+    }
+    chain.push((name, rule));
+    for parent in &rule.extends {
+        push_role(af, parent, chain);
+    }
+}
+
+/// A per-role setting from the first role in precedence order that sets it.
+fn role_setting<'a, T>(
+    af: &'a AccessFile,
+    email: &str,
+    f: impl Fn(&'a PermRule) -> Option<T>,
+) -> Option<T> {
+    role_chain(af, email).into_iter().find_map(|(_, r)| f(r))
+}
+
+/// Command timeout for `email`: `default_secs` capped by the role's
+/// `max_timeout_secs`. Managers get `default_secs`. Zero means no timeout.
+pub fn effective_timeout_secs(af: &AccessFile, email: &str, default_secs: u64) -> u64 {
+    if is_manager(af, email) {
+        return default_secs;
+    }
+    match role_setting(af, email, |r| r.max_timeout_secs) {
+        Some(max) if default_secs == 0 || max < default_secs => max,
+        _ => default_secs,
+    }
+}
+
+/// Where `email`'s commands should run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunAsDecision<'a> {
+    /// No `run_as` is configured anywhere; run as the agent's own user.
+    Agent,
+    Mapped(&'a RunAs),
+    /// Mappings exist but none covers `email`.
+    Unmapped,
+}
+
+/// Resolves the Unix account for `email`: the per-email `run_as` entry
+/// first, then the role's. Once any mapping is configured, requesters
+/// without one (managers included) are not run as the agent's user.
+pub fn run_as_for<'a>(af: &'a AccessFile, email: &str) -> RunAsDecision<'a> {
+    let by_email = af
+        .run_as
+        .iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(email))
+        .map(|(_, r)| r);
+    if let Some(r) = by_email.or_else(|| role_setting(af, email, |r| r.run_as.as_ref())) {
+        return RunAsDecision::Mapped(r);
+    }
+    if af.run_as.is_empty() && af.perms.values().all(|r| r.run_as.is_none()) {
+        RunAsDecision::Agent
+    } else {
+        RunAsDecision::Unmapped
+    }
+}
+
+/// Sandbox profile for `email`'s role, if it names one. Unlike permission
+/// rules this applies to managers too. Naming a profile that does not exist
+/// is an error, so a typo cannot silently run commands unconfined.
+pub fn sandbox_for<'a>(af: &'a AccessFile, email: &str) -> Result<Option<&'a SandboxProfile>> {
+    let Some(name) = sandbox_name_for(af, email) else {
+        return Ok(None);
+    };
+    af.sandbox_profiles
+        .get(name)
+        .map(Some)
+        .ok_or_else(|| anyhow!("unknown sandbox profile '{}'", name))
+}
+
+/// Name of the sandbox profile `email`'s commands run in, if any.
+pub fn sandbox_name_for<'a>(af: &'a AccessFile, email: &str) -> Option<&'a str> {
+    role_setting(af, email, |r| r.sandbox.as_deref())
+}
+
+/// Starting directory for `email`'s commands from the role's
+/// `working_dir`. Applies to managers too.
+pub fn working_dir_for<'a>(af: &'a AccessFile, email: &str) -> Option<&'a str> {
+    role_setting(af, email, |r| r.working_dir.as_deref())
+}
+
+/// Whether denial messages posted to Notion for `email` may name the rule
+/// that denied the command and its `reason`.
+pub fn reveals_reason(af: &AccessFile, email: &str) -> bool {
+    role_setting(af, email, |r| r.reveal).unwrap_or(false)
+}
+
+/// Outcome of the permission check and what decided it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Roles that applied, highest precedence first; empty for managers.
+    pub roles: Vec<String>,
+    /// Which rule decided, e.g. `deny "rm" of role "ops" matched "rm -rf /tmp"`.
+    pub explanation: String,
+    /// The `reason` of the rule that denied the command, if it has one.
+    pub reason: Option<String>,
+    /// What denied the command; `None` when it is allowed.
+    pub denied_by: Option<DeniedBy>,
+}
+
+/// What kind of check denied a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeniedBy {
+    /// The `allow`/`deny` rules.
+    Rule,
+    /// Outside the role's `time_windows`.
+    TimeWindow,
+    RateLimit,
+    DailyQuota,
+}
+
+impl DeniedBy {
+    /// Name used in the audit log.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeniedBy::Rule => "rule",
+            DeniedBy::TimeWindow => "time_window",
+            DeniedBy::RateLimit => "rate_limit",
+            DeniedBy::DailyQuota => "daily_quota",
+        }
+    }
+}
+
+impl Decision {
+    fn allow(roles: Vec<String>, explanation: String) -> Self {
+        Self { allowed: true, roles, explanation, reason: None, denied_by: None }
+    }
+
+    fn deny(roles: Vec<String>, explanation: String, reason: Option<&str>) -> Self {
+        Self {
+            allowed: false,
+            roles,
+            explanation,
+            reason: reason.map(str::to_string),
+            denied_by: Some(DeniedBy::Rule),
+        }
+    }
+}
+
+/// One rule item compared with one program of the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleCheck {
+    pub role: String,
+    pub kind: RuleKind,
+    pub rule: String,
+    pub invocation: String,
+    pub result: RuleMatch,
+}
+
+/// Everything `decide` looked at, in the order it looked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub email: String,
+    pub command: String,
+    pub manager: bool,
+    /// The programs found in the command line, or why it could not be analyzed.
+    pub invocations: std::result::Result<Vec<String>, String>,
+    pub checks: Vec<RuleCheck>,
+    pub decision: Decision,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "email:    {}", self.email)?;
+        writeln!(f, "command:  {}", self.command)?;
+        if self.manager {
+            writeln!(f, "roles:    (manager)")?;
+        } else {
+            writeln!(f, "roles:    {}", self.decision.roles.join(", "))?;
+        }
+        match &self.invocations {
+            Ok(invs) => {
+                writeln!(f, "programs:")?;
+                for inv in invs {
+                    writeln!(f, "  {}", inv)?;
+                }
+            }
+            Err(e) => writeln!(f, "programs: cannot be analyzed ({})", e)?,
+        }
+        if !self.checks.is_empty() {
+            writeln!(f, "rules:")?;
+        }
+        for c in &self.checks {
+            let kind = match c.kind {
+                RuleKind::Allow => "allow",
+                RuleKind::Deny => "deny ",
+            };
+            let result = match c.result {
+                RuleMatch::Yes => "matched",
+                RuleMatch::No => "no match",
+                RuleMatch::Constrained => "matched, constraints do not hold",
+            };
+            let rule = format!("\"{}\"", c.rule);
+            writeln!(f, "  {} {:<24} role {:<12} {:<24} {}", kind, rule, c.role, c.invocation, result)?;
+        }
+        let verdict = if self.decision.allowed { "allowed" } else { "denied" };
+        write!(f, "decision: {} - {}", verdict, self.decision.explanation)?;
+        if let Some(reason) = &self.decision.reason {
+            write!(f, " (reason: {})", reason)?;
+        }
+        Ok(())
     }
-    // Otherwise compare first token
-    let tok = first_token(cmd);
-    tok.eq_ignore_ascii_case(item)
 }
 
 pub fn is_allowed(af: &AccessFile, email: &str, cmd: &str) -> bool {
+    decide(af, email, cmd).allowed
+}
+
+/// Checks `cmd` against the rules of every role of `email`: a deny rule of
+/// any role wins, otherwise every program needs an allow rule of some role.
+pub fn decide(af: &AccessFile, email: &str, cmd: &str) -> Decision {
+    explain(af, email, cmd).decision
+}
+
+/// `decide` for a command starting at `now`, then the role's time windows,
+/// rate limit and daily quota given the requester's earlier commands in
+/// `usage`. Managers are limited by their roles too, unless
+/// `managers_skip_limits` is set.
+pub fn decide_at(af: &AccessFile, email: &str, cmd: &str, now: DateTime<Utc>, usage: &Usage) -> Decision {
+    let mut decision = decide(af, email, cmd);
+    if !decision.allowed || (af.managers_skip_limits && is_manager(af, email)) {
+        return decision;
+    }
+    let chain = role_chain(af, email);
+    if let Err((denied_by, explanation)) = limits::check(&chain, email, now, usage) {
+        decision.allowed = false;
+        decision.explanation = explanation;
+        decision.denied_by = Some(denied_by);
+    }
+    decision
+}
+
+/// Like `decide`, also recording every rule item it compared.
+pub fn explain(af: &AccessFile, email: &str, cmd: &str) -> Explanation {
+    let mut ex = Explanation {
+        email: email.to_string(),
+        command: cmd.to_string(),
+        manager: is_manager(af, email),
+        invocations: Ok(Vec::new()),
+        checks: Vec::new(),
+        decision: Decision::deny(Vec::new(), String::new(), None),
+    };
+    let parsed = shell::analyze(cmd);
+    ex.invocations = match &parsed {
+        Ok(invs) => Ok(invs.iter().map(|i| i.to_string()).collect()),
+        Err(e) => Err(e.to_string()),
+    };
+
     // Managers: always allowed
-    if af.perm_manager.iter().any(|e| e.eq_ignore_ascii_case(email)) {
-        return true;
+    if ex.manager {
+        ex.decision = Decision::allow(Vec::new(), format!("{} is in perm_manager", email));
+        return ex;
     }
 
-    // Resolve role -> rules
-    let role = af
-        .emails
-        .get(email)
-        .map(|s| s.as_str())
-        .unwrap_or("default");
-    let rules = af.perms.get(role).or_else(|| af.perms.get("default"));
-    let Some(rules) = rules else { return false };
+    let chain = role_chain(af, email);
+    let roles: Vec<String> = chain.iter().map(|(name, _)| name.to_string()).collect();
+    if chain.is_empty() {
+        ex.decision = Decision::deny(roles, "no role applies".to_string(), None);
+        return ex;
+    }
+
+    // Every program the shell would run is checked; command lines that
+    // cannot be analyzed only run for unrestricted roles.
+    let invocations = match parsed {
+        Ok(invocations) => invocations,
+        Err(e) if is_unrestricted(&chain) => {
+            let why = format!("command line cannot be analyzed ({}), but the roles are unrestricted", e);
+            ex.decision = Decision::allow(roles, why);
+            return ex;
+        }
+        Err(e) => {
+            ex.decision = Decision::deny(roles, format!("command line cannot be analyzed ({})", e), None);
+            return ex;
+        }
+    };
 
     // Deny has priority
-    if rules.deny.iter().any(|d| matches_rule_item(d, cmd)) {
-        return false;
+    for inv in &invocations {
+        for (role, rules) in &chain {
+            for d in &rules.deny {
+                let result = d.matches(inv, RuleKind::Deny);
+                ex.checks.push(RuleCheck::new(role, RuleKind::Deny, d, inv, result));
+                if result == RuleMatch::Yes {
+                    let why = format!("deny \"{}\" of role \"{}\" matched \"{}\"", d.pattern(), role, inv);
+                    ex.decision = Decision::deny(roles, why, d.reason());
+                    return ex;
+                }
+            }
+        }
     }
 
-    // Allow
-    if rules.allow.iter().any(|a| matches_rule_item(a, cmd)) {
-        return true;
+    // Each program needs an explicit allow
+    let mut allowed_by = Vec::new();
+    for inv in &invocations {
+        match check_allow(&chain, inv, &mut ex.checks) {
+            Ok(why) => allowed_by.push(why),
+            Err((why, reason)) => {
+                ex.decision = Decision::deny(roles, why, reason);
+                return ex;
+            }
+        }
     }
+    ex.decision = Decision::allow(roles, allowed_by.join("; "));
+    ex
+}
 
-    // If no explicit allow matched, deny
-    false
+impl RuleCheck {
+    fn new(role: &str, kind: RuleKind, item: &RuleItem, inv: &Invocation, result: RuleMatch) -> Self {
+        Self {
+            role: role.to_string(),
+            kind,
+            rule: item.pattern().to_string(),
+            invocation: inv.to_string(),
+            result,
+        }
+    }
 }
 
+/// Allowed when some item matches outright; otherwise explains why not,
+/// with the reason of an item whose pattern matched but whose constraints
+/// did not hold.
+fn check_allow<'a>(
+    chain: &[(&str, &'a PermRule)],
+    inv: &Invocation,
+    checks: &mut Vec<RuleCheck>,
+) -> std::result::Result<String, (String, Option<&'a str>)> {
+    let mut constrained = None;
+    for (role, rules) in chain {
+        for item in &rules.allow {
+            let result = item.matches(inv, RuleKind::Allow);
+            checks.push(RuleCheck::new(role, RuleKind::Allow, item, inv, result));
+            match result {
+                RuleMatch::Yes => {
+                    return Ok(format!(
+                        "allow \"{}\" of role \"{}\" matched \"{}\"",
+                        item.pattern(),
+                        role,
+                        inv
+                    ));
+                }
+                RuleMatch::Constrained if constrained.is_none() => constrained = Some((*role, item)),
+                _ => {}
+            }
+        }
+    }
+    Err(match constrained {
+        Some((role, item)) => (
+            format!(
+                "allow \"{}\" of role \"{}\" matched \"{}\" but its constraints do not hold",
+                item.pattern(),
+                role,
+                inv
+            ),
+            item.reason(),
+        ),
+        None => (format!("no allow rule matches \"{}\"", inv), None),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_capped_by_role() {
+        let mut af = AccessFile::default();
+        af.perm_manager.push("boss@example.com".into());
+        af.emails.insert("c@example.com".into(), "contractor".into());
+        af.perms.insert(
+            "contractor".into(),
+            PermRule { allow: vec!["*".into()], max_timeout_secs: Some(60), ..Default::default() },
+        );
+        assert_eq!(effective_timeout_secs(&af, "c@example.com", 300), 60);
+        assert_eq!(effective_timeout_secs(&af, "c@example.com", 30), 30);
+        assert_eq!(effective_timeout_secs(&af, "c@example.com", 0), 60);
+        assert_eq!(effective_timeout_secs(&af, "x@example.com", 300), 300);
+        assert_eq!(effective_timeout_secs(&af, "boss@example.com", 300), 300);
+    }
+
+    #[test]
+    fn managers_are_limited_unless_exempted() {
+        let mut af = AccessFile::default();
+        af.perm_manager.push("boss@example.com".into());
+        af.perms.get_mut("default").unwrap().rate_limit = Some(RateLimit { per_minute: Some(1), per_hour: None });
+        let now = Utc::now();
+        let mut usage = Usage::default();
+        usage.record("boss@example.com", now);
+        let decision = decide_at(&af, "boss@example.com", "ls", now, &usage);
+        assert_eq!(decision.denied_by, Some(DeniedBy::RateLimit));
+
+        af.managers_skip_limits = true;
+        assert!(decide_at(&af, "boss@example.com", "ls", now, &usage).allowed);
+    }
+
+    #[test]
+    fn rules_apply_to_every_program_in_the_line() {
+        let mut af = AccessFile::default();
+        af.emails.insert("r@example.com".into(), "restricted".into());
+        af.perms.insert(
+            "restricted".into(),
+            PermRule {
+                allow: vec!["ls".into(), "echo".into(), "git status".into()],
+                deny: vec!["rm".into(), "sudo".into()],
+                ..Default::default()
+            },
+        );
+        let r = "r@example.com";
+        assert!(is_allowed(&af, r, "ls -la | echo done"));
+        assert!(is_allowed(&af, r, "/bin/ls /tmp"));
+        assert!(is_allowed(&af, r, "git status --short"));
+        assert!(!is_allowed(&af, r, "git push"));
+        assert!(!is_allowed(&af, r, "ls; rm -rf /"));
+        assert!(!is_allowed(&af, r, "echo x && sudo reboot"));
+        assert!(!is_allowed(&af, r, "echo $(rm -rf /)"));
+        assert!(!is_allowed(&af, r, "/bin/rm x"));
+        assert!(!is_allowed(&af, r, "LS=1 RM /"));
+        // Cannot be analyzed: denied for restricted roles only.
+        assert!(!is_allowed(&af, r, "$(which ls)"));
+        assert!(is_allowed(&af, "x@example.com", "$(which ls)"));
+    }
+
+    #[test]
+    fn rule_objects_carry_constraints_and_reasons() {
+        let json = r#"{
+            "emails": {"d@example.com": "dev"},
+            "perms": {
+                "default": {"allow": ["*"], "deny": []},
+                "dev": {
+                    "allow": ["ls", "systemctl restart app-*",
+                              {"match": "docker", "forbid_flags": ["--privileged"], "reason": "no privileged containers"}],
+                    "deny": [{"match": "re:ls .*/etc/.*", "reason": "config is off limits"}]
+                }
+            }
+        }"#;
+        let af: AccessFile = serde_json::from_str(json).unwrap();
+        validate(&af).unwrap();
+        let d = "d@example.com";
+        assert!(is_allowed(&af, d, "systemctl restart app-api"));
+        assert!(!is_allowed(&af, d, "systemctl restart sshd"));
+        assert_eq!(decide(&af, d, "systemctl restart sshd").reason, None);
+        assert!(is_allowed(&af, d, "docker run --rm alpine"));
+        assert_eq!(
+            decide(&af, d, "docker run --privileged alpine").reason.as_deref(),
+            Some("no privileged containers")
+        );
+        assert_eq!(decide(&af, d, "ls -la /etc/ssh").reason.as_deref(), Some("config is off limits"));
+
+        let mut broken = af.clone();
+        broken.perms.get_mut("dev").unwrap().allow.push("re:([".into());
+        assert!(validate(&broken).is_err());
+    }
+
+    #[test]
+    fn roles_extend_combine_and_explain() {
+        let json = r#"{
+            "emails": {"a@example.com": ["dev", "oncall"], "b@example.com": "missing"},
+            "groups": {
+                "sre": {"members": ["B@example.com"], "roles": ["oncall"]}
+            },
+            "perms": {
+                "default": {"allow": ["ls"]},
+                "base": {"allow": ["ls", "cat"], "deny": ["rm"], "working_dir": "/srv"},
+                "dev": {"extends": ["base"], "allow": ["git"], "max_timeout_secs": 60},
+                "oncall": {"extends": ["base", "dev"], "allow": ["systemctl restart *"], "max_timeout_secs": 300}
+            }
+        }"#;
+        let af: AccessFile = serde_json::from_str(json).unwrap();
+        let a = "a@example.com";
+        assert_eq!(roles_for(&af, a), vec!["dev", "base", "oncall"]);
+        // Unknown roles are skipped; group roles still apply.
+        assert_eq!(roles_for(&af, "b@example.com"), vec!["oncall", "base", "dev"]);
+        assert_eq!(roles_for(&af, "x@example.com"), vec!["default"]);
+
+        // Union of allows, union of denies; settings from the first role.
+        assert!(is_allowed(&af, a, "git log && systemctl restart app && cat x"));
+        assert!(!is_allowed(&af, a, "git rm x; rm y"));
+        assert_eq!(effective_timeout_secs(&af, a, 600), 60);
+        assert_eq!(working_dir_for(&af, a), Some("/srv"));
+
+        let d = decide(&af, a, "ls /tmp | rm x");
+        assert!(!d.allowed);
+        assert_eq!(d.explanation, r#"deny "rm" of role "base" matched "rm x""#);
+        let d = decide(&af, "b@example.com", "systemctl restart app");
+        assert!(d.allowed);
+        assert_eq!(d.roles, vec!["oncall", "base", "dev"]);
+        assert!(d.explanation.contains(r#"allow "systemctl restart *" of role "oncall""#));
+        let d = decide(&af, "x@example.com", "git status");
+        assert_eq!(d.explanation, r#"no allow rule matches "git status""#);
+    }
+
+    #[test]
+    fn explain_lists_every_rule_compared() {
+        let mut af = AccessFile::default();
+        af.emails.insert("r@example.com".into(), "restricted".into());
+        af.perms.insert(
+            "restricted".into(),
+            PermRule { allow: vec!["ls".into(), "cat".into()], deny: vec!["rm".into()], ..Default::default() },
+        );
+        let ex = explain(&af, "r@example.com", "ls /tmp && cat x");
+        assert!(ex.decision.allowed);
+        assert_eq!(ex.invocations, Ok(vec!["ls /tmp".to_string(), "cat x".to_string()]));
+        let summary: Vec<_> = ex.checks.iter().map(|c| (c.kind, c.rule.as_str(), c.result)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (RuleKind::Deny, "rm", RuleMatch::No),
+                (RuleKind::Deny, "rm", RuleMatch::No),
+                (RuleKind::Allow, "ls", RuleMatch::Yes),
+                (RuleKind::Allow, "ls", RuleMatch::No),
+                (RuleKind::Allow, "cat", RuleMatch::Yes),
+            ]
+        );
+        let text = ex.to_string();
+        assert!(text.contains("roles:    restricted"));
+        assert!(text.ends_with(
+            r#"decision: allowed - allow "ls" of role "restricted" matched "ls /tmp"; allow "cat" of role "restricted" matched "cat x""#
+        ));
+
+        af.perm_manager.push("r@example.com".into());
+        assert!(explain(&af, "r@example.com", "rm -rf /").to_string().contains("roles:    (manager)"));
+    }
+
+    #[test]
+    fn lint_reports_likely_mistakes() {
+        let json = r#"{
+            "emails": {
+                "a@example.com": "develper",
+                "A@example.com": "dev",
+                "b@example.com": "dev",
+                "b@example.com": "ops",
+                "boss@example.com": "dev",
+                "c@example.com": "nope"
+            },
+            "perm_manager": ["boss@example.com"],
+            "groups": {"sre": {"members": ["Engineering", "6f1c2b9e-0000-4000-8000-00000000abcd"], "roles": ["dev"]}},
+            "perms": {
+                "default": {"allow": ["*"], "deny": []},
+                "dev": {"allow": ["*", "git"], "extends": ["ops"]},
+                "ops": {"allow": ["ls"], "deny": ["*"], "extends": ["dev", "audit"], "sandbox": "strict"}
+            }
+        }"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.json");
+        fs::write(&path, json).unwrap();
+        let warnings: Vec<String> = lint_file(&path).unwrap().iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "emails.b@example.com: listed more than once; only the last entry is used",
+                "emails.a@example.com: unknown role 'develper' is ignored",
+                "emails.a@example.com: conflicts with 'A@example.com'; emails are compared case-insensitively",
+                "emails.boss@example.com: is in perm_manager, so the allow/deny rules of its roles are never checked",
+                "emails.c@example.com: unknown role 'nope' is ignored",
+                "emails.c@example.com: no known role; falls back to 'default'",
+                "groups.sre: 'Engineering' is not an email or Notion user ID; Notion workspace groups are not resolved",
+                "perms.dev: extends itself through a cycle",
+                "perms.dev.allow: 'git' is shadowed by '*'",
+                "perms.ops: extends unknown role 'audit'",
+                "perms.ops: extends itself through a cycle",
+                "perms.ops: unknown sandbox profile 'strict'; its commands will be rejected",
+                "perms.ops: sandboxed commands run as the agent's user (root) because no run_as is set",
+                "perms.ops.deny: '*' denies every command, so allow rules never apply",
+                "perms.default: allows every command to anyone without another role",
+            ]
+        );
+
+        let mut clean = AccessFile::default();
+        clean.perms.insert("default".into(), PermRule { allow: vec!["ls".into()], ..Default::default() });
+        assert!(lint(&clean).is_empty());
+    }
+
+    #[test]
+    fn run_as_prefers_email_then_role() {
+        let mut af = AccessFile::default();
+        assert_eq!(run_as_for(&af, "a@example.com"), RunAsDecision::Agent);
+
+        let deploy = RunAs { user: "deploy".into(), group: None };
+        let ops = RunAs { user: "ops".into(), group: Some("wheel".into()) };
+        af.emails.insert("o@example.com".into(), "ops".into());
+        af.perms.insert("ops".into(), PermRule { run_as: Some(ops.clone()), ..Default::default() });
+        af.run_as.insert("D@example.com".into(), deploy.clone());
+        af.emails.insert("d@example.com".into(), "ops".into());
+
+        assert_eq!(run_as_for(&af, "o@example.com"), RunAsDecision::Mapped(&ops));
+        assert_eq!(run_as_for(&af, "d@example.com"), RunAsDecision::Mapped(&deploy));
+        assert_eq!(run_as_for(&af, "x@example.com"), RunAsDecision::Unmapped);
+    }
+
+    #[test]
+    fn sandbox_profile_by_role() {
+        let json = r#"{
+            "emails": {"c@example.com": "contractor", "t@example.com": "typo"},
+            "perms": {
+                "default": {"allow": ["*"], "deny": []},
+                "contractor": {"allow": ["*"], "deny": [], "sandbox": "strict"},
+                "typo": {"allow": ["*"], "deny": [], "sandbox": "strcit"}
+            },
+            "sandbox_profiles": {"strict": {"isolate_network": false, "memory_bytes": 1048576}}
+        }"#;
+        let af: AccessFile = serde_json::from_str(json).unwrap();
+        let strict = sandbox_for(&af, "c@example.com").unwrap().unwrap();
+        assert!(!strict.isolate_network);
+        assert!(strict.read_only_root);
+        assert_eq!(strict.memory_bytes, Some(1_048_576));
+        assert!(sandbox_for(&af, "x@example.com").unwrap().is_none());
+        assert!(sandbox_for(&af, "t@example.com").is_err());
+    }
+}
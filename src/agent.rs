@@ -0,0 +1,1852 @@
+use crate::access::{
+    decide, decide_at, effective_timeout_secs, is_restricted, lint_file, load_from_path, reveals_reason, run_as_for, sandbox_for,
+    sandbox_name_for, unidentified_policy, working_dir_for, AccessFile, DeniedBy, EditedBlocks,
+    RunAsDecision, UNKNOWN_REQUESTER,
+};
+use crate::approval::{self, ApprovalState};
+use crate::clock::Clock;
+use crate::config::{load_from_storage_path, Config};
+use crate::limits::Usage;
+use crate::executor::{
+    cap_output, execute_command_with, exit_signal, shell_attempts, ExecOptions, ExecutionResult,
+};
+use crate::logger::{
+    prune_outputs, save_output, write_audit_entry, write_command_log_with_dir, AuditEntry, LogPaths,
+};
+use crate::model::CommandTask;
+use crate::notion::{
+    append_result_children, append_session_output, ensure_status_block, fetch_all_children,
+    finish_streaming_result, is_block_processed, start_streaming_result,
+    update_status_block, update_streaming_result, NotionApi, UploadedFile,
+};
+use crate::parser::{block_text, is_session_close, parse_command_from_block, parse_session_from_block};
+use crate::privilege;
+use crate::pty::{render_terminal, PtyProcess};
+use crate::reload::FileWatch;
+use crate::scheduler::Scheduler;
+use crate::session::{is_protected_var, parse_builtin, resolve_cd, Builtin, Sessions};
+use crate::shutdown::Shutdown;
+use crate::status::NodeStatus;
+use crate::users::UserCache;
+use crate::util::{extract_page_id, os_name};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const DENIED_MESSAGE: &str =
+    "permission denied to execute the command. Please retry with an authorized account.";
+const UNMAPPED_MESSAGE: &str =
+    "permission denied: no local account is mapped to this requester. Ask an administrator to add a run_as entry.";
+/// Inline head + tail shown when the full output goes out as a file.
+const UPLOAD_PREVIEW_BYTES: usize = 3000;
+/// An interactive program is considered done answering once it has been
+/// quiet this long...
+const SESSION_QUIET: Duration = Duration::from_millis(500);
+/// ...or after this long, whichever comes first; later output is posted on
+/// the next poll.
+const SESSION_MAX_WAIT: Duration = Duration::from_secs(10);
+
+/// Outcome of a single command block handled by a worker.
+#[derive(Debug, Clone)]
+pub struct HandledTask {
+    pub task: CommandTask,
+    pub requester: String,
+    /// Audit status written for the task ("success" / "failed" / "timeout" /
+    /// "interrupted" / "dry_run").
+    pub status: String,
+    /// False when the command was rejected before running.
+    pub executed: bool,
+    /// Text posted to Notion.
+    pub output: String,
+    /// Streams, exit code and timing; `None` when the command did not run.
+    pub result: Option<ExecutionResult>,
+    /// Who approved the command, when it needed approval.
+    pub approver: Option<String>,
+}
+
+/// What one `Agent::tick` did.
+#[derive(Debug, Default)]
+pub struct TickReport {
+    /// Tasks whose workers finished since the previous tick.
+    pub handled: Vec<HandledTask>,
+    /// Number of tasks handed to workers during this tick.
+    pub dispatched: usize,
+    /// Set when the page could not be scanned.
+    pub fetch_error: Option<String>,
+    /// Tasks that failed to run or whose result could not be written.
+    pub errors: Vec<String>,
+}
+
+/// A command waiting for a worker.
+struct Job {
+    task: CommandTask,
+    requester: String,
+    /// `!!(session ...)`: started right away, outside the scheduler.
+    interactive: bool,
+    /// Second person who approved the command, when it needed approval.
+    approver: Option<String>,
+    /// Posted instead of running the job, e.g. when its approval expired.
+    refusal: Option<Refusal>,
+}
+
+/// Message a worker sends back when its job is done.
+struct Finished {
+    user: String,
+    block_id: String,
+    interactive: bool,
+    result: Result<HandledTask>,
+}
+
+/// Why an interactive session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionEnd {
+    Exited,
+    Closed,
+    Idle,
+    Interrupted,
+}
+
+/// Everything a worker thread needs to run a job and record its result.
+#[derive(Clone)]
+struct Worker {
+    api: Arc<dyn NotionApi>,
+    logs: LogPaths,
+    /// Serializes command/audit log writes across workers.
+    log_lock: Arc<Mutex<()>>,
+    /// Set when running commands must be killed (shutdown deadline).
+    cancel: Arc<AtomicBool>,
+    /// Set once shutdown is requested; interactive sessions close right
+    /// away instead of waiting for the grace period.
+    closing: Arc<AtomicBool>,
+    outputs: OutputPolicy,
+    /// Working directory and exported variables per requester.
+    sessions: Sessions,
+    users: Arc<UserCache>,
+}
+
+/// How long outputs are posted, taken from `Config`.
+#[derive(Debug, Clone, Copy)]
+struct OutputPolicy {
+    /// 0 disables uploads.
+    upload_threshold: usize,
+    /// 0 keeps everything inline.
+    max_inline: usize,
+    /// `None` keeps saved outputs forever.
+    retention: Option<Duration>,
+}
+
+impl OutputPolicy {
+    fn from_config(config: &Config) -> Self {
+        let days = config.output_retention_days;
+        Self {
+            upload_threshold: config.upload_threshold_bytes,
+            max_inline: config.max_output_bytes,
+            retention: (days > 0).then(|| Duration::from_secs(days * 86_400)),
+        }
+    }
+}
+
+/// Why a job is not run: the message posted to Notion, its audit status
+/// and, when a check refused it, which one (written as `denied=`).
+#[derive(Debug, Clone)]
+struct Refusal {
+    message: String,
+    status: &'static str,
+    denied_by: Option<&'static str>,
+}
+
+impl Refusal {
+    fn new(message: impl Into<String>, denied_by: &'static str) -> Self {
+        Self { message: message.into(), status: "failed", denied_by: Some(denied_by) }
+    }
+
+    /// A dry-run report posted in place of running the command.
+    fn dry_run(report: String) -> Self {
+        Self { message: report, status: "dry_run", denied_by: None }
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        self.denied_by.map(|d| vec![("denied".to_string(), d.to_string())]).unwrap_or_default()
+    }
+}
+
+impl Worker {
+    /// Runs the job with `opts`, or posts the denial message in `Err`.
+    fn run(
+        &self,
+        task: CommandTask,
+        requester: String,
+        approver: Option<String>,
+        opts: Result<ExecOptions, Refusal>,
+    ) -> Result<HandledTask> {
+        let api = self.api.as_ref();
+        let executed = opts.is_ok();
+        let mut result_block = None;
+        let mut attachment = None;
+        let mut exec_result = None;
+        let mut details = Vec::new();
+        let builtin = opts.as_ref().ok().and_then(|_| parse_builtin(&task.command));
+        let (output, status) = match (opts, builtin) {
+            (Ok(opts), Some(builtin)) => self.run_builtin(&requester, builtin, &opts),
+            (Ok(opts), None) => {
+                // Show a "running…" block right away and keep it updated.
+                if opts.progress_interval.is_some() {
+                    result_block =
+                        Some(start_streaming_result(api, &task.block_id, &task.command, &requester)?);
+                }
+                let started = Instant::now();
+                let stop = || self.cancel.load(Ordering::SeqCst);
+                let mut on_progress = |partial: &str| {
+                    let Some(id) = &result_block else { return };
+                    let elapsed = started.elapsed().as_secs();
+                    if let Err(e) =
+                        update_streaming_result(api, id, &task.command, partial, &requester, elapsed)
+                    {
+                        eprintln!("[!] Failed to stream output for block {}: {e:#}", task.block_id);
+                    }
+                };
+                // A command that fails to start is still answered and marked,
+                // so it is not picked up again on the next tick.
+                let result = execute_command_with(&task.command, &opts, &stop, &mut on_progress)
+                    .unwrap_or_else(|e| ExecutionResult::failed_to_start(&e, started.elapsed()));
+                let status = result.audit_status();
+                if let Some(change) = &result.state {
+                    self.sessions.update(&requester, |s| s.apply(change));
+                }
+                let (output, file) = self.attach_large_output(&task, result.render());
+                attachment = file;
+                exec_result = Some(result);
+                (output, status)
+            }
+            (Err(refusal), _) => {
+                details = refusal.details();
+                (refusal.message, refusal.status)
+            }
+        };
+
+        if let Some(result) = &exec_result {
+            details = result.audit_details();
+        }
+        details.extend(approver.clone().map(|a| ("approved_by".to_string(), a)));
+        self.write_logs(&task, &requester, &task.command, status, details)?;
+        let finished = result_block.as_deref().map(|id| {
+            finish_streaming_result(
+                api,
+                &task.block_id,
+                id,
+                &task.command,
+                &output,
+                &requester,
+                attachment.as_ref(),
+            )
+        });
+        match finished {
+            Some(Ok(())) => {}
+            Some(Err(e)) => {
+                // The running block may have been deleted; post the result anew.
+                eprintln!("[!] Failed to finalize streamed result for block {}: {e:#}", task.block_id);
+                append_result_children(
+                    api,
+                    &task.block_id,
+                    &task.command,
+                    &output,
+                    &requester,
+                    attachment.as_ref(),
+                )?;
+            }
+            None => append_result_children(
+                api,
+                &task.block_id,
+                &task.command,
+                &output,
+                &requester,
+                attachment.as_ref(),
+            )?,
+        }
+
+        Ok(HandledTask {
+            task,
+            requester,
+            status: status.to_string(),
+            executed,
+            output,
+            result: exec_result,
+            approver,
+        })
+    }
+
+    /// Writes the command log line and the audit entry for `command`.
+    fn write_logs(
+        &self,
+        task: &CommandTask,
+        requester: &str,
+        command: &str,
+        status: &str,
+        details: Vec<(String, String)>,
+    ) -> Result<()> {
+        let mut details = details;
+        details.extend(task.created_by_id.clone().map(|id| ("created_by".to_string(), id)));
+        details.extend(task.last_edited_by_id.clone().map(|id| ("last_edited_by".to_string(), id)));
+        let _guard = self.log_lock.lock().unwrap_or_else(|e| e.into_inner());
+        write_command_log_with_dir(&self.logs.command_dir, requester, command)?;
+        let entry = AuditEntry {
+            command: command.to_string(),
+            requester: requester.to_string(),
+            requested_at: task.created_time.clone(),
+            node: os_name(),
+            status: status.to_string(),
+            details,
+        };
+        write_audit_entry(&self.logs.audit_file, &entry)
+    }
+
+    /// Runs an interactive session: starts the program under a
+    /// pseudo-terminal, then types each paragraph the requester writes under
+    /// the session block into it and posts what it printed, until the
+    /// program exits, `!!(close)` is written, no input arrives for
+    /// `idle_timeout` or the agent shuts down. Every input goes to the
+    /// command log; the audit log gets one entry when the session ends.
+    fn run_session(
+        &self,
+        task: CommandTask,
+        requester: String,
+        approver: Option<String>,
+        opts: Result<ExecOptions, Refusal>,
+        idle_timeout: Option<Duration>,
+    ) -> Result<HandledTask> {
+        let api = self.api.as_ref();
+        let label = format!("session {}", task.command);
+        let started = Instant::now();
+        let spawned = opts.and_then(|opts| {
+            PtyProcess::spawn(&task.command, &opts).map_err(|e| Refusal {
+                message: format!("failed to start session: {e:#}"),
+                status: "failed",
+                denied_by: None,
+            })
+        });
+        let approved_by = approver.clone().map(|a| ("approved_by".to_string(), a));
+        let mut pty = match spawned {
+            Ok(pty) => pty,
+            Err(refusal) => {
+                let mut details = refusal.details();
+                details.extend(approved_by);
+                self.write_logs(&task, &requester, &label, refusal.status, details)?;
+                let message = refusal.message;
+                append_result_children(api, &task.block_id, &label, &message, &requester, None)?;
+                return Ok(HandledTask {
+                    task,
+                    requester,
+                    status: refusal.status.to_string(),
+                    executed: false,
+                    output: message,
+                    result: None,
+                    approver,
+                });
+            }
+        };
+
+        // Mark the block right away so a restart does not reopen the session.
+        let banner = render_terminal(&pty.read_until_quiet(SESSION_QUIET, SESSION_MAX_WAIT));
+        let intro = format!(
+            "{}[session started: write input as paragraphs under this block, !!(close) to end]",
+            banner
+        );
+        append_result_children(api, &task.block_id, &label, &intro, &requester, None)?;
+        let child_id = |b: &serde_json::Value| b.get("id").and_then(|v| v.as_str()).map(|s| s.to_string());
+        let mut seen: HashSet<String> =
+            fetch_all_children(api, &task.block_id)?.iter().filter_map(child_id).collect();
+        let mut last_input = Instant::now();
+        let mut inputs = 0;
+
+        let end = 'session: loop {
+            if self.cancel.load(Ordering::SeqCst) || self.closing.load(Ordering::SeqCst) {
+                break SessionEnd::Interrupted;
+            }
+            if pty.try_wait().is_some() {
+                break SessionEnd::Exited;
+            }
+            if idle_timeout.is_some_and(|t| last_input.elapsed() >= t) {
+                break SessionEnd::Idle;
+            }
+            // Output that arrived after the last answer was posted.
+            let late = render_terminal(&pty.read_until_quiet(Duration::ZERO, Duration::ZERO));
+            if !late.is_empty() {
+                seen.extend(append_session_output(api, &task.block_id, &late)?);
+            }
+            let children = match fetch_all_children(api, &task.block_id) {
+                Ok(children) => children,
+                Err(e) => {
+                    eprintln!("[!] Failed to read input for session {}: {e:#}", task.block_id);
+                    std::thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            for child in &children {
+                let Some(id) = child_id(child) else { continue };
+                if !seen.insert(id) {
+                    continue;
+                }
+                let Some(text) = block_text(child) else { continue };
+                let text = text.trim();
+                if text.is_empty() {
+                    continue;
+                }
+                // Only the requester may type into their session, and only
+                // input nobody else has edited since.
+                let author_id = child["created_by"]["id"].as_str().unwrap_or("");
+                let editor_id = child["last_edited_by"]["id"].as_str().unwrap_or(author_id);
+                let other = [author_id, editor_id].into_iter().find(|id| task.created_by_id.as_deref() != Some(id));
+                if let Some(other) = other {
+                    let who = self
+                        .users
+                        .get(api, other)
+                        .ok()
+                        .flatten()
+                        .and_then(|u| u.email.or(u.name))
+                        .unwrap_or_else(|| other.to_string());
+                    let note = format!("[input from {} ignored: only {} can use this session]", who, requester);
+                    seen.extend(append_session_output(api, &task.block_id, &note)?);
+                    continue;
+                }
+                if is_session_close(text) {
+                    break 'session SessionEnd::Closed;
+                }
+                {
+                    let _guard = self.log_lock.lock().unwrap_or_else(|e| e.into_inner());
+                    write_command_log_with_dir(&self.logs.command_dir, &requester, &format!("[{}] {}", label, text))?;
+                }
+                pty.send_line(text)?;
+                inputs += 1;
+                last_input = Instant::now();
+                let mut answer = render_terminal(&pty.read_until_quiet(SESSION_QUIET, SESSION_MAX_WAIT));
+                if answer.is_empty() {
+                    answer.push_str("(no output)");
+                }
+                seen.extend(append_session_output(api, &task.block_id, &answer)?);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        let exit = match end {
+            SessionEnd::Exited => pty.try_wait(),
+            _ => pty.kill(),
+        };
+        let rest = render_terminal(&pty.read_until_quiet(Duration::from_millis(100), Duration::from_millis(500)));
+        let duration = started.elapsed();
+        let exit_code = exit.and_then(|s| s.code());
+        let signal = exit.and_then(exit_signal);
+        let (reason, status) = match end {
+            SessionEnd::Exited if exit_code == Some(0) => ("program exited".to_string(), "success"),
+            SessionEnd::Exited => ("program exited".to_string(), "failed"),
+            SessionEnd::Closed => ("closed".to_string(), "success"),
+            SessionEnd::Idle => (
+                format!("closed after {} s without input", idle_timeout.unwrap_or_default().as_secs()),
+                "timeout",
+            ),
+            SessionEnd::Interrupted => ("closed: agent shutting down".to_string(), "interrupted"),
+        };
+        let show = |v: Option<i32>| v.map(|v| v.to_string()).unwrap_or_else(|| "none".into());
+        let mut output = rest;
+        if !output.is_empty() && !output.ends_with('\n') {
+            output.push('\n');
+        }
+        output.push_str(&format!(
+            "[session {} | exit_code={} | signal={} | duration={:.2}s | inputs={}]",
+            reason,
+            show(exit_code),
+            show(signal),
+            duration.as_secs_f64(),
+            inputs
+        ));
+        append_session_output(api, &task.block_id, &output)?;
+        let mut details = vec![
+            ("exit_code".to_string(), show(exit_code)),
+            ("signal".to_string(), show(signal)),
+            ("duration_ms".to_string(), duration.as_millis().to_string()),
+            ("inputs".to_string(), inputs.to_string()),
+        ];
+        details.extend(approved_by);
+        self.write_logs(&task, &requester, &label, status, details)?;
+        Ok(HandledTask {
+            task,
+            requester,
+            status: status.to_string(),
+            executed: true,
+            output,
+            result: None,
+            approver,
+        })
+    }
+
+    /// Handles `cd` and `export` without a shell by updating the requester's
+    /// session; returns the text to post and the audit status.
+    fn run_builtin(&self, requester: &str, builtin: Builtin, opts: &ExecOptions) -> (String, &'static str) {
+        match builtin {
+            Builtin::Cd(target) => {
+                let cwd = opts
+                    .cwd
+                    .clone()
+                    .or_else(|| opts.run_as.as_ref().map(|id| id.home.clone()))
+                    .or_else(|| std::env::current_dir().ok())
+                    .unwrap_or_else(|| PathBuf::from("/"));
+                let home = opts
+                    .run_as
+                    .as_ref()
+                    .map(|id| id.home.clone())
+                    .or_else(|| std::env::var_os("HOME").map(PathBuf::from))
+                    .unwrap_or_else(|| cwd.clone());
+                match resolve_cd(target.as_deref(), &cwd, &home, opts.run_as.as_ref()) {
+                    Ok(dir) => {
+                        let text = format!("cwd: {}", dir.display());
+                        self.sessions.update(requester, |s| s.cwd = Some(dir));
+                        (text, "success")
+                    }
+                    Err(e) => (format!("{e:#}"), "failed"),
+                }
+            }
+            Builtin::Export(vars) => {
+                let names: Vec<&str> = vars.iter().map(|(k, _)| k.as_str()).collect();
+                let text = format!("exported: {}", names.join(", "));
+                self.sessions.update(requester, |s| s.env.extend(vars.iter().cloned()));
+                (text, "success")
+            }
+        }
+    }
+
+    /// Past the upload threshold, saves `output` locally, uploads it and
+    /// returns a short preview to post inline instead. Otherwise (or if the
+    /// upload fails) returns the output capped to the inline limit.
+    fn attach_large_output(&self, task: &CommandTask, output: String) -> (String, Option<UploadedFile>) {
+        let policy = self.outputs;
+        if policy.upload_threshold == 0 {
+            // The executor already applied the inline cap.
+            return (output, None);
+        }
+        let inline = |text: String| match policy.max_inline {
+            0 => text,
+            cap => cap_output(text, cap).0,
+        };
+        if output.len() <= policy.upload_threshold {
+            return (inline(output), None);
+        }
+
+        let saved = save_output(&self.logs.output_dir, &task.block_id, &output)
+            .map_err(|e| eprintln!("[!] Failed to save output of block {}: {e:#}", task.block_id))
+            .ok();
+        if let Some(max_age) = policy.retention {
+            if let Err(e) = prune_outputs(&self.logs.output_dir, max_age) {
+                eprintln!("[!] Failed to prune saved outputs: {e:#}");
+            }
+        }
+        let name = saved
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| format!("{}.log", task.block_id));
+
+        let total = output.len();
+        match self.api.upload_file(&name, "text/plain", output.clone().into_bytes()) {
+            Ok(id) => {
+                let (mut preview, _) = cap_output(output, UPLOAD_PREVIEW_BYTES);
+                preview.push_str(&format!("\n... [full output ({} bytes) attached as {}]\n", total, name));
+                (preview, Some(UploadedFile { id, name }))
+            }
+            Err(e) => {
+                eprintln!("[!] Failed to upload output of block {}: {e:#}", task.block_id);
+                let mut text = inline(output);
+                if let Some(path) = &saved {
+                    text.push_str(&format!("\n... [upload failed; full output kept at {}]\n", path.display()));
+                }
+                (text, None)
+            }
+        }
+    }
+}
+
+/// The polling agent: scans the configured page, checks permissions, hands
+/// commands to a bounded pool of workers and writes results back. It never
+/// reads stdin or exits the process, so it can be embedded in a supervisor.
+pub struct Agent {
+    api: Arc<dyn NotionApi>,
+    access: AccessFile,
+    config: Config,
+    page_id: String,
+    worker: Worker,
+    scheduler: Scheduler<Job>,
+    /// Block ids that are queued or running, so rescans skip them.
+    in_flight: HashSet<String>,
+    /// Interactive sessions currently open.
+    sessions_open: usize,
+    done_tx: Sender<Finished>,
+    done_rx: Receiver<Finished>,
+    status: NodeStatus,
+    status_block: Option<String>,
+    last_heartbeat: Option<Instant>,
+    /// Time source for the heartbeat interval.
+    clock: Clock,
+    /// access.json and storage.json, reloaded when they change.
+    access_watch: Option<FileWatch>,
+    config_watch: Option<FileWatch>,
+    /// Refuse reloaded access files with lint warnings.
+    strict_access: bool,
+    /// Recent command starts per requester, for rate limits and quotas.
+    usage: Usage,
+    /// Approval requests posted, by command block id.
+    approvals: HashMap<String, approval::Request>,
+}
+
+impl Agent {
+    pub fn new(config: Config, access: AccessFile, api: Arc<dyn NotionApi>) -> Result<Self> {
+        let page_id = extract_page_id(&config.page_url)?;
+        let (done_tx, done_rx) = mpsc::channel();
+        let worker = Worker {
+            api: api.clone(),
+            logs: LogPaths::default(),
+            log_lock: Arc::new(Mutex::new(())),
+            cancel: Arc::new(AtomicBool::new(false)),
+            closing: Arc::new(AtomicBool::new(false)),
+            outputs: OutputPolicy::from_config(&config),
+            sessions: Sessions::default(),
+            users: Arc::new(UserCache::new(Duration::from_secs(config.user_cache_secs))),
+        };
+        let scheduler =
+            Scheduler::new(config.max_concurrent_commands, config.max_concurrent_per_user);
+        Ok(Self {
+            api,
+            access,
+            config,
+            page_id,
+            worker,
+            scheduler,
+            in_flight: HashSet::new(),
+            sessions_open: 0,
+            done_tx,
+            done_rx,
+            status: NodeStatus::new(),
+            status_block: None,
+            last_heartbeat: None,
+            clock: Clock::system(),
+            access_watch: None,
+            config_watch: None,
+            strict_access: false,
+            usage: Usage::default(),
+            approvals: HashMap::new(),
+        })
+    }
+
+    /// Redirects the command and audit logs and saved outputs (defaults to
+    /// `./logs`, `./log` and `.notionSSH/outputs`).
+    pub fn with_log_paths(mut self, logs: LogPaths) -> Self {
+        self.worker.logs = logs;
+        self
+    }
+
+    /// Measures the heartbeat interval on `clock` instead of wall time.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Reloads the access policy from `path` whenever the file changes.
+    pub fn watch_access(mut self, path: impl Into<PathBuf>) -> Self {
+        self.access_watch = Some(FileWatch::new(path));
+        self
+    }
+
+    /// Keeps the current policy when a reloaded access file has lint
+    /// warnings, not only when it fails to load.
+    pub fn strict_access(mut self, strict: bool) -> Self {
+        self.strict_access = strict;
+        self
+    }
+
+    /// Reloads the runtime settings from the storage file at `path`
+    /// whenever it changes.
+    pub fn watch_config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_watch = Some(FileWatch::new(path));
+        self
+    }
+
+    pub fn page_id(&self) -> &str {
+        &self.page_id
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn access(&self) -> &AccessFile {
+        &self.access
+    }
+
+    pub fn status(&self) -> &NodeStatus {
+        &self.status
+    }
+
+    /// Collects finished tasks, scans the page once and dispatches queued
+    /// commands to free workers. Does not wait for commands to finish.
+    pub fn tick(&mut self) -> Result<TickReport> {
+        self.tick_inner(None)
+    }
+
+    /// Keeps dispatching and collecting until nothing is queued or running.
+    /// Interactive sessions are not waited for.
+    pub fn wait_idle(&mut self) -> TickReport {
+        let mut report = TickReport::default();
+        loop {
+            self.collect(&mut report);
+            self.dispatch(&mut report);
+            if self.scheduler.running() == 0 && self.scheduler.queued() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        self.status.queue_depth = 0;
+        report
+    }
+
+    /// Calls `tick` once per poll interval until `shutdown` is requested.
+    /// Queued tasks are left for the next start; running commands get the
+    /// shutdown grace period to finish and their results are always recorded.
+    /// Finally the status block is updated to say the node went offline.
+    pub fn run_until(&mut self, shutdown: &Shutdown) -> Result<()> {
+        self.heartbeat(true);
+        while !shutdown.is_requested() {
+            self.tick_inner(Some(shutdown))?;
+            let until = Instant::now() + POLL_INTERVAL;
+            while Instant::now() < until && !shutdown.is_requested() {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+
+        self.scheduler.clear_queued();
+        self.worker.closing.store(true, Ordering::SeqCst);
+        let mut report = TickReport::default();
+        while self.scheduler.running() > 0 || self.sessions_open > 0 {
+            if shutdown.deadline_passed() {
+                self.worker.cancel.store(true, Ordering::SeqCst);
+            }
+            self.collect(&mut report);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        let text = self.status.render_offline();
+        if let Err(e) = self.publish_status(&text) {
+            eprintln!("[!] Failed to update status block: {e:#}");
+        }
+        Ok(())
+    }
+
+    /// Rewrites the status block when the heartbeat interval has elapsed
+    /// (or unconditionally with `force`). Failures are logged, not returned.
+    fn heartbeat(&mut self, force: bool) {
+        let interval = Duration::from_secs(self.config.heartbeat_interval_secs);
+        let now = self.clock.now();
+        let due = force || self.last_heartbeat.is_none_or(|t| now.duration_since(t) >= interval);
+        if !due {
+            return;
+        }
+        self.last_heartbeat = Some(now);
+        let text = self.status.render_online();
+        if let Err(e) = self.publish_status(&text) {
+            eprintln!("[!] Failed to update status block: {e:#}");
+            // The block may have been deleted; look it up again next time.
+            self.status_block = None;
+        }
+    }
+
+    fn publish_status(&mut self, text: &str) -> Result<()> {
+        let api = self.api.as_ref();
+        let id = match &self.status_block {
+            Some(id) => id.clone(),
+            None => {
+                let id = ensure_status_block(api, &self.page_id)?;
+                self.status_block = Some(id.clone());
+                id
+            }
+        };
+        update_status_block(api, &id, text)
+    }
+
+    /// Swaps in access.json and storage.json when they were written since
+    /// the last check and announces it on the status block. A file that
+    /// fails to load keeps the current settings and is reported as an error.
+    fn reload_changed(&mut self) {
+        let mut announce = false;
+        if let Some(path) = self.access_watch.as_mut().and_then(|w| w.changed().then(|| w.path().to_path_buf())) {
+            announce = true;
+            let loaded = load_from_path(&path).and_then(|access| {
+                let warnings = lint_file(&path)?;
+                for w in &warnings {
+                    eprintln!("[!] {}: {}", path.display(), w);
+                }
+                if self.strict_access && !warnings.is_empty() {
+                    return Err(anyhow!("{} lint warning(s) with strict access checking", warnings.len()));
+                }
+                Ok(access)
+            });
+            match loaded {
+                Ok(access) => {
+                    self.access = access;
+                    println!("[*] Reloaded {}", path.display());
+                    self.status.record_reload("access.json reloaded");
+                }
+                Err(e) => {
+                    let err = e.context(format!("{} not reloaded, keeping the previous policy", path.display()));
+                    eprintln!("[!] {err:#}");
+                    self.status.record_error(&err);
+                    self.status.record_reload("access.json rejected");
+                }
+            }
+        }
+        if let Some(path) = self.config_watch.as_mut().and_then(|w| w.changed().then(|| w.path().to_path_buf())) {
+            announce = true;
+            let loaded = load_from_storage_path(&path)
+                .and_then(|c| c.ok_or_else(|| anyhow!("file is missing or empty")));
+            match loaded {
+                Ok(config) => {
+                    let (changed, restart) = self.config.apply_reloadable(&config);
+                    self.scheduler
+                        .set_limits(self.config.max_concurrent_commands, self.config.max_concurrent_per_user);
+                    self.worker.outputs = OutputPolicy::from_config(&self.config);
+                    self.worker.users.set_ttl(Duration::from_secs(self.config.user_cache_secs));
+                    let mut note = format!("storage.json reloaded: changed=[{}]", changed.join(", "));
+                    if !restart.is_empty() {
+                        note.push_str(&format!(" needs restart=[{}]", restart.join(", ")));
+                    }
+                    println!("[*] Reloaded {} ({})", path.display(), note);
+                    self.status.record_reload(&note);
+                }
+                Err(e) => {
+                    let err = e.context(format!("{} not reloaded, keeping the previous settings", path.display()));
+                    eprintln!("[!] {err:#}");
+                    self.status.record_error(&err);
+                    self.status.record_reload("storage.json rejected");
+                }
+            }
+        }
+        if announce {
+            self.heartbeat(true);
+        }
+    }
+
+    fn tick_inner(&mut self, shutdown: Option<&Shutdown>) -> Result<TickReport> {
+        let mut report = TickReport::default();
+        self.reload_changed();
+        self.collect(&mut report);
+        // Stop accepting new tasks once shutdown has been requested.
+        if !shutdown.is_some_and(|s| s.is_requested()) {
+            match self.scan() {
+                Ok(jobs) => {
+                    self.status.last_poll = Some(Local::now());
+                    for job in jobs {
+                        let Some(job) = self.await_approval(job) else { continue };
+                        if job.interactive {
+                            self.start_session(job, &mut report);
+                            continue;
+                        }
+                        self.in_flight.insert(job.task.block_id.clone());
+                        let user = job.requester.clone();
+                        self.scheduler.enqueue(&user, job);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Failed to fetch children: {err:#}");
+                    report.fetch_error = Some(format!("{err:#}"));
+                    self.status.record_error(&err);
+                }
+            }
+            self.dispatch(&mut report);
+        }
+        self.status.queue_depth = self.scheduler.queued();
+        self.heartbeat(false);
+        Ok(report)
+    }
+
+    fn scan(&self) -> Result<Vec<Job>> {
+        let api = self.api.as_ref();
+        let mut jobs = Vec::new();
+        for b in fetch_all_children(api, &self.page_id)?.iter() {
+            let (task, interactive) = match parse_command_from_block(b) {
+                Some(task) => (task, false),
+                None => match parse_session_from_block(b) {
+                    Some(task) => (task, true),
+                    None => continue,
+                },
+            };
+            // Staged to_do commands wait until their box is checked.
+            if self.config.require_checked_todo && task.checked == Some(false) {
+                continue;
+            }
+            if self.in_flight.contains(&task.block_id)
+                || is_block_processed(api, &task.block_id).unwrap_or(false)
+            {
+                continue;
+            }
+            let requester =
+                self.worker.users.requester(api, &self.access, task.created_by_id.as_deref().unwrap_or(""))?;
+            let refusal = match self.identity_refusal(&requester) {
+                Some(refusal) => Some(refusal),
+                None => self.edit_refusal(&task, &requester)?,
+            };
+            jobs.push(Job { task, requester, interactive, approver: None, refusal });
+        }
+        Ok(jobs)
+    }
+
+    /// Refuses commands from bots and unidentified users when `unidentified`
+    /// in access.json says "deny" for them.
+    fn identity_refusal(&self, requester: &str) -> Option<Refusal> {
+        if unidentified_policy(&self.access, requester) != Some("deny") {
+            return None;
+        }
+        let who = if requester == UNKNOWN_REQUESTER {
+            "the requester could not be identified (no email is visible to the integration)"
+        } else {
+            "commands from bots are not accepted"
+        };
+        Some(Refusal::new(format!("permission denied: {}.", who), "identity"))
+    }
+
+    /// Refuses a command block that someone other than its author edited
+    /// last, unless `edited_blocks` is "stricter" and the editor may run the
+    /// command as well. Edits by this agent's own bot user, made when it
+    /// posts under the block, do not count.
+    fn edit_refusal(&self, task: &CommandTask, requester: &str) -> Result<Option<Refusal>> {
+        let (Some(author), Some(editor)) = (&task.created_by_id, &task.last_edited_by_id) else {
+            return Ok(None);
+        };
+        if author == editor {
+            return Ok(None);
+        }
+        let api = self.api.as_ref();
+        if editor == self.worker.users.own_id(api)? {
+            return Ok(None);
+        }
+        let editor = self.worker.users.requester(api, &self.access, editor)?;
+        Ok(match self.access.edited_blocks {
+            EditedBlocks::Reject => Some(Refusal::new(
+                format!(
+                    "permission denied: {} edited this command after {} wrote it. Write it in a new block to run it.",
+                    editor, requester
+                ),
+                "edited",
+            )),
+            EditedBlocks::Stricter if !decide(&self.access, &editor, &task.command).allowed => Some(Refusal::new(
+                format!("permission denied: {} last edited this command and may not run it.", editor),
+                "editor",
+            )),
+            EditedBlocks::Stricter => None,
+        })
+    }
+
+    /// Holds back commands that need a second person's approval: posts the
+    /// request under the block the first time, and lets the job through once
+    /// it is approved or has expired. Commands the requester may not run
+    /// anyway go straight on to be denied.
+    fn await_approval(&mut self, mut job: Job) -> Option<Job> {
+        let command = &job.task.command;
+        // A dry run only reports that approval would be needed.
+        if self.config.dry_run
+            || job.refusal.is_some()
+            || !self.access.approval.requires_approval(command)
+            || !decide(&self.access, &job.requester, command).allowed
+        {
+            return Some(job);
+        }
+        let api = self.api.as_ref();
+        let block_id = job.task.block_id.clone();
+        let now = Utc::now();
+        let asked = self.approvals.get(&block_id);
+        let state = fetch_all_children(api, &block_id).and_then(|children| {
+            approval::check(api, &self.worker.users, &self.access, asked, command, &job.requester, &children, now)
+        });
+        // Each request is posted for the command as read at that moment.
+        let asked = approval::Request {
+            command: command.clone(),
+            expires: approval::expiry(&self.access.approval, now),
+        };
+        let err = match state {
+            Ok(ApprovalState::Approved { approver }) => {
+                self.approvals.remove(&block_id);
+                job.approver = Some(approver);
+                return Some(job);
+            }
+            Ok(ApprovalState::Expired) => {
+                self.approvals.remove(&block_id);
+                let message = "approval expired: nobody approved this command in time. Write it again to ask anew.";
+                job.refusal = Some(Refusal::new(message, "approval_expired"));
+                return Some(job);
+            }
+            Ok(ApprovalState::Pending) => return None,
+            Ok(ApprovalState::Missing) => {
+                let request = approval::request_block(command, &job.requester, asked.expires, None);
+                match api.append_children(&block_id, vec![request]) {
+                    Ok(_) => {
+                        self.approvals.insert(block_id, asked);
+                        return None;
+                    }
+                    Err(e) => e,
+                }
+            }
+            Ok(ApprovalState::Stale { id, note }) => {
+                let request = approval::request_block(command, &job.requester, asked.expires, Some(note));
+                match api.update_block(&id, serde_json::json!({ "to_do": request["to_do"] })) {
+                    Ok(_) => {
+                        self.approvals.insert(block_id, asked);
+                        return None;
+                    }
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        let err = err.context(format!("approval of block {}", block_id));
+        eprintln!("[!] {err:#}");
+        self.status.record_error(&err);
+        None
+    }
+
+    /// Starts queued jobs while the scheduler has capacity. The permission
+    /// check happens here, right before the job runs.
+    fn dispatch(&mut self, report: &mut TickReport) {
+        while let Some((user, job)) = self.scheduler.start_next() {
+            let Job { task, requester, approver, refusal, .. } = job;
+            let opts = match refusal {
+                Some(refusal) => Err(refusal),
+                None if self.config.dry_run => Err(Refusal::dry_run(self.dry_run_report(&task, &requester))),
+                None => self.exec_options(&task, &requester),
+            };
+            let worker = self.worker.clone();
+            let tx = self.done_tx.clone();
+            std::thread::spawn(move || {
+                let block_id = task.block_id.clone();
+                let result = worker.run(task, requester, approver, opts);
+                let _ = tx.send(Finished { user, block_id, interactive: false, result });
+            });
+            report.dispatched += 1;
+        }
+    }
+
+    /// Starts an interactive session on its own thread, outside the
+    /// scheduler, subject to the same permission check on the program. Over
+    /// `max_sessions` the block is left for a later scan.
+    fn start_session(&mut self, job: Job, report: &mut TickReport) {
+        if self.sessions_open >= self.config.max_sessions {
+            return;
+        }
+        let Job { task, requester, approver, refusal, .. } = job;
+        let opts = match refusal {
+            Some(refusal) => Err(refusal),
+            None if self.config.dry_run => Err(Refusal::dry_run(self.dry_run_report(&task, &requester))),
+            None => self.exec_options(&task, &requester),
+        };
+        let opts = opts.map(|opts| ExecOptions {
+            timeout: None,
+            progress_interval: None,
+            max_output_bytes: None,
+            capture_state: false,
+            ..opts
+        });
+        let idle = self.config.session_idle_timeout_secs;
+        let idle_timeout = (idle > 0).then(|| Duration::from_secs(idle));
+        self.in_flight.insert(task.block_id.clone());
+        self.sessions_open += 1;
+        let worker = self.worker.clone();
+        let tx = self.done_tx.clone();
+        std::thread::spawn(move || {
+            let block_id = task.block_id.clone();
+            let user = requester.clone();
+            let result = worker.run_session(task, requester, approver, opts, idle_timeout);
+            let _ = tx.send(Finished { user, block_id, interactive: true, result });
+        });
+        report.dispatched += 1;
+    }
+
+    /// Permission check and per-requester settings for a job about to run;
+    /// `Err` carries the message posted instead of running it. A job that
+    /// passes counts towards the requester's rate limit and quota.
+    fn exec_options(&mut self, task: &CommandTask, requester: &str) -> Result<ExecOptions, Refusal> {
+        let now = Utc::now();
+        let opts = self.resolve_options(task, requester, now)?;
+        self.usage.record(requester, now);
+        Ok(opts)
+    }
+
+    /// What running `task` would do, without running it: the permission
+    /// decision and, if allowed, the shell, directory, account and sandbox.
+    fn dry_run_report(&self, task: &CommandTask, requester: &str) -> String {
+        let decision = decide(&self.access, requester, &task.command);
+        let roles = if decision.roles.is_empty() { "-".to_string() } else { decision.roles.join(", ") };
+        let mut lines = vec!["[dry run: not executed]".to_string(), format!("roles: {}", roles)];
+        match self.resolve_options(task, requester, Utc::now()) {
+            Err(refusal) => lines.push(format!("decision: denied ({})", refusal.message)),
+            Ok(opts) => {
+                lines.push(format!("decision: allowed ({})", decision.explanation));
+                if self.access.approval.requires_approval(&task.command) {
+                    let role = &self.access.approval.approver_role;
+                    lines.push(format!("approval: required from a manager or '{}' member", role));
+                }
+                // The first shell tried; the command itself is the last argument.
+                let shell = shell_attempts("")
+                    .into_iter()
+                    .next()
+                    .map(|(prog, mut args)| {
+                        args.pop();
+                        format!("{} {}", prog, args.join(" "))
+                    })
+                    .unwrap_or_else(|| "none available".to_string());
+                lines.push(format!("shell: {}", shell.trim_end()));
+                let cwd = opts
+                    .cwd
+                    .or_else(|| opts.run_as.as_ref().map(|id| id.home.clone()))
+                    .or_else(|| std::env::current_dir().ok())
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                lines.push(format!("cwd: {}", cwd));
+                let user = match &opts.run_as {
+                    Some(id) => format!("{} (uid {}, gid {})", id.user, id.uid, id.gid),
+                    None => "the agent's own account".to_string(),
+                };
+                lines.push(format!("run as: {}", user));
+                lines.push(format!("sandbox: {}", sandbox_name_for(&self.access, requester).unwrap_or("none")));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Permission check and per-requester settings for a job starting at
+    /// `now`, leaving usage untouched.
+    fn resolve_options(
+        &self,
+        task: &CommandTask,
+        requester: &str,
+        now: DateTime<Utc>,
+    ) -> Result<ExecOptions, Refusal> {
+        let decision = decide_at(&self.access, requester, &task.command, now, &self.usage);
+        match decision.denied_by {
+            None => {}
+            Some(DeniedBy::Rule) => {
+                if !reveals_reason(&self.access, requester) {
+                    return Err(Refusal::new(DENIED_MESSAGE, DeniedBy::Rule.as_str()));
+                }
+                let mut message = format!("{} Denied by {}", DENIED_MESSAGE, decision.explanation);
+                if let Some(reason) = decision.reason {
+                    message.push_str(&format!(": {}", reason));
+                }
+                return Err(Refusal::new(message, DeniedBy::Rule.as_str()));
+            }
+            Some(limit) => return Err(Refusal::new(decision.explanation, limit.as_str())),
+        }
+        let run_as = match run_as_for(&self.access, requester) {
+            RunAsDecision::Agent => None,
+            RunAsDecision::Mapped(r) => Some(privilege::resolve(&r.user, r.group.as_deref()).map_err(|e| {
+                Refusal::new(format!("permission denied: cannot run as '{}': {e:#}", r.user), "run_as")
+            })?),
+            RunAsDecision::Unmapped => return Err(Refusal::new(UNMAPPED_MESSAGE, "run_as")),
+        };
+        let sandbox = sandbox_for(&self.access, requester)
+            .map_err(|e| Refusal::new(format!("permission denied: {e:#}"), "sandbox"))?
+            .cloned();
+        if sandbox.is_some() && !crate::sandbox::supported() {
+            let message = "permission denied: this role must run sandboxed, which needs Linux.";
+            return Err(Refusal::new(message, "sandbox"));
+        }
+        let timeout = effective_timeout_secs(&self.access, requester, self.config.command_timeout_secs);
+        let stream = self.config.stream_interval_secs;
+        // With uploads on, the worker needs the full output and caps it itself.
+        let cap = match self.config.upload_threshold_bytes {
+            0 => self.config.max_output_bytes,
+            _ => 0,
+        };
+        // Restricted roles may not change which programs or libraries their
+        // later commands load.
+        let restricted = is_restricted(&self.access, requester);
+        if let (true, Some(Builtin::Export(vars))) = (restricted, parse_builtin(&task.command)) {
+            if let Some((name, _)) = vars.iter().find(|(k, _)| is_protected_var(k)) {
+                let message = format!("permission denied: your role cannot export {}.", name);
+                return Err(Refusal::new(message, "export"));
+            }
+        }
+        // Where the requester last `cd`ed to, else the role's directory.
+        let mut session = self.worker.sessions.get(requester);
+        if restricted {
+            session.env.retain(|k, _| !is_protected_var(k));
+        }
+        let cwd = session
+            .cwd
+            .or_else(|| working_dir_for(&self.access, requester).map(PathBuf::from));
+        Ok(ExecOptions {
+            timeout: (timeout > 0).then(|| Duration::from_secs(timeout)),
+            progress_interval: (stream > 0).then(|| Duration::from_secs(stream)),
+            max_output_bytes: (cap > 0).then_some(cap),
+            run_as,
+            sandbox,
+            cwd,
+            env: session.env.into_iter().collect(),
+            capture_state: true,
+        })
+    }
+
+    fn collect(&mut self, report: &mut TickReport) {
+        while let Ok(done) = self.done_rx.try_recv() {
+            if done.interactive {
+                self.sessions_open -= 1;
+            } else {
+                self.scheduler.finish(&done.user);
+            }
+            self.in_flight.remove(&done.block_id);
+            match done.result {
+                Ok(handled) => {
+                    if handled.executed {
+                        self.status.commands_executed += 1;
+                    }
+                    report.handled.push(handled);
+                }
+                Err(e) => {
+                    eprintln!("[!] Failed to handle block {}: {e:#}", done.block_id);
+                    self.status.record_error(&e);
+                    report.errors.push(format!("{e:#}"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::PermRule;
+    use crate::fake::FakeNotion;
+    use crate::notion::{find_status_block, STATUS_MARKER};
+    use crate::parser::children_contains_marker;
+    use tempfile::{tempdir, TempDir};
+
+    const PAGE_URL: &str = "https://www.notion.so/test-0123456789abcdef0123456789abcdef";
+    const PAGE_ID: &str = "01234567-89ab-cdef-0123-456789abcdef";
+
+    fn agent_with(access: AccessFile) -> (Agent, Arc<FakeNotion>, TempDir) {
+        agent_with_config(Config::new("k", PAGE_URL), access)
+    }
+
+    fn agent_with_config(config: Config, access: AccessFile) -> (Agent, Arc<FakeNotion>, TempDir) {
+        let dir = tempdir().unwrap();
+        let fake = Arc::new(FakeNotion::new());
+        fake.add_user("u1", "u@example.com");
+        fake.add_user("u2", "v@example.com");
+        let agent = Agent::new(config, access, fake.clone())
+            .unwrap()
+            .with_log_paths(LogPaths::under(dir.path()));
+        (agent, fake, dir)
+    }
+
+    /// `agent_with(access)` plus one command block written by u1.
+    fn agent_with_command(access: AccessFile, text: &str) -> (Agent, Arc<FakeNotion>, TempDir, String) {
+        let (agent, fake, dir) = agent_with(access);
+        let block = fake.add_paragraph(PAGE_ID, text, "u1");
+        (agent, fake, dir, block)
+    }
+
+    /// One tick, then waits for everything it started.
+    fn run_tick(agent: &mut Agent) -> TickReport {
+        agent.tick().unwrap();
+        agent.wait_idle()
+    }
+
+    fn audit(dir: &TempDir) -> String {
+        std::fs::read_to_string(LogPaths::under(dir.path()).audit_file).unwrap()
+    }
+
+    /// A command that keeps running until the file `gate` exists, then runs
+    /// `then`; stands in for a slow command without sleeping for long. It
+    /// creates `<gate>.started` once its shell is up.
+    fn gated(gate: &std::path::Path, then: &str) -> String {
+        let gate = gate.display();
+        format!("!(touch {gate}.started; while [ ! -e {gate} ]; do sleep 0.05; done; {then})")
+    }
+
+    #[test]
+    fn tick_executes_and_marks_commands() {
+        let (mut agent, fake, dir, cmd_block) = agent_with_command(AccessFile::default(), "!(echo hello)");
+        fake.add_paragraph(PAGE_ID, "just a note", "u1");
+
+        assert_eq!(agent.tick().unwrap().dispatched, 1);
+        let report = agent.wait_idle();
+        assert_eq!(report.handled.len(), 1);
+        assert_eq!(report.handled[0].requester, "u@example.com");
+        assert_eq!(report.handled[0].status, "success");
+        let children = fake.children(&cmd_block);
+        assert!(children_contains_marker(&children));
+        assert!(children[0].to_string().contains("hello"));
+        assert!(children[0].to_string().contains("executed_by=u@example.com"));
+
+        // Already marked blocks are not executed twice.
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        let audit = audit(&dir);
+        assert_eq!(audit.lines().count(), 1);
+        assert!(audit.contains("echo hello , u@example.com"));
+        assert!(audit.contains(" , success , exit_code=0 signal=none duration_ms="));
+        assert_eq!(report.handled[0].result.as_ref().unwrap().stdout, "hello\n");
+    }
+
+    #[test]
+    fn tick_denies_without_permission() {
+        let mut access = AccessFile::default();
+        access.perms.insert("default".into(), PermRule::default());
+        let (mut agent, fake, _dir, cmd_block) = agent_with_command(access, "!(echo hello)");
+
+        let report = run_tick(&mut agent);
+        assert_eq!(report.handled[0].status, "failed");
+        assert!(!report.handled[0].executed);
+        assert!(fake.children(&cmd_block)[0].to_string().contains("permission denied"));
+    }
+
+    #[test]
+    fn access_and_storage_files_are_reloaded_when_changed() {
+        let (agent, fake, dir) = agent_with(AccessFile::default());
+        let access_path = dir.path().join("access.json");
+        let storage_path = dir.path().join("storage.json");
+        std::fs::write(&storage_path, serde_json::to_string(agent.config()).unwrap()).unwrap();
+        let mut agent = agent.watch_access(&access_path).watch_config(&storage_path);
+
+        // Only `echo` allowed from now on; `ls` is denied.
+        let mut restricted = AccessFile::default();
+        restricted.perms.insert("default".into(), PermRule { allow: vec!["echo".into()], ..Default::default() });
+        std::fs::write(&access_path, serde_json::to_string(&restricted).unwrap()).unwrap();
+        let denied = fake.add_paragraph(PAGE_ID, "!(ls)", "u1");
+        run_tick(&mut agent);
+        assert!(fake.children(&denied)[0].to_string().contains("permission denied"));
+        let status = find_status_block(fake.as_ref(), PAGE_ID).unwrap().unwrap();
+        assert!(fake.block(&status).unwrap().to_string().contains("access.json reloaded"));
+
+        // A broken file keeps the previous policy and reports why.
+        std::fs::write(&access_path, "{\"perms\": {").unwrap();
+        let allowed = fake.add_paragraph(PAGE_ID, "!(echo still)", "u1");
+        run_tick(&mut agent);
+        assert!(fake.children(&allowed)[0].to_string().contains("still"));
+        assert_eq!(agent.access().perms["default"].allow, vec!["echo".into()]);
+        assert!(agent.status().last_error.as_deref().unwrap().contains("keeping the previous policy"));
+
+        let mut config = agent.config().clone();
+        config.command_timeout_secs = 5;
+        config.api_key = "other".into();
+        std::fs::write(&storage_path, serde_json::to_string(&config).unwrap()).unwrap();
+        agent.tick().unwrap();
+        assert_eq!(agent.config().command_timeout_secs, 5);
+        assert_eq!(agent.config().api_key, "k");
+        let reload = agent.status().last_reload.as_deref().unwrap();
+        assert!(reload.contains("changed=[command_timeout_secs] needs restart=[api_key]"), "{reload}");
+    }
+
+    #[test]
+    fn strict_access_rejects_reloaded_files_with_warnings() {
+        let (agent, _fake, dir) = agent_with(AccessFile::default());
+        let path = dir.path().join("access.json");
+        let mut agent = agent.watch_access(&path).strict_access(true);
+
+        let mut typo = AccessFile::default();
+        typo.perms.insert("default".into(), PermRule { allow: vec!["ls".into()], ..Default::default() });
+        typo.emails.insert("u@example.com".into(), "opps".into());
+        std::fs::write(&path, serde_json::to_string(&typo).unwrap()).unwrap();
+        agent.tick().unwrap();
+        assert!(agent.access().emails.is_empty());
+        assert!(agent.status().last_error.as_deref().unwrap().contains("strict access checking"));
+    }
+
+    #[test]
+    fn denial_names_the_rule_only_when_the_role_reveals_it() {
+        let mut access = AccessFile::default();
+        let deny = serde_json::from_str(r#"{"match": "cat", "reason": "secrets live here"}"#).unwrap();
+        access.perms.insert("default".into(), PermRule { deny: vec![deny], ..access.perms["default"].clone() });
+        access.emails.insert("v@example.com".into(), "open".into());
+        access.perms.insert(
+            "open".into(),
+            PermRule { extends: vec!["default".into()], reveal: Some(true), ..Default::default() },
+        );
+        let (mut agent, fake, _dir, hidden) = agent_with_command(access, "!(cat /etc/shadow)");
+        let shown = fake.add_paragraph(PAGE_ID, "!(cat /etc/shadow)", "u2");
+
+        run_tick(&mut agent);
+        let hidden = fake.children(&hidden)[0].to_string();
+        assert!(hidden.contains("permission denied") && !hidden.contains("secrets"));
+        let shown = fake.children(&shown)[0].to_string();
+        assert!(shown.contains(r#"Denied by deny \"cat\" of role \"default\""#), "{shown}");
+        assert!(shown.contains("secrets live here"));
+    }
+
+    #[test]
+    fn rate_limit_denials_are_posted_and_audited() {
+        let mut access = AccessFile::default();
+        let limit = crate::access::RateLimit { per_minute: Some(1), per_hour: None };
+        access.perms.insert(
+            "default".into(),
+            PermRule { rate_limit: Some(limit), ..access.perms["default"].clone() },
+        );
+        let (mut agent, fake, dir, first) = agent_with_command(access, "!(echo one)");
+        let second = fake.add_paragraph(PAGE_ID, "!(echo two)", "u1");
+
+        run_tick(&mut agent);
+        assert!(fake.children(&first)[0].to_string().contains("one"));
+        let denied = fake.children(&second)[0].to_string();
+        assert!(denied.contains("rate limit exceeded: at most 1 commands per minute"), "{denied}");
+        let audit = audit(&dir);
+        assert!(audit.lines().any(|l| l.contains("echo two") && l.contains("denied=rate_limit")), "{audit}");
+    }
+
+    #[test]
+    fn dangerous_commands_wait_for_a_second_person() {
+        let mut access = AccessFile::default();
+        access.approval.commands = vec!["echo danger*".into()];
+        access.perms.insert("approvers".into(), PermRule { extends: vec!["default".into()], ..Default::default() });
+        access.emails.insert("v@example.com".into(), "approvers".into());
+        let (mut agent, fake, dir, cmd_block) = agent_with_command(access, "!(echo dangerous)");
+
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        let request = fake.children(&cmd_block)[0].clone();
+        let request_id = request["id"].as_str().unwrap().to_string();
+        assert!(block_text(&request).unwrap().contains("requested by u@example.com"));
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        assert_eq!(fake.children(&cmd_block).len(), 1);
+
+        let check_by = |user: &str| {
+            let mut to_do = fake.block(&request_id).unwrap()["to_do"].clone();
+            to_do["checked"] = true.into();
+            let patch = serde_json::json!({"to_do": to_do, "last_edited_by": {"id": user}});
+            fake.update_block(&request_id, patch).unwrap();
+        };
+        check_by("u1");
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        assert!(block_text(&fake.block(&request_id).unwrap()).unwrap().contains("cannot approve their own"));
+
+        // Approved, then the requester swaps the command: asked anew.
+        check_by("u2");
+        let text = "!(echo dangerous; echo swapped)";
+        let rich_text = serde_json::json!([{"type": "text", "text": {"content": text}, "plain_text": text}]);
+        fake.update_block(&cmd_block, serde_json::json!({"paragraph": {"rich_text": rich_text}})).unwrap();
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        let request = fake.block(&request_id).unwrap();
+        assert!(block_text(&request).unwrap().contains("the command changed"));
+        assert!(block_text(&request).unwrap().contains("`echo dangerous; echo swapped`"));
+        assert_eq!(request["to_do"]["checked"], false);
+
+        check_by("u2");
+        assert_eq!(agent.tick().unwrap().dispatched, 1);
+        let report = agent.wait_idle();
+        assert_eq!(report.handled[0].approver.as_deref(), Some("v@example.com"));
+        assert!(fake.children(&cmd_block)[1].to_string().contains("swapped"));
+        let audit = audit(&dir);
+        assert!(audit.contains("echo dangerous; echo swapped , u@example.com"));
+        assert!(audit.contains("approved_by=v@example.com"), "{audit}");
+    }
+
+    #[test]
+    fn staged_to_dos_wait_for_their_box_and_dry_runs_only_report() {
+        let mut config = Config::new("k", PAGE_URL);
+        config.require_checked_todo = true;
+        config.dry_run = true;
+        let mut access = AccessFile::default();
+        access.perms.insert("default".into(), PermRule { allow: vec!["echo".into()], ..Default::default() });
+        let (mut agent, fake, dir) = agent_with_config(config, access);
+        let staged = fake.add_todo(PAGE_ID, "!(echo armed > /tmp/never)", "u1", false);
+        let denied = fake.add_paragraph(PAGE_ID, "!(rm -rf /tmp/x)", "u1");
+
+        assert_eq!(agent.tick().unwrap().dispatched, 1);
+        agent.wait_idle();
+        assert!(fake.children(&staged).is_empty());
+        let report = fake.children(&denied)[0].to_string();
+        assert!(report.contains("[dry run: not executed]") && report.contains("decision: denied"), "{report}");
+
+        let mut to_do = fake.block(&staged).unwrap()["to_do"].clone();
+        to_do["checked"] = true.into();
+        fake.update_block(&staged, serde_json::json!({ "to_do": to_do })).unwrap();
+        assert_eq!(agent.tick().unwrap().dispatched, 1);
+        let handled = agent.wait_idle();
+        assert_eq!(handled.handled[0].status, "dry_run");
+        assert!(!handled.handled[0].executed);
+        let report = fake.children(&staged)[0].to_string();
+        assert!(report.contains("decision: allowed") && report.contains("run as: "), "{report}");
+        assert!(report.contains("shell: ") && report.contains("cwd: "));
+        let audit = audit(&dir);
+        assert!(audit.contains(" , dry_run"), "{audit}");
+    }
+
+    #[test]
+    fn blocks_edited_by_someone_else_are_refused_or_checked_for_both() {
+        let mut access = AccessFile::default();
+        access.perm_manager.push("u@example.com".into());
+        access.perms.get_mut("default").unwrap().deny = vec!["echo secret*".into()];
+        let edited_by_u2 = |fake: &FakeNotion, text: &str| {
+            let id = fake.add_paragraph(PAGE_ID, text, "u1");
+            fake.update_block(&id, serde_json::json!({"last_edited_by": {"id": "u2"}})).unwrap();
+            id
+        };
+
+        let (mut agent, fake, dir) = agent_with(access.clone());
+        let edited = edited_by_u2(&fake, "!(echo hello)");
+        fake.add_raw_user(serde_json::json!({"object": "user", "id": "b1", "type": "bot", "bot": {}}));
+        let by_bot = fake.add_paragraph(PAGE_ID, "!(echo bot)", "u1");
+        fake.update_block(&by_bot, serde_json::json!({"last_edited_by": {"id": "b1"}})).unwrap();
+        let by_agent = fake.add_paragraph(PAGE_ID, "!(echo agent)", "u1");
+        fake.update_block(&by_agent, serde_json::json!({"last_edited_by": {"id": crate::fake::BOT_USER_ID}})).unwrap();
+        run_tick(&mut agent);
+        let denied = fake.children(&edited)[0].to_string();
+        assert!(denied.contains("v@example.com edited this command after u@example.com wrote it"), "{denied}");
+        assert!(fake.children(&by_bot)[0].to_string().contains("bot:b1 edited this command"));
+        assert!(fake.children(&by_agent)[0].to_string().contains("executed_by=u@example.com"));
+        let audit = audit(&dir);
+        assert!(audit.contains("denied=edited created_by=u1 last_edited_by=u2"), "{audit}");
+
+        access.edited_blocks = EditedBlocks::Stricter;
+        let (mut agent, fake, _dir) = agent_with(access);
+        let secret = edited_by_u2(&fake, "!(echo secret)");
+        let hello = edited_by_u2(&fake, "!(echo hello)");
+        run_tick(&mut agent);
+        assert!(fake.children(&secret)[0].to_string().contains("v@example.com last edited this command"));
+        assert!(fake.children(&hello)[0].to_string().contains("executed_by=u@example.com"));
+    }
+
+    #[test]
+    fn commands_that_fail_to_start_are_answered_once() {
+        let (mut agent, fake, dir) = agent_with(AccessFile::default());
+        // The process cannot be spawned with a NUL byte in its environment.
+        agent.worker.sessions.update("u@example.com", |s| {
+            s.env.insert("BAD".into(), "a\0b".into());
+        });
+        let cmd_block = fake.add_paragraph(PAGE_ID, "!(echo hello)", "u1");
+
+        let report = run_tick(&mut agent);
+        assert_eq!(report.handled[0].status, "failed");
+        let children = fake.children(&cmd_block);
+        assert!(children_contains_marker(&children));
+        assert!(children[0].to_string().contains("failed to start"), "{}", children[0]);
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        let audit = audit(&dir);
+        assert!(audit.contains("echo hello , u@example.com") && audit.contains(" , failed , "), "{audit}");
+    }
+
+    #[test]
+    fn bots_are_denied_unless_listed_by_user_id() {
+        let mut access = AccessFile::default();
+        access.perms.insert("ci".into(), PermRule { allow: vec!["echo *".into()], ..Default::default() });
+        let b2 = "b2b2b2b2-0000-4000-8000-000000000002";
+        access.emails.insert(b2.into(), "ci".into());
+        let (mut agent, fake, dir) = agent_with(access);
+        fake.add_raw_user(serde_json::json!({"object": "user", "id": "b1", "type": "bot", "bot": {}}));
+        fake.add_raw_user(serde_json::json!({"object": "user", "id": b2, "type": "bot", "bot": {}}));
+        let unlisted = fake.add_paragraph(PAGE_ID, "!(echo one)", "b1");
+        let listed = fake.add_paragraph(PAGE_ID, "!(echo two)", b2);
+
+        let report = run_tick(&mut agent);
+        assert_eq!(report.handled.len(), 2);
+        assert!(fake.children(&unlisted)[0].to_string().contains("commands from bots are not accepted"));
+        assert!(fake.children(&listed)[0].to_string().contains(&format!("executed_by={b2}")));
+        let audit = audit(&dir);
+        assert!(audit.lines().any(|l| l.contains("bot:b1") && l.contains("denied=identity")), "{audit}");
+    }
+
+    #[test]
+    fn tick_rejects_requester_without_run_as_mapping() {
+        let mut access = AccessFile::default();
+        access.run_as.insert(
+            "v@example.com".into(),
+            crate::access::RunAs { user: "root".into(), group: None },
+        );
+        let (mut agent, fake, _dir, cmd_block) = agent_with_command(access, "!(echo hello)");
+
+        let report = run_tick(&mut agent);
+        assert!(!report.handled[0].executed);
+        assert!(fake.children(&cmd_block)[0].to_string().contains("no local account is mapped"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_keeps_cwd_and_exports_between_commands() {
+        let (mut agent, fake, dir) = agent_with(AccessFile::default());
+        let work = dir.path().canonicalize().unwrap();
+        fake.add_paragraph(PAGE_ID, &format!("!(cd {})", work.display()), "u1");
+        fake.add_paragraph(PAGE_ID, "!(export GREETING=hi)", "u1");
+        fake.add_paragraph(PAGE_ID, "!(mkdir sub && cd sub && export FROM_SHELL=yes)", "u1");
+        let last = fake.add_paragraph(PAGE_ID, "!(pwd; echo $GREETING $FROM_SHELL)", "u1");
+        let other = fake.add_paragraph(PAGE_ID, "!(echo [$GREETING])", "u2");
+
+        let report = run_tick(&mut agent);
+        assert!(report.handled.iter().all(|h| h.status == "success"), "{:?}", report.handled);
+        let stdout = |block: &str| {
+            let h = report.handled.iter().find(|h| h.task.block_id == block).unwrap();
+            h.result.as_ref().unwrap().stdout.clone()
+        };
+        assert_eq!(stdout(&last), format!("{}\nhi yes\n", work.join("sub").display()));
+        // Sessions are per requester.
+        assert_eq!(stdout(&other), "[]\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restricted_roles_cannot_carry_over_loader_variables() {
+        let mut access = AccessFile::default();
+        let allow = vec!["echo".into(), "export".into(), "true".into()];
+        access.perms.insert("default".into(), PermRule { allow, ..Default::default() });
+        let (mut agent, fake, dir) = agent_with(access);
+        let direct = fake.add_paragraph(PAGE_ID, "!(export LD_PRELOAD=/tmp/x.so)", "u1");
+        fake.add_paragraph(PAGE_ID, "!(export LD_AUDIT=/tmp/x.so PATH=/tmp; true)", "u1");
+        let last = fake.add_paragraph(PAGE_ID, "!(echo [$LD_AUDIT])", "u1");
+
+        let report = run_tick(&mut agent);
+        let handled = |block: &str| report.handled.iter().find(|h| h.task.block_id == block).unwrap();
+        assert!(!handled(&direct).executed);
+        assert!(handled(&direct).output.contains("cannot export LD_PRELOAD"));
+        assert_eq!(handled(&last).result.as_ref().unwrap().stdout, "[]\n");
+        assert!(audit(&dir).contains("denied=export"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_types_requester_input_into_program() {
+        let (mut agent, fake, dir, block) = agent_with_command(AccessFile::default(), "!!(session cat)");
+        let transcript = |fake: &FakeNotion| -> String {
+            let text = |b: &serde_json::Value| b["code"]["rich_text"][0]["text"]["content"].as_str().map(String::from);
+            fake.children(&block).iter().filter_map(text).collect::<Vec<_>>().join("\n")
+        };
+        let wait_for = |agent: &mut Agent, done: &dyn Fn() -> bool| {
+            let until = Instant::now() + Duration::from_secs(30);
+            let mut handled = Vec::new();
+            while !done() && Instant::now() < until {
+                handled.extend(agent.tick().unwrap().handled);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            handled
+        };
+
+        assert_eq!(agent.tick().unwrap().dispatched, 1);
+        wait_for(&mut agent, &|| children_contains_marker(&fake.children(&block)));
+        // Marked at start, so rescans do not open it again.
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+
+        fake.add_paragraph(&block, "hello session", "u1");
+        fake.add_paragraph(&block, "from someone else", "u2");
+        let mut edited = fake.block(&fake.add_paragraph("elsewhere", "edited by someone else", "u1")).unwrap();
+        edited["last_edited_by"]["id"] = "u2".into();
+        edited.as_object_mut().unwrap().remove("id");
+        fake.add_block(&block, edited);
+        // Echoed by the terminal, then printed by cat.
+        wait_for(&mut agent, &|| {
+            let text = transcript(&fake);
+            text.matches("hello session\n").count() >= 2 && text.matches("ignored").count() >= 2
+        });
+        assert!(transcript(&fake).contains("input from v@example.com ignored"));
+        assert!(!transcript(&fake).contains("from someone else\n"));
+        assert!(!transcript(&fake).contains("edited by someone else\n"));
+
+        fake.add_paragraph(&block, "!!(close)", "u1");
+        let mut handled = Vec::new();
+        let until = Instant::now() + Duration::from_secs(30);
+        while handled.is_empty() && Instant::now() < until {
+            handled.extend(agent.tick().unwrap().handled);
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(handled[0].status, "success");
+        assert!(handled[0].output.contains("[session closed | exit_code=none | signal=9"));
+        assert!(handled[0].output.contains("inputs=1]"));
+        let audit = audit(&dir);
+        assert!(audit.contains("session cat , u@example.com"));
+        assert!(audit.contains("inputs=1"));
+    }
+
+    #[test]
+    fn session_program_is_permission_checked() {
+        let mut access = AccessFile::default();
+        access.perms.insert("default".into(), PermRule { allow: vec!["cat".into()], ..Default::default() });
+        let (mut agent, fake, _dir, block) = agent_with_command(access, "!!(session python3)");
+
+        agent.tick().unwrap();
+        let until = Instant::now() + Duration::from_secs(10);
+        let mut handled = Vec::new();
+        while handled.is_empty() && Instant::now() < until {
+            handled.extend(agent.tick().unwrap().handled);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!handled[0].executed);
+        assert!(fake.children(&block)[0].to_string().contains("permission denied"));
+    }
+
+    #[test]
+    fn slow_command_does_not_block_other_users() {
+        let (mut agent, fake, dir) = agent_with(AccessFile::default());
+        let gate = dir.path().join("gate");
+        let slow = fake.add_paragraph(PAGE_ID, &gated(&gate, "true"), "u1");
+        let fast = fake.add_paragraph(PAGE_ID, "!(echo fast)", "u2");
+
+        assert_eq!(agent.tick().unwrap().dispatched, 2);
+        let until = Instant::now() + Duration::from_secs(30);
+        while !children_contains_marker(&fake.children(&fast)) && Instant::now() < until {
+            std::thread::sleep(Duration::from_millis(20));
+            agent.tick().unwrap();
+        }
+        assert!(children_contains_marker(&fake.children(&fast)), "fast command did not finish");
+        assert!(!children_contains_marker(&fake.children(&slow)));
+        // The running block is not picked up again by rescans.
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        std::fs::write(&gate, "").unwrap();
+        agent.wait_idle();
+        assert!(children_contains_marker(&fake.children(&slow)));
+    }
+
+    #[test]
+    fn one_users_commands_run_in_order() {
+        let (mut agent, fake, dir) = agent_with(AccessFile::default());
+        let gate = dir.path().join("gate");
+        fake.add_paragraph(PAGE_ID, &gated(&gate, "echo first"), "u1");
+        fake.add_paragraph(PAGE_ID, "!(echo second)", "u1");
+
+        // Only one command per user runs at a time by default.
+        assert_eq!(agent.tick().unwrap().dispatched, 1);
+        assert_eq!(agent.tick().unwrap().dispatched, 0);
+        std::fs::write(&gate, "").unwrap();
+        assert_eq!(agent.wait_idle().handled.len(), 2);
+        let audit = audit(&dir);
+        let lines: Vec<_> = audit.lines().collect();
+        assert!(lines[0].starts_with("touch "));
+        assert!(lines[1].starts_with("echo second"));
+    }
+
+    #[test]
+    fn large_output_is_uploaded_with_preview() {
+        let mut config = Config::new("k", PAGE_URL);
+        config.stream_interval_secs = 0;
+        config.upload_threshold_bytes = 10_000;
+        let (mut agent, fake, dir) = agent_with_config(config, AccessFile::default());
+        let cmd_block = fake.add_paragraph(PAGE_ID, "!(seq 1 20000)", "u1");
+
+        run_tick(&mut agent);
+        let children = fake.children(&cmd_block);
+        assert!(children_contains_marker(&children));
+        let file = children.iter().find(|c| c["type"] == "file").expect("file block");
+        let upload_id = file["file"]["file_upload"]["id"].as_str().unwrap();
+        let (name, data) = fake.upload(upload_id).unwrap();
+        let full = String::from_utf8(data).unwrap();
+        assert!(full.starts_with("[stdout]\n1\n2\n") && full.contains("\n20000\n"));
+
+        let preview = code_output(&children[0]);
+        assert!(preview.len() < 5_000);
+        assert!(preview.contains(&format!("attached as {}", name)));
+        let saved = LogPaths::under(dir.path()).output_dir.join(&name);
+        assert_eq!(std::fs::read_to_string(saved).unwrap(), full);
+    }
+
+    /// Output part of a result code block, without the `$ cmd` line.
+    fn code_output(block: &serde_json::Value) -> String {
+        let text: String = block["code"]["rich_text"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r["text"]["content"].as_str())
+            .collect();
+        text.split_once('\n').map(|(_, rest)| rest.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn output_is_streamed_while_running() {
+        let mut config = Config::new("k", PAGE_URL);
+        config.stream_interval_secs = 1;
+        let (mut agent, fake, dir) = agent_with_config(config, AccessFile::default());
+        let gate = dir.path().join("gate");
+        let cmd = gated(&gate, "echo two").replacen("!(", "!(echo one; ", 1);
+        let cmd_block = fake.add_paragraph(PAGE_ID, &cmd, "u1");
+
+        agent.tick().unwrap();
+        let mut seen_partial = false;
+        let until = Instant::now() + Duration::from_secs(30);
+        while !seen_partial && Instant::now() < until {
+            std::thread::sleep(Duration::from_millis(100));
+            let children = fake.children(&cmd_block);
+            seen_partial = children.first().is_some_and(|c| {
+                let out = code_output(c);
+                out.contains("one") && out.contains("running") && !out.contains("two")
+            });
+            assert!(!children_contains_marker(&children));
+        }
+        assert!(seen_partial, "partial output was never streamed");
+
+        std::fs::write(&gate, "").unwrap();
+        agent.wait_idle();
+        let children = fake.children(&cmd_block);
+        assert_eq!(children.len(), 2);
+        let out = code_output(&children[0]);
+        assert!(out.contains("one") && out.contains("two"));
+        assert!(!out.contains("running"));
+        assert!(children_contains_marker(&children));
+    }
+
+    #[test]
+    fn run_until_marks_node_offline() {
+        let (mut agent, fake, _dir, cmd_block) = agent_with_command(AccessFile::default(), "!(echo hello)");
+        let shutdown = Shutdown::new(Duration::from_secs(1));
+        shutdown.request();
+        agent.run_until(&shutdown).unwrap();
+
+        // No new task is started after shutdown was requested.
+        assert!(fake.children(&cmd_block).is_empty());
+        let page = fake.children(PAGE_ID);
+        let status = page.last().unwrap().to_string();
+        assert!(status.contains("went offline"));
+        assert!(status.contains(STATUS_MARKER));
+    }
+
+    #[test]
+    fn running_commands_are_interrupted_once_the_grace_period_ends() {
+        let (agent, fake, dir) = agent_with(AccessFile::default());
+        let clock = Clock::manual();
+        let mut agent = agent.with_clock(clock.clone());
+        let gate = dir.path().join("gate");
+        let cmd_block = fake.add_paragraph(PAGE_ID, &gated(&gate, "true"), "u1");
+        assert_eq!(agent.tick().unwrap().dispatched, 1);
+        let until = Instant::now() + Duration::from_secs(30);
+        while !gate.with_extension("started").exists() && Instant::now() < until {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(gate.with_extension("started").exists(), "gated command did not start");
+
+        let shutdown = Shutdown::with_clock(Duration::from_secs(30), clock.clone());
+        shutdown.request();
+        clock.advance(Duration::from_secs(30));
+        agent.run_until(&shutdown).unwrap();
+        assert!(children_contains_marker(&fake.children(&cmd_block)));
+        assert!(audit(&dir).contains(" , interrupted , "), "{}", audit(&dir));
+    }
+
+    #[test]
+    fn tick_publishes_heartbeat() {
+        let mut config = Config::new("k", PAGE_URL);
+        config.heartbeat_interval_secs = 30;
+        let (agent, fake, dir) = agent_with_config(config, AccessFile::default());
+        let clock = Clock::manual();
+        let mut agent = agent.with_clock(clock.clone());
+        let gate = dir.path().join("gate");
+        fake.add_paragraph(PAGE_ID, &gated(&gate, "true"), "u1");
+        fake.add_paragraph(PAGE_ID, "!(echo queued)", "u1");
+        agent.tick().unwrap();
+        assert!(agent.status().last_poll.is_some());
+
+        // The first heartbeat fires at the end of the first tick.
+        let id = find_status_block(fake.as_ref(), PAGE_ID).unwrap().expect("status block");
+        let status = || fake.block(&id).unwrap().to_string();
+        assert!(status().contains("NotionSSH online"));
+        assert!(status().contains("queue=1"));
+
+        // The next one waits for the interval.
+        std::fs::write(&gate, "").unwrap();
+        agent.wait_idle();
+        agent.tick().unwrap();
+        assert!(status().contains("queue=1"));
+        clock.advance(Duration::from_secs(30));
+        agent.tick().unwrap();
+        assert!(status().contains("queue=0"), "{}", status());
+        assert_eq!(agent.status().commands_executed, 2);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::access::{is_manager, roles_for, unidentified_policy, AccessFile};
+use crate::notion::NotionApi;
+use crate::parser::block_text;
+use crate::rules::{RuleItem, RuleKind, RuleMatch};
+use crate::shell;
+use crate::users::UserCache;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Marks the to_do block asking for approval of a command.
+pub const APPROVAL_MARKER: &str = "notionSSH-approval";
+const DEFAULT_APPROVER_ROLE: &str = "approvers";
+const DEFAULT_EXPIRE_SECS: u64 = 3600;
+
+/// Commands that only run once a second person approves them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovalPolicy {
+    /// Written like `allow`/`deny` items; a command needs approval when any
+    /// program it runs matches one.
+    #[serde(default)]
+    pub commands: Vec<RuleItem>,
+    /// Members of this role may approve, as may `perm_manager`.
+    #[serde(default = "default_approver_role")]
+    pub approver_role: String,
+    /// How long a request stays open; 0 never expires.
+    #[serde(default = "default_expire_secs")]
+    pub expire_secs: u64,
+}
+
+fn default_approver_role() -> String {
+    DEFAULT_APPROVER_ROLE.to_string()
+}
+
+fn default_expire_secs() -> u64 {
+    DEFAULT_EXPIRE_SECS
+}
+
+impl Default for ApprovalPolicy {
+    fn default() -> Self {
+        Self { commands: Vec::new(), approver_role: default_approver_role(), expire_secs: default_expire_secs() }
+    }
+}
+
+impl ApprovalPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Command lines that cannot be analyzed need approval whenever any
+    /// command does, since it is unknown what they run.
+    pub fn requires_approval(&self, cmd: &str) -> bool {
+        if self.commands.is_empty() {
+            return false;
+        }
+        match shell::analyze(cmd) {
+            Ok(invocations) => invocations
+                .iter()
+                .any(|inv| self.commands.iter().any(|c| c.matches(inv, RuleKind::Deny) == RuleMatch::Yes)),
+            Err(_) => true,
+        }
+    }
+}
+
+/// Whether `email` may approve other requesters' commands.
+pub fn is_approver(af: &AccessFile, email: &str) -> bool {
+    is_manager(af, email) || roles_for(af, email).contains(&af.approval.approver_role.as_str())
+}
+
+/// What the agent asked approval for. It is kept by the agent, not read
+/// back from the request block, whose text anyone can edit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub command: String,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// Where the approval of a command block stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApprovalState {
+    /// No request has been posted under the block yet.
+    Missing,
+    /// The request block under the command was not posted for this command
+    /// (it was edited since, or the agent restarted) and must be posted
+    /// again before anyone's check counts.
+    Stale { id: String, note: &'static str },
+    Pending,
+    Approved { approver: String },
+    /// Nobody approved before the deadline.
+    Expired,
+}
+
+/// The to_do block asking for approval of `cmd`, open until `expires`.
+/// `note` explains why an earlier check was undone.
+pub fn request_block(cmd: &str, requester: &str, expires: Option<DateTime<Utc>>, note: Option<&str>) -> Value {
+    let expires = expires
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_else(|| "never".to_string());
+    let mut text = format!(
+        "Approval needed: `{}` requested by {}. Another manager or approver checks this box to run it. \
+         expires={} # {}",
+        cmd, requester, expires, APPROVAL_MARKER
+    );
+    if let Some(note) = note {
+        text = format!("[{}] {}", note, text);
+    }
+    json!({
+        "object": "block",
+        "type": "to_do",
+        "to_do": {
+            "rich_text": [{"type": "text", "text": {"content": text}}],
+            "checked": false
+        }
+    })
+}
+
+/// Reads the approval request for `cmd` among `children` of its block;
+/// `asked` is what the agent posted it for, if it remembers. A box checked
+/// by the requester or by someone who may not approve is unchecked again
+/// with a note saying why, as is one checked by a bot or an unidentified
+/// user.
+#[allow(clippy::too_many_arguments)]
+pub fn check(
+    api: &dyn NotionApi,
+    users: &UserCache,
+    af: &AccessFile,
+    asked: Option<&Request>,
+    cmd: &str,
+    requester: &str,
+    children: &[Value],
+    now: DateTime<Utc>,
+) -> Result<ApprovalState> {
+    let request = children.iter().find(|c| {
+        c["type"] == "to_do" && block_text(c).is_some_and(|t| t.contains(APPROVAL_MARKER))
+    });
+    let Some(request) = request else { return Ok(ApprovalState::Missing) };
+    let id = request["id"].as_str().ok_or_else(|| anyhow!("approval request without id"))?;
+    let expires = match asked {
+        Some(asked) if asked.command == cmd => asked.expires,
+        Some(_) => {
+            let note = "the command changed after approval was requested";
+            return Ok(ApprovalState::Stale { id: id.to_string(), note });
+        }
+        None => {
+            let note = "requested again after the agent restarted";
+            return Ok(ApprovalState::Stale { id: id.to_string(), note });
+        }
+    };
+    let expired_at = |t: DateTime<Utc>| expires.is_some_and(|e| t > e);
+
+    if request["to_do"]["checked"].as_bool() != Some(true) {
+        return Ok(if expired_at(now) { ApprovalState::Expired } else { ApprovalState::Pending });
+    }
+    // Checking the box is the last edit, so it tells who and when.
+    let checked_at = request["last_edited_time"]
+        .as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or(now);
+    if expired_at(checked_at) {
+        return Ok(ApprovalState::Expired);
+    }
+    let approver_id = request["last_edited_by"]["id"].as_str().unwrap_or("");
+    let approver = users.requester(api, af, approver_id)?;
+    let refused = if approver.eq_ignore_ascii_case(requester) {
+        Some("the requester cannot approve their own command".to_string())
+    } else if unidentified_policy(af, &approver).is_some() || !is_approver(af, &approver) {
+        Some(format!("{} may not approve commands", approver))
+    } else {
+        None
+    };
+    let Some(note) = refused else { return Ok(ApprovalState::Approved { approver }) };
+
+    let block = request_block(cmd, requester, expires, Some(&note));
+    api.update_block(id, json!({ "to_do": block["to_do"] }))?;
+    Ok(if expired_at(now) { ApprovalState::Expired } else { ApprovalState::Pending })
+}
+
+/// Deadline for a request posted at `now`.
+pub fn expiry(policy: &ApprovalPolicy, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    (policy.expire_secs > 0).then(|| now + Duration::seconds(policy.expire_secs as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::PermRule;
+    use crate::fake::FakeNotion;
+    use std::time::Duration as StdDuration;
+
+    #[test]
+    fn only_a_different_approver_counts() {
+        let mut af = AccessFile::default();
+        af.approval.commands = vec!["systemctl restart *".into(), "re:.*DROP.*".into()];
+        af.perms.insert("approvers".into(), PermRule::default());
+        af.emails.insert("lead@example.com".into(), "approvers".into());
+        assert!(af.approval.requires_approval("sudo -n true; systemctl restart app"));
+        assert!(af.approval.requires_approval("psql -c 'DROP TABLE t'"));
+        assert!(!af.approval.requires_approval("systemctl status app"));
+
+        let fake = FakeNotion::new();
+        fake.add_user("u1", "u@example.com");
+        fake.add_user("u2", "lead@example.com");
+        fake.add_user("u3", "v@example.com");
+        let now = Utc::now();
+        let cmd = "systemctl restart app";
+        let asked = Request { command: cmd.into(), expires: expiry(&af.approval, now) };
+        let mut request = request_block(cmd, "u@example.com", asked.expires, None);
+        let id = fake.add_block("cmd", request.clone());
+        let users = UserCache::new(StdDuration::ZERO);
+        let state = |at| check(&fake, &users, &af, Some(&asked), cmd, "u@example.com", &fake.children("cmd"), at).unwrap();
+        assert_eq!(state(now), ApprovalState::Pending);
+        assert_eq!(state(now + Duration::hours(2)), ApprovalState::Expired);
+
+        // Checked by the requester: undone, with a note.
+        request["id"] = json!(id);
+        request["to_do"]["checked"] = json!(true);
+        request["last_edited_by"] = json!({"id": "u1"});
+        fake.update_block(&id, request.clone()).unwrap();
+        assert_eq!(state(now), ApprovalState::Pending);
+        let text = block_text(&fake.block(&id).unwrap()).unwrap();
+        assert!(text.starts_with("[the requester cannot approve their own command]"), "{text}");
+        assert_eq!(fake.block(&id).unwrap()["to_do"]["checked"], false);
+
+        request["last_edited_by"] = json!({"id": "u3"});
+        fake.update_block(&id, request.clone()).unwrap();
+        assert_eq!(state(now), ApprovalState::Pending);
+
+        request["last_edited_by"] = json!({"id": "u2"});
+        fake.update_block(&id, request).unwrap();
+        assert_eq!(state(now), ApprovalState::Approved { approver: "lead@example.com".into() });
+
+        // Approved, then the command or the deadline in the text edited.
+        let children = fake.children("cmd");
+        let edited = check(&fake, &users, &af, Some(&asked), "systemctl restart db", "u@example.com", &children, now);
+        assert!(matches!(edited.unwrap(), ApprovalState::Stale { id: stale, .. } if stale == id));
+        let forgotten = check(&fake, &users, &af, None, cmd, "u@example.com", &children, now);
+        assert!(matches!(forgotten.unwrap(), ApprovalState::Stale { .. }));
+        let late = now + Duration::hours(2);
+        let mut text = request_block(cmd, "u@example.com", Some(late + Duration::hours(1)), None);
+        text["to_do"]["checked"] = json!(true);
+        fake.update_block(&id, json!({"to_do": text["to_do"], "last_edited_time": late.to_rfc3339()})).unwrap();
+        assert_eq!(state(late), ApprovalState::Expired);
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where the agent reads the time for heartbeats and the shutdown grace
+/// period. `Clock::system()` follows wall time; tests use `Clock::manual()`
+/// and move it forward with `advance`.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    manual: Option<Arc<Mutex<Instant>>>,
+}
+
+impl Clock {
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    /// Stands still until `advance` is called.
+    pub fn manual() -> Self {
+        Self { manual: Some(Arc::new(Mutex::new(Instant::now()))) }
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.manual {
+            Some(now) => *now.lock().unwrap_or_else(|e| e.into_inner()),
+            None => Instant::now(),
+        }
+    }
+
+    /// Moves a manual clock forward; does nothing for the system clock.
+    pub fn advance(&self, by: Duration) {
+        if let Some(now) = &self.manual {
+            *now.lock().unwrap_or_else(|e| e.into_inner()) += by;
+        }
+    }
+}
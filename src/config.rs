@@ -10,6 +10,171 @@ use std::path::{Path, PathBuf};
 pub struct Config {
     pub api_key: String,
     pub page_url: String,
+    /// Notion API root; override to go through a self-hosted proxy.
+    #[serde(default = "default_api_base_url")]
+    pub api_base_url: String,
+    /// How long a running command may continue after Ctrl+C / SIGTERM.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    /// How often the status block on the page is rewritten.
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// Default per-command timeout; 0 disables it. Roles may lower it with
+    /// `max_timeout_secs` in access.json.
+    #[serde(default = "default_command_timeout_secs")]
+    pub command_timeout_secs: u64,
+    /// Upper bound on commands running at the same time.
+    #[serde(default = "default_max_concurrent_commands")]
+    pub max_concurrent_commands: usize,
+    /// Upper bound on running commands per requester; their commands start
+    /// in the order they were found on the page.
+    #[serde(default = "default_max_concurrent_per_user")]
+    pub max_concurrent_per_user: usize,
+    /// How often a running command's result block is refreshed with the
+    /// output so far; 0 posts the result only once the command finishes.
+    #[serde(default = "default_stream_interval_secs")]
+    pub stream_interval_secs: u64,
+    /// Cap on the output kept per command; beyond it the middle is dropped
+    /// and the head and tail are posted. 0 keeps everything.
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// Output longer than this is saved under `.notionSSH/outputs/` and
+    /// attached to the result as a file, with only a preview inline.
+    /// 0 disables uploads.
+    #[serde(default)]
+    pub upload_threshold_bytes: usize,
+    /// Saved outputs older than this are deleted; 0 keeps them forever.
+    #[serde(default = "default_output_retention_days")]
+    pub output_retention_days: u64,
+    /// Interactive sessions (`!!(session ...)`) with no input for this long
+    /// are closed; 0 keeps them open until closed explicitly.
+    #[serde(default = "default_session_idle_timeout_secs")]
+    pub session_idle_timeout_secs: u64,
+    /// Upper bound on interactive sessions open at the same time. They do
+    /// not take command slots.
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    /// Commands written in to_do blocks wait until the box is checked, so
+    /// they can be staged and armed later. Paragraph commands are unaffected.
+    #[serde(default)]
+    pub require_checked_todo: bool,
+    /// Reply to each command with how it would run (permission decision,
+    /// shell, working directory, account) instead of running it.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// How long a looked-up Notion user (name, email, bot or person) is
+    /// reused before being fetched again; 0 looks users up every time.
+    #[serde(default = "default_user_cache_secs")]
+    pub user_cache_secs: u64,
+}
+
+fn default_api_base_url() -> String {
+    crate::notion::DEFAULT_API_BASE_URL.to_string()
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    30
+}
+
+fn default_command_timeout_secs() -> u64 {
+    600
+}
+
+fn default_max_concurrent_commands() -> usize {
+    4
+}
+
+fn default_max_concurrent_per_user() -> usize {
+    1
+}
+
+fn default_stream_interval_secs() -> u64 {
+    3
+}
+
+fn default_max_output_bytes() -> usize {
+    200_000
+}
+
+fn default_output_retention_days() -> u64 {
+    7
+}
+
+fn default_session_idle_timeout_secs() -> u64 {
+    600
+}
+
+fn default_max_sessions() -> usize {
+    4
+}
+
+fn default_user_cache_secs() -> u64 {
+    600
+}
+
+impl Config {
+    pub fn new(api_key: impl Into<String>, page_url: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            page_url: page_url.into(),
+            api_base_url: default_api_base_url(),
+            shutdown_grace_secs: default_shutdown_grace_secs(),
+            heartbeat_interval_secs: default_heartbeat_interval_secs(),
+            command_timeout_secs: default_command_timeout_secs(),
+            max_concurrent_commands: default_max_concurrent_commands(),
+            max_concurrent_per_user: default_max_concurrent_per_user(),
+            stream_interval_secs: default_stream_interval_secs(),
+            max_output_bytes: default_max_output_bytes(),
+            upload_threshold_bytes: 0,
+            output_retention_days: default_output_retention_days(),
+            session_idle_timeout_secs: default_session_idle_timeout_secs(),
+            max_sessions: default_max_sessions(),
+            require_checked_todo: false,
+            dry_run: false,
+            user_cache_secs: default_user_cache_secs(),
+        }
+    }
+
+    /// Takes the settings that can change while the agent runs from
+    /// `other` and returns the names of those that changed. The Notion
+    /// connection and the shutdown grace period keep their current values;
+    /// the second list names those that differ and need a restart.
+    pub fn apply_reloadable(&mut self, other: &Config) -> (Vec<String>, Vec<String>) {
+        let next = Config {
+            api_key: self.api_key.clone(),
+            page_url: self.page_url.clone(),
+            api_base_url: self.api_base_url.clone(),
+            shutdown_grace_secs: self.shutdown_grace_secs,
+            ..other.clone()
+        };
+        let changed = changed_fields(self, &next);
+        let restart = changed_fields(&next, other);
+        *self = next;
+        (changed, restart)
+    }
+
+    fn with_env_overrides(mut self) -> Self {
+        if let Ok(base) = env::var("NotionAPIBaseURL").or_else(|_| env::var("NOTION_API_BASE_URL")) {
+            if !base.trim().is_empty() {
+                self.api_base_url = base.trim().to_string();
+            }
+        }
+        self
+    }
+}
+
+/// Names of the fields whose values differ, sorted.
+fn changed_fields(a: &Config, b: &Config) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) =
+        (serde_json::to_value(a), serde_json::to_value(b))
+    else {
+        return Vec::new();
+    };
+    a.iter().filter(|(k, v)| b.get(*k) != Some(*v)).map(|(k, _)| k.clone()).collect()
 }
 
 pub fn load_config() -> Result<Config> {
@@ -17,12 +182,12 @@ pub fn load_config() -> Result<Config> {
     let env_api = env::var("NotionAPIKey").or_else(|_| env::var("NOTION_API_KEY")).ok();
     let env_url = env::var("NotionPageURL").or_else(|_| env::var("NOTION_PAGE_URL")).ok();
     if let (Some(api_key), Some(page_url)) = (env_api.clone(), env_url.clone()) {
-        return Ok(Config { api_key, page_url });
+        return Ok(Config::new(api_key, page_url).with_env_overrides());
     }
 
     // 2) Otherwise try storage file
     if let Some(cfg) = load_from_storage_default().ok().flatten() {
-        return Ok(cfg);
+        return Ok(cfg.with_env_overrides());
     }
 
     // 3) Prompt for both and save
@@ -32,9 +197,9 @@ pub fn load_config() -> Result<Config> {
         eprintln!("Invalid URL. Please enter http/https URL with valid domain.");
         page_url = prompt("NOTION_PAGE_URL : ");
     }
-    let cfg = Config { api_key, page_url };
+    let cfg = Config::new(api_key, page_url);
     let _ = save_to_storage_default(&cfg);
-    Ok(cfg)
+    Ok(cfg.with_env_overrides())
 }
 
 fn prompt(label: &str) -> String {
@@ -50,37 +215,7 @@ pub fn is_valid_http_https_url(url: &str) -> bool {
     re.is_match(url.trim())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
-
-    #[test]
-    fn url_validation() {
-        assert!(is_valid_http_https_url("http://example.com"));
-        assert!(is_valid_http_https_url("https://sub.domain.co.kr/path?q=1"));
-        assert!(is_valid_http_https_url("https://notion.so"));
-        assert!(is_valid_http_https_url("https://www.notion.so/page-123"));
-        assert!(is_valid_http_https_url("https://example.com:443/path"));
-
-        assert!(!is_valid_http_https_url("ftp://example.com"));
-        assert!(!is_valid_http_https_url("http://localhost"));
-        assert!(!is_valid_http_https_url("https://invalid_domain"));
-        assert!(!is_valid_http_https_url("not a url"));
-    }
-
-    #[test]
-    fn storage_roundtrip() {
-        let dir = tempdir().unwrap();
-        let path = dir.path().join(".notionSSH").join("storage.json");
-        let cfg = Config { api_key: "k1".into(), page_url: "https://example.com/p".into() };
-        save_to_storage_path(&path, &cfg).unwrap();
-        let loaded = load_from_storage_path(&path).unwrap().unwrap();
-        assert_eq!(cfg, loaded);
-    }
-}
-
-fn storage_default_path() -> PathBuf {
+pub fn storage_default_path() -> PathBuf {
     Path::new(".notionSSH").join("storage.json")
 }
 
@@ -101,7 +236,7 @@ fn save_to_storage_default(cfg: &Config) -> Result<()> {
     save_to_storage_path(&storage_default_path(), cfg)
 }
 
-fn load_from_storage_path(path: &Path) -> Result<Option<Config>> {
+pub fn load_from_storage_path(path: &Path) -> Result<Option<Config>> {
     if !path.exists() { return Ok(None); }
     let mut f = fs::File::open(path)?;
     let mut s = String::new();
@@ -118,3 +253,42 @@ fn save_to_storage_path(path: &Path, cfg: &Config) -> Result<()> {
     f.write_all(s.as_bytes())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn url_validation() {
+        assert!(is_valid_http_https_url("http://example.com"));
+        assert!(is_valid_http_https_url("https://sub.domain.co.kr/path?q=1"));
+        assert!(is_valid_http_https_url("https://notion.so"));
+        assert!(is_valid_http_https_url("https://www.notion.so/page-123"));
+        assert!(is_valid_http_https_url("https://example.com:443/path"));
+
+        assert!(!is_valid_http_https_url("ftp://example.com"));
+        assert!(!is_valid_http_https_url("http://localhost"));
+        assert!(!is_valid_http_https_url("https://invalid_domain"));
+        assert!(!is_valid_http_https_url("not a url"));
+    }
+
+    #[test]
+    fn storage_roundtrip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(".notionSSH").join("storage.json");
+        let cfg = Config { api_key: "k1".into(), page_url: "https://example.com/p".into(), ..Config::new("", "") };
+        save_to_storage_path(&path, &cfg).unwrap();
+        let loaded = load_from_storage_path(&path).unwrap().unwrap();
+        assert_eq!(cfg, loaded);
+    }
+
+    #[test]
+    fn storage_without_base_url_uses_default() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("storage.json");
+        fs::write(&path, r#"{"api_key":"k","page_url":"https://example.com/p"}"#).unwrap();
+        let loaded = load_from_storage_path(&path).unwrap().unwrap();
+        assert_eq!(loaded.api_base_url, "https://api.notion.com/v1");
+    }
+}
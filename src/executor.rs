@@ -1,74 +1,520 @@
+use crate::privilege::UnixIdentity;
+use crate::sandbox::SandboxProfile;
+use crate::session::StateChange;
 use anyhow::{anyhow, Result};
-use std::process::Command;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+/// Bytes of each stream held in memory when `ExecOptions::max_output_bytes`
+/// is not set, e.g. while large outputs are uploaded.
+const MAX_CAPTURE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Per-command execution settings.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// Kill the command (and every process in its group) after this long.
+    pub timeout: Option<Duration>,
+    /// How often the `progress` callback receives the output so far.
+    pub progress_interval: Option<Duration>,
+    /// Keep at most this many bytes of output (head and tail); `None` keeps all.
+    pub max_output_bytes: Option<usize>,
+    /// Run the shell as this account instead of the agent's own.
+    pub run_as: Option<UnixIdentity>,
+    /// Run the shell inside this sandbox.
+    pub sandbox: Option<SandboxProfile>,
+    /// Working directory, overriding the `run_as` account's home.
+    pub cwd: Option<PathBuf>,
+    /// Extra environment variables, set after any `run_as` reset.
+    pub env: Vec<(String, String)>,
+    /// Report the directory and environment the command left behind in
+    /// `ExecutionResult::state` (unix only).
+    pub capture_state: bool,
+}
+
+/// Result of running a command through `execute_command_with`.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the process was ended by a signal.
+    pub exit_code: Option<i32>,
+    /// Signal that terminated the process (unix only).
+    pub signal: Option<i32>,
+    pub duration: Duration,
+    /// Bytes dropped from the middle of each stream by
+    /// `ExecOptions::max_output_bytes`.
+    pub stdout_omitted: usize,
+    pub stderr_omitted: usize,
+    /// The command was killed because `stop` returned true.
+    pub interrupted: bool,
+    /// The command was killed because it ran past `ExecOptions::timeout`.
+    pub timed_out: bool,
+    /// The timeout that applied, for reporting.
+    pub timeout: Option<Duration>,
+    /// Directory and environment changes, with `ExecOptions::capture_state`
+    /// and only when the command ran to the end.
+    pub state: Option<StateChange>,
+}
+
+impl ExecutionResult {
+    /// A command that could not be started, e.g. because its working
+    /// directory, account or sandbox could not be set up. Reported as failed.
+    pub fn failed_to_start(err: &anyhow::Error, duration: Duration) -> Self {
+        Self {
+            stdout: String::new(),
+            stderr: format!("failed to start: {err:#}"),
+            exit_code: None,
+            signal: None,
+            duration,
+            stdout_omitted: 0,
+            stderr_omitted: 0,
+            interrupted: false,
+            timed_out: false,
+            timeout: None,
+            state: None,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0) && !self.interrupted && !self.timed_out
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.stdout_omitted > 0 || self.stderr_omitted > 0
+    }
+
+    /// Status string written to the audit log.
+    pub fn audit_status(&self) -> &'static str {
+        if self.timed_out {
+            "timeout"
+        } else if self.interrupted {
+            "interrupted"
+        } else if self.success() {
+            "success"
+        } else {
+            "failed"
+        }
+    }
+
+    /// `key=value` details recorded next to the audit status.
+    pub fn audit_details(&self) -> Vec<(String, String)> {
+        let truncated = match (self.stdout_omitted > 0, self.stderr_omitted > 0) {
+            (true, true) => "stdout,stderr",
+            (true, false) => "stdout",
+            (false, true) => "stderr",
+            (false, false) => "none",
+        };
+        vec![
+            ("exit_code".into(), opt_to_string(self.exit_code)),
+            ("signal".into(), opt_to_string(self.signal)),
+            ("duration_ms".into(), self.duration.as_millis().to_string()),
+            ("truncated".into(), truncated.into()),
+        ]
+    }
+
+    /// stdout followed by stderr, as a shell would show them.
+    pub fn combined(&self) -> String {
+        combine(self.stdout.as_bytes(), self.stderr.as_bytes())
+    }
+
+    /// Text posted to Notion: stdout and stderr in separate sections followed
+    /// by a summary line with the exit code, signal and duration.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, body) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            if body.is_empty() {
+                continue;
+            }
+            text.push_str(&format!("[{}]\n{}", name, body));
+            if !body.ends_with('\n') {
+                text.push('\n');
+            }
+        }
+        if text.is_empty() {
+            text.push_str("(no output)\n");
+        }
+        if self.timed_out {
+            let secs = self.timeout.unwrap_or_default().as_secs();
+            text.push_str(&format!("... [timed out after {} s]\n", secs));
+        } else if self.interrupted {
+            text.push_str("... [interrupted: agent shutting down]\n");
+        }
+        text.push_str(&format!(
+            "[exit_code={} | signal={} | duration={:.2}s]",
+            opt_to_string(self.exit_code),
+            opt_to_string(self.signal),
+            self.duration.as_secs_f64()
+        ));
+        text
+    }
+}
+
+fn opt_to_string(v: Option<i32>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_else(|| "none".into())
+}
 
 pub fn execute_command(cmd: &str) -> Result<(String, bool)> {
+    let result = execute_command_with(cmd, &ExecOptions::default(), &|| false, &mut |_| {})?;
+    Ok((result.combined(), result.success()))
+}
+
+/// Runs `cmd` in the platform shell, polling `stop` while it runs. When `stop`
+/// returns true or the timeout expires, the whole process tree is killed and
+/// whatever it printed so far is kept. `progress` is called with stdout and
+/// stderr combined every `opts.progress_interval` while they keep growing.
+pub fn execute_command_with(
+    cmd: &str,
+    opts: &ExecOptions,
+    stop: &dyn Fn() -> bool,
+    progress: &mut dyn FnMut(&str),
+) -> Result<ExecutionResult> {
+    let capture = cfg!(unix) && opts.capture_state;
+    let script = shell_script(if capture { crate::session::wrap_command(cmd) } else { cmd.to_string() }, opts);
+    let mut last_err: Option<anyhow::Error> = None;
+    for (prog, args) in shell_attempts(&script) {
+        let mut command = Command::new(&prog);
+        command
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut state_reader = None;
+        // Put the shell in its own process group so a kill reaches
+        // everything it started, not just the shell itself.
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+            if capture {
+                state_reader = Some(state_pipe(&mut command)?);
+            }
+        }
+        apply_options(&mut command, opts)?;
+        let spawned = command.spawn();
+        // Drop our copy of the pipe's write end so the reader sees EOF
+        // once the shell exits.
+        drop(command);
+        match spawned {
+            Ok(child) => return wait_for(child, state_reader, opts, stop, progress),
+            Err(e) => {
+                last_err = Some(e.into());
+            }
+        }
+    }
+    Err(anyhow!(
+        "Failed to run command with available shells (last error: {:?})",
+        last_err
+    ))
+}
+
+/// Prefixes `script` with a `cd` into `opts.cwd`. The directory is changed
+/// in the shell rather than before exec, so the `run_as` account's own
+/// permissions apply and a missing directory is reported like any other
+/// shell error.
+pub(crate) fn shell_script(script: String, opts: &ExecOptions) -> String {
+    match &opts.cwd {
+        Some(dir) if cfg!(unix) => {
+            format!("cd -- {} || exit 1\n{}", crate::session::quote(&dir.to_string_lossy()), script)
+        }
+        _ => script,
+    }
+}
+
+/// Applies the sandbox, account, directory and environment in `opts` to
+/// `command`. Unix `pre_exec` hooks registered earlier run before these.
+pub(crate) fn apply_options(command: &mut Command, opts: &ExecOptions) -> Result<()> {
+    #[cfg(unix)]
+    {
+        // The sandbox needs root to mount, so it goes before the user switch.
+        if let Some(profile) = &opts.sandbox {
+            crate::sandbox::apply(command, profile)?;
+        }
+        if let Some(id) = &opts.run_as {
+            crate::privilege::apply(command, id);
+        }
+        if let Some(profile) = &opts.sandbox {
+            crate::sandbox::lock_down(command, profile)?;
+        }
+    }
+    #[cfg(not(unix))]
+    if let Some(dir) = &opts.cwd {
+        command.current_dir(dir);
+    }
+    command.envs(opts.env.iter().map(|(k, v)| (k, v)));
+    Ok(())
+}
+
+/// Opens the pipe the wrapped script reports its state on and hands the
+/// write end to the child as fd 3.
+#[cfg(unix)]
+fn state_pipe(command: &mut Command) -> Result<std::io::PipeReader> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    // Both ends are close-on-exec; only the dup2'd copy reaches the shell.
+    let (reader, writer) = std::io::pipe()?;
+    unsafe {
+        command.pre_exec(move || {
+            let fd = writer.as_raw_fd();
+            let rc = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if rc < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(reader)
+}
+
+pub(crate) fn shell_attempts(cmd: &str) -> Vec<(String, Vec<String>)> {
+    let mut attempts: Vec<(String, Vec<String>)> = Vec::new();
     #[cfg(target_os = "windows")]
     {
         // Force the shell to use UTF-8 code page so that captured output
         // decodes correctly for Korean and other Unicode text.
         let prefixed = format!("chcp 65001>nul & {cmd}");
-        let output = Command::new("cmd").args(["/C", &prefixed]).output()?;
-        let mut combined = String::new();
-        if !output.stdout.is_empty() {
-            combined.push_str(&String::from_utf8_lossy(&output.stdout));
-        }
-        if !output.stderr.is_empty() {
-            if !combined.is_empty() { combined.push_str("\n"); }
-            combined.push_str(&String::from_utf8_lossy(&output.stderr));
+        attempts.push(("cmd".into(), vec!["/C".into(), prefixed]));
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(shell) = std::env::var("SHELL") {
+            attempts.push((shell, vec!["-lc".into(), cmd.to_string()]));
         }
-        let max = 16_000;
-        if combined.len() > max {
-            combined = combined[..max].to_string();
-            combined.push_str("\n... [truncated]\n");
+        attempts.push(("bash".into(), vec!["-lc".into(), cmd.to_string()]));
+        attempts.push(("sh".into(), vec!["-lc".into(), cmd.to_string()]));
+    }
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+    {
+        attempts.push(("sh".into(), vec!["-lc".into(), cmd.to_string()]));
+    }
+    attempts
+}
+
+/// What was read from one stream: all of it while it fits in `limit`,
+/// after that its first `limit / 2` bytes and a ring of the latest ones.
+struct Capture {
+    limit: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    /// Bytes that fell out of the ring.
+    dropped: usize,
+}
+
+impl Capture {
+    fn new(limit: usize) -> Self {
+        Self { limit, head: Vec::new(), tail: VecDeque::new(), dropped: 0 }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let room = (self.limit / 2).saturating_sub(self.head.len()).min(bytes.len());
+        self.head.extend_from_slice(&bytes[..room]);
+        self.tail.extend(&bytes[room..]);
+        let excess = self.tail.len().saturating_sub(self.limit - self.limit / 2);
+        self.tail.drain(..excess);
+        self.dropped += excess;
+    }
+
+    /// Bytes read so far, including dropped ones.
+    fn seen(&self) -> usize {
+        self.head.len() + self.dropped + self.tail.len()
+    }
+
+    /// The kept bytes, with nothing to mark where bytes were dropped.
+    fn kept(&self) -> Vec<u8> {
+        let mut bytes = self.head.clone();
+        bytes.extend(&self.tail);
+        bytes
+    }
+
+    /// The output capped at `cap` bytes, and how many bytes were left out.
+    fn capped(&self, cap: usize) -> (String, usize) {
+        if self.dropped == 0 {
+            return cap_output(String::from_utf8_lossy(&self.kept()).into_owned(), cap);
         }
-        return Ok((combined, output.status.success()));
+        let tail: Vec<u8> = self.tail.iter().copied().collect();
+        elide(&String::from_utf8_lossy(&self.head), &String::from_utf8_lossy(&tail), self.dropped, cap)
     }
+}
 
-    #[cfg(not(target_os = "windows"))]
-    {
-        let mut attempts: Vec<(String, Vec<String>)> = Vec::new();
-        #[cfg(target_os = "linux")]
-        {
-            if let Ok(shell) = std::env::var("SHELL") {
-                attempts.push((shell, vec!["-lc".into(), cmd.to_string()]));
+fn pump<R: Read + Send + 'static>(mut reader: R, buf: Arc<Mutex<Capture>>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut chunk = [0u8; 8192];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => buf.lock().unwrap().push(&chunk[..n]),
             }
-            attempts.push(("bash".into(), vec!["-lc".into(), cmd.to_string()]));
-            attempts.push(("sh".into(), vec!["-lc".into(), cmd.to_string()]));
         }
-        #[cfg(not(target_os = "linux"))]
-        {
-            attempts.push(("sh".into(), vec!["-lc".into(), cmd.to_string()]));
-        }
-
-        let mut last_err: Option<anyhow::Error> = None;
-        for (prog, args) in attempts {
-            match Command::new(&prog).args(&args).output() {
-                Ok(out) => {
-                    let mut combined = String::new();
-                    if !out.stdout.is_empty() {
-                        combined.push_str(&String::from_utf8_lossy(&out.stdout));
-                    }
-                    if !out.stderr.is_empty() {
-                        if !combined.is_empty() { combined.push_str("\n"); }
-                        combined.push_str(&String::from_utf8_lossy(&out.stderr));
-                    }
-                    let max = 16_000;
-                    if combined.len() > max {
-                        combined = combined[..max].to_string();
-                        combined.push_str("\n... [truncated]\n");
-                    }
-                    return Ok((combined, out.status.success()));
-                }
-                Err(e) => {
-                    last_err = Some(e.into());
-                }
+    })
+}
+
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) {
+    // The child leads its own process group (see `process_group(0)`).
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+#[cfg(windows)]
+fn kill_tree(child: &mut Child) {
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &child.id().to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    let _ = child.kill();
+}
+
+fn combine(stdout: &[u8], stderr: &[u8]) -> String {
+    let mut combined = String::new();
+    if !stdout.is_empty() {
+        combined.push_str(&String::from_utf8_lossy(stdout));
+    }
+    if !stderr.is_empty() {
+        if !combined.is_empty() { combined.push('\n'); }
+        combined.push_str(&String::from_utf8_lossy(stderr));
+    }
+    combined
+}
+
+/// Keeps the first and last `cap / 2` bytes of `text` (on char boundaries)
+/// and replaces the middle with a note saying how much was dropped.
+pub fn cap_output(text: String, cap: usize) -> (String, usize) {
+    if text.len() <= cap {
+        return (text, 0);
+    }
+    let mut head_end = cap / 2;
+    while !text.is_char_boundary(head_end) {
+        head_end -= 1;
+    }
+    elide(&text[..head_end], &text[head_end..], 0, cap)
+}
+
+/// Up to `cap / 2` bytes from the start of `head` and the rest of `cap`
+/// from the end of `tail`, with one note in between counting everything
+/// left out, `dropped` bytes that were between them included.
+fn elide(head: &str, tail: &str, dropped: usize, cap: usize) -> (String, usize) {
+    let mut head_end = (cap / 2).min(head.len());
+    while !head.is_char_boundary(head_end) {
+        head_end -= 1;
+    }
+    let mut tail_start = tail.len().saturating_sub(cap - cap / 2);
+    while !tail.is_char_boundary(tail_start) {
+        tail_start += 1;
+    }
+    let omitted = head.len() - head_end + dropped + tail_start;
+    let capped = format!("{}\n... [{} bytes omitted] ...\n{}", &head[..head_end], omitted, &tail[tail_start..]);
+    (capped, omitted)
+}
+
+fn wait_for(
+    mut child: Child,
+    state_reader: Option<impl Read + Send + 'static>,
+    opts: &ExecOptions,
+    stop: &dyn Fn() -> bool,
+    progress: &mut dyn FnMut(&str),
+) -> Result<ExecutionResult> {
+    let started = Instant::now();
+    let mut last_progress = started;
+    let mut reported_len = 0;
+    let limit = opts.max_output_bytes.unwrap_or(MAX_CAPTURE_BYTES);
+    let stdout = Arc::new(Mutex::new(Capture::new(limit)));
+    let stderr = Arc::new(Mutex::new(Capture::new(limit)));
+    let mut readers = Vec::new();
+    if let Some(out) = child.stdout.take() {
+        readers.push(pump(out, stdout.clone()));
+    }
+    if let Some(err) = child.stderr.take() {
+        readers.push(pump(err, stderr.clone()));
+    }
+    let state = Arc::new(Mutex::new(Capture::new(MAX_CAPTURE_BYTES)));
+    if let Some(reader) = state_reader {
+        readers.push(pump(reader, state.clone()));
+    }
+
+    let mut interrupted = false;
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        timed_out = opts.timeout.is_some_and(|t| started.elapsed() >= t);
+        interrupted = !timed_out && stop();
+        if timed_out || interrupted {
+            kill_tree(&mut child);
+            break child.wait().ok();
+        }
+        if opts.progress_interval.is_some_and(|i| last_progress.elapsed() >= i) {
+            last_progress = Instant::now();
+            let (stdout, stderr) = (stdout.lock().unwrap(), stderr.lock().unwrap());
+            if stdout.seen() + stderr.seen() != reported_len {
+                reported_len = stdout.seen() + stderr.seen();
+                progress(&combine(&stdout.kept(), &stderr.kept()));
             }
         }
-        Err(anyhow!(
-            "Failed to run command with available shells (last error: {:?})",
-            last_err
-        ))
+        std::thread::sleep(WAIT_INTERVAL);
+    };
+    // After a kill, background grandchildren may still hold the pipes open;
+    // give the readers a moment to drain instead of joining them.
+    if timed_out || interrupted {
+        let drain_until = std::time::Instant::now() + Duration::from_millis(200);
+        while readers.iter().any(|r| !r.is_finished()) && std::time::Instant::now() < drain_until {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    } else {
+        for r in readers {
+            let _ = r.join();
+        }
     }
+
+    let duration = started.elapsed();
+    let state = state.lock().unwrap();
+    let state = if timed_out || interrupted || state.dropped > 0 {
+        None
+    } else {
+        crate::session::parse_state(&state.kept())
+    };
+    let (stdout, stderr) = (stdout.lock().unwrap(), stderr.lock().unwrap());
+    // Share the cap; a quiet stream leaves its unused half to the other.
+    let stdout_cap = limit - stderr.seen().min(limit / 2);
+    let stderr_cap = limit - stdout.seen().min(stdout_cap);
+    let ((stdout, stdout_omitted), (stderr, stderr_omitted)) = (stdout.capped(stdout_cap), stderr.capped(stderr_cap));
+    Ok(ExecutionResult {
+        stdout,
+        stderr,
+        exit_code: status.and_then(|s| s.code()),
+        signal: status.and_then(exit_signal),
+        duration,
+        stdout_omitted,
+        stderr_omitted,
+        interrupted,
+        timed_out,
+        timeout: opts.timeout,
+        state,
+    })
+}
+
+#[cfg(unix)]
+pub(crate) fn exit_signal(status: std::process::ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn exit_signal(_status: std::process::ExitStatus) -> Option<i32> {
+    None
 }
 
 #[cfg(test)]
@@ -93,4 +539,187 @@ mod tests {
         let (_out, ok) = execute_command("nonexistent_command_zzz").unwrap();
         assert!(!ok);
     }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn exec_stop_kills_and_keeps_output() {
+        let started = std::time::Instant::now();
+        // Login shells may take a while to source profiles before the echo runs.
+        let stop = move || started.elapsed() > Duration::from_secs(5);
+        let result =
+            execute_command_with("echo partial; exec sleep 30", &ExecOptions::default(), &stop, &mut |_| {})
+                .unwrap();
+        assert!(result.interrupted);
+        assert!(!result.success());
+        assert_eq!(result.audit_status(), "interrupted");
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        assert!(result.stdout.contains("partial"));
+        assert!(result.render().contains("interrupted: agent shutting down"));
+        assert!(started.elapsed() < Duration::from_secs(20));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn exec_timeout_kills_process_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("survived");
+        // The background sleeper would create the marker if it outlived the kill.
+        let cmd = format!("(sleep 8; touch {}) & echo started; sleep 30", marker.display());
+        let opts = ExecOptions { timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let started = Instant::now();
+        let result = execute_command_with(&cmd, &opts, &|| false, &mut |_| {}).unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.audit_status(), "timeout");
+        assert!(result.stdout.contains("started"));
+        assert!(result.render().contains("timed out after 5 s"));
+        assert!(started.elapsed() < Duration::from_secs(8));
+        std::thread::sleep(Duration::from_secs(4));
+        assert!(!marker.exists());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn exec_reports_progress() {
+        let opts = ExecOptions { progress_interval: Some(Duration::from_millis(200)), ..Default::default() };
+        let mut snapshots = Vec::new();
+        let result =
+            execute_command_with("echo one; sleep 1; echo two", &opts, &|| false, &mut |s| {
+                snapshots.push(s.to_string())
+            })
+            .unwrap();
+        assert!(result.success());
+        assert!(snapshots.iter().any(|s| s.contains("one") && !s.contains("two")));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn exec_separates_streams_and_exit_code() {
+        let result =
+            execute_command_with("echo out; echo err >&2; exit 3", &ExecOptions::default(), &|| false, &mut |_| {})
+                .unwrap();
+        assert_eq!(result.stdout, "out\n");
+        // Login shells may print profile warnings to stderr first.
+        assert!(result.stderr.ends_with("err\n"));
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.signal, None);
+        assert_eq!(result.audit_status(), "failed");
+        let text = result.render();
+        assert!(text.starts_with("[stdout]\nout\n[stderr]\n"));
+        assert!(text.contains("err\n[exit_code=3 | signal=none | duration="));
+        assert!(result.audit_details().contains(&("exit_code".into(), "3".into())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_runs_as_mapped_user() {
+        // Switching users needs root; skip elsewhere.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let Ok(id) = crate::privilege::resolve("nobody", None) else { return };
+        let opts = ExecOptions { run_as: Some(id.clone()), ..Default::default() };
+        let result = execute_command_with("id -u; echo $HOME", &opts, &|| false, &mut |_| {}).unwrap();
+        assert!(result.success(), "{}", result.render());
+        let mut lines = result.stdout.lines();
+        assert_eq!(lines.next(), Some(id.uid.to_string().as_str()));
+        assert_eq!(lines.next(), Some(id.home.to_string_lossy().as_ref()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn exec_in_sandbox_is_isolated() {
+        // Namespaces and mounts need root; skip elsewhere.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let profile = SandboxProfile {
+            writable_paths: vec![dir.path().display().to_string()],
+            file_size_bytes: Some(4096),
+            seccomp: true,
+            ..Default::default()
+        };
+        let opts = ExecOptions { sandbox: Some(profile), capture_state: true, ..Default::default() };
+        let cmd = format!(
+            "echo pid=$$; ls /sys/class/net >/dev/null; cat /proc/net/dev | tail -n +3 | cut -d: -f1 | tr -d ' '; \
+             touch /etc/notionssh-sandbox-test 2>/dev/null || echo root-ro; \
+             echo ok > {dir}/w && echo tmp-rw; \
+             head -c 10000 /dev/zero > {dir}/big 2>/dev/null; echo size=$(stat -c %s {dir}/big); \
+             unshare -n true 2>/dev/null || echo unshare-blocked; \
+             echo dev=$(ls /dev); echo x > /dev/null && echo null-rw",
+            dir = dir.path().display()
+        );
+        let result = execute_command_with(&cmd, &opts, &|| false, &mut |_| {}).unwrap();
+        let out = result.stdout;
+        assert!(out.contains("pid=1\n"), "{out}");
+        assert!(out.contains("\nlo\n") && !out.contains("eth0"), "{out}");
+        assert!(out.contains("root-ro"), "{out}");
+        assert!(out.contains("tmp-rw"), "{out}");
+        assert!(out.contains("size=4096"), "{out}");
+        assert!(out.contains("unshare-blocked"), "{out}");
+        assert!(out.contains("dev=fd null stderr stdin stdout tty urandom zero\n"), "{out}");
+        assert!(out.contains("null-rw"), "{out}");
+        assert!(!std::path::Path::new("/etc/notionssh-sandbox-test").exists());
+        assert!(result.state.is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sandbox_cannot_be_undone_from_inside() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let target = "/etc/notionssh-escape-test";
+        let cmd = format!(
+            "mount -o remount,rw / 2>/dev/null && echo remounted; \
+             mount -o remount,rw,bind /etc 2>/dev/null && echo remounted; \
+             unshare -rm sh -c 'mount -o remount,rw,bind /etc' 2>/dev/null && echo remounted; \
+             touch {target} 2>/dev/null || echo etc-ro; \
+             grep -E '^Cap(Eff|Prm|Bnd)' /proc/self/status",
+        );
+        // Without seccomp, the dropped capabilities alone must hold.
+        for seccomp in [true, false] {
+            let profile =
+                SandboxProfile { writable_paths: vec![dir.path().display().to_string()], seccomp, ..Default::default() };
+            let opts = ExecOptions { sandbox: Some(profile), ..Default::default() };
+            let result = execute_command_with(&cmd, &opts, &|| false, &mut |_| {}).unwrap();
+            let out = result.stdout;
+            assert!(!out.contains("remounted"), "seccomp={seccomp}: {out}");
+            assert!(out.contains("etc-ro"), "seccomp={seccomp}: {out}");
+            assert_eq!(out.matches(":\t0000000000000000").count(), 3, "seccomp={seccomp}: {out}");
+            assert!(!std::path::Path::new(target).exists());
+        }
+
+        // The user switch sits between the sandbox setup and the lock-down.
+        let Ok(id) = crate::privilege::resolve("nobody", None) else { return };
+        let opts = ExecOptions { sandbox: Some(SandboxProfile::default()), run_as: Some(id.clone()), ..Default::default() };
+        let result = execute_command_with("id -u", &opts, &|| false, &mut |_| {}).unwrap();
+        assert_eq!(result.stdout.trim(), id.uid.to_string(), "{}", result.render());
+    }
+
+    #[test]
+    fn cap_keeps_head_and_tail_on_char_boundaries() {
+        let text = format!("head{}tail", "\u{d55c}".repeat(1000));
+        let (capped, omitted) = cap_output(text.clone(), 101);
+        assert!(capped.starts_with("head") && capped.ends_with("tail"));
+        assert_eq!(capped.matches("bytes omitted").count(), 1);
+        assert!(capped.contains(&format!("[{} bytes omitted]", omitted)));
+        assert!(omitted >= text.len() - 101);
+        assert_eq!(cap_output("short".into(), 101), ("short".to_string(), 0));
+    }
+
+    #[test]
+    fn capture_keeps_head_and_latest_bytes_while_reading() {
+        let mut capture = Capture::new(10);
+        for chunk in ["abc", "defgh", "ijklmnop", "qrstuvwxyz"] {
+            capture.push(chunk.as_bytes());
+        }
+        assert_eq!(capture.kept(), b"abcdevwxyz");
+        assert_eq!((capture.dropped, capture.seen()), (16, 26));
+        let (text, omitted) = capture.capped(10);
+        assert_eq!((text.as_str(), omitted), ("abcde\n... [16 bytes omitted] ...\nvwxyz", 16));
+        let (text, omitted) = capture.capped(4);
+        assert_eq!((text.as_str(), omitted), ("ab\n... [22 bytes omitted] ...\nyz", 22));
+    }
 }
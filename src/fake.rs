@@ -0,0 +1,220 @@
+use crate::model::PaginatedBlocks;
+use crate::notion::NotionApi;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The bot user `retrieve_me` returns.
+pub const BOT_USER_ID: &str = "00000000-0000-4000-a000-000000000001";
+
+/// In-memory `NotionApi` implementation.
+///
+/// Blocks live in a tree keyed by id, so the whole poll -> parse -> execute ->
+/// append cycle can run without network access.
+#[derive(Default)]
+pub struct FakeNotion {
+    state: Mutex<FakeState>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    blocks: HashMap<String, Value>,
+    children: HashMap<String, Vec<String>>,
+    users: HashMap<String, Value>,
+    uploads: HashMap<String, (String, Vec<u8>)>,
+    /// `retrieve_user` calls still to fail.
+    failing_user_lookups: u32,
+    next_id: u64,
+}
+
+impl FakeState {
+    fn insert(&mut self, parent_id: &str, mut block: Value) -> Value {
+        self.next_id += 1;
+        let id = block
+            .get("id")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| format!("00000000-0000-4000-8000-{:012x}", self.next_id));
+        if let Some(obj) = block.as_object_mut() {
+            obj.insert("id".into(), Value::String(id.clone()));
+            obj.entry("object").or_insert_with(|| json!("block"));
+            obj.entry("created_time").or_insert_with(|| json!("2025-01-01T00:00:00.000Z"));
+            obj.insert("has_children".into(), json!(false));
+        }
+        if let Some(parent) = self.blocks.get_mut(parent_id).and_then(|p| p.as_object_mut()) {
+            parent.insert("has_children".into(), json!(true));
+        }
+        self.blocks.insert(id.clone(), block.clone());
+        self.children.entry(parent_id.to_string()).or_default().push(id);
+        block
+    }
+}
+
+impl FakeNotion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a person user so `retrieve_user` can resolve their email.
+    pub fn add_user(&self, user_id: &str, email: &str) {
+        self.add_raw_user(json!({
+            "object": "user",
+            "id": user_id,
+            "type": "person",
+            "person": {"email": email}
+        }));
+    }
+
+    /// Registers a user object as `GET /users/{id}` returns it, e.g. a bot.
+    pub fn add_raw_user(&self, user: Value) {
+        let id = user["id"].as_str().unwrap_or_default().to_string();
+        self.state.lock().unwrap().users.insert(id, user);
+    }
+
+    /// Makes the next `times` `retrieve_user` calls fail, as when Notion is
+    /// briefly unreachable.
+    pub fn fail_user_lookups(&self, times: u32) {
+        self.state.lock().unwrap().failing_user_lookups = times;
+    }
+
+    /// Appends a raw block under `parent_id` and returns its id.
+    pub fn add_block(&self, parent_id: &str, block: Value) -> String {
+        let created = self.state.lock().unwrap().insert(parent_id, block);
+        created["id"].as_str().unwrap_or_default().to_string()
+    }
+
+    /// Appends a paragraph block containing `text`, authored by `user_id`.
+    pub fn add_paragraph(&self, parent_id: &str, text: &str, user_id: &str) -> String {
+        self.add_block(
+            parent_id,
+            json!({
+                "type": "paragraph",
+                "created_by": {"object": "user", "id": user_id},
+                "last_edited_by": {"object": "user", "id": user_id},
+                "paragraph": {"rich_text": [{"type": "text", "text": {"content": text}, "plain_text": text}]}
+            }),
+        )
+    }
+
+    /// Appends a to_do block containing `text`, authored by `user_id`.
+    pub fn add_todo(&self, parent_id: &str, text: &str, user_id: &str, checked: bool) -> String {
+        self.add_block(
+            parent_id,
+            json!({
+                "type": "to_do",
+                "created_by": {"object": "user", "id": user_id},
+                "last_edited_by": {"object": "user", "id": user_id},
+                "to_do": {
+                    "rich_text": [{"type": "text", "text": {"content": text}, "plain_text": text}],
+                    "checked": checked
+                }
+            }),
+        )
+    }
+
+    pub fn block(&self, block_id: &str) -> Option<Value> {
+        self.state.lock().unwrap().blocks.get(block_id).cloned()
+    }
+
+    /// Filename and content of a file sent through `upload_file`.
+    pub fn upload(&self, upload_id: &str) -> Option<(String, Vec<u8>)> {
+        self.state.lock().unwrap().uploads.get(upload_id).cloned()
+    }
+
+    pub fn children(&self, block_id: &str) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .children
+            .get(block_id)
+            .map(|ids| ids.iter().filter_map(|id| state.blocks.get(id).cloned()).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl NotionApi for FakeNotion {
+    fn list_children(
+        &self,
+        block_id: &str,
+        start_cursor: Option<&str>,
+        page_size: u32,
+    ) -> Result<PaginatedBlocks> {
+        let all = self.children(block_id);
+        let start = match start_cursor {
+            Some(c) => all
+                .iter()
+                .position(|b| b.get("id").and_then(|v| v.as_str()) == Some(c))
+                .ok_or_else(|| anyhow!("fake notion: unknown cursor {c}"))?,
+            None => 0,
+        };
+        let end = (start + page_size.max(1) as usize).min(all.len());
+        let next_cursor = all
+            .get(end)
+            .and_then(|b| b.get("id"))
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string());
+        Ok(PaginatedBlocks {
+            results: all[start..end].to_vec(),
+            has_more: next_cursor.is_some(),
+            next_cursor,
+        })
+    }
+
+    fn append_children(&self, block_id: &str, children: Vec<Value>) -> Result<Vec<Value>> {
+        let mut state = self.state.lock().unwrap();
+        Ok(children.into_iter().map(|c| state.insert(block_id, c)).collect())
+    }
+
+    fn update_block(&self, block_id: &str, patch: Value) -> Result<Value> {
+        let mut state = self.state.lock().unwrap();
+        let block = state
+            .blocks
+            .get_mut(block_id)
+            .ok_or_else(|| anyhow!("fake notion: block {block_id} not found"))?;
+        if let (Some(obj), Some(fields)) = (block.as_object_mut(), patch.as_object()) {
+            for (k, v) in fields {
+                obj.insert(k.clone(), v.clone());
+            }
+        }
+        Ok(block.clone())
+    }
+
+    fn retrieve_user(&self, user_id: &str) -> Result<Option<Value>> {
+        let mut state = self.state.lock().unwrap();
+        if state.failing_user_lookups > 0 {
+            state.failing_user_lookups -= 1;
+            return Err(anyhow!("fake notion: user lookup failed"));
+        }
+        Ok(state.users.get(user_id).cloned())
+    }
+
+    fn retrieve_me(&self) -> Result<Value> {
+        Ok(json!({"object": "user", "id": BOT_USER_ID, "type": "bot", "bot": {}}))
+    }
+
+    fn upload_file(&self, filename: &str, _content_type: &str, data: Vec<u8>) -> Result<String> {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = format!("00000000-0000-4000-9000-{:012x}", state.next_id);
+        state.uploads.insert(id.clone(), (filename.to_string(), data));
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notion::fetch_all_children;
+
+    #[test]
+    fn pagination_walks_all_children() {
+        let fake = FakeNotion::new();
+        for i in 0..130 {
+            fake.add_paragraph("page", &format!("line {i}"), "u1");
+        }
+        let first = fake.list_children("page", None, 100).unwrap();
+        assert_eq!(first.results.len(), 100);
+        assert!(first.has_more);
+        assert_eq!(fetch_all_children(&fake, "page").unwrap().len(), 130);
+    }
+}
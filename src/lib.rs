@@ -1,26 +1,43 @@
+#![allow(non_snake_case)]
+
+pub mod agent;
+pub mod approval;
+pub mod clock;
 pub mod config;
 pub mod access;
 pub mod executor;
+pub mod fake;
+pub mod limits;
 pub mod logger;
 pub mod model;
 pub mod notion;
 pub mod parser;
+pub mod privilege;
+pub mod pty;
+pub mod reload;
+pub mod rules;
+pub mod sandbox;
+pub mod scheduler;
+pub mod session;
+pub mod shell;
+pub mod shutdown;
+pub mod status;
+pub mod users;
 pub mod util;
 pub mod verify;
 
 use anyhow::Result;
 
-pub fn run() -> Result<()> {
-    use config::load_config;
-    use access::{is_allowed, load_or_create};
-    use executor::execute_command;
-    use logger::{write_audit_log, write_command_log};
-    use notion::{
-        append_result_children, build_client, fetch_all_children, is_block_processed,
-        lookup_user_email,
-    };
-    use parser::parse_command_from_block;
-    use util::{extract_page_id, os_name};
+/// Starts the agent. With `strict_access`, lint warnings in access.json
+/// stop it from starting and keep a reloaded file from being applied.
+pub fn run(strict_access: bool) -> Result<()> {
+    use config::{load_config, storage_default_path};
+    use access::{load_or_create, ACCESS_PATH};
+    use agent::Agent;
+    use notion::HttpNotion;
+    use shutdown::{install_signal_handler, Shutdown};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     let enable_ca = if verify::saved_ca_pins_exist() {
         println!("[*] Using saved CA public key verification (.notionSSH/ca.json)");
@@ -46,69 +63,71 @@ pub fn run() -> Result<()> {
     let cfg = load_config()?;
     // Load or initialize access control file
     let access_ctrl = load_or_create()?;
-    let client = build_client(&cfg.api_key)?;
-    let page_id = extract_page_id(&cfg.page_url)?;
+    let warnings = access::lint_file(std::path::Path::new(ACCESS_PATH))?;
+    for w in &warnings {
+        eprintln!("[!] access.json: {w}");
+    }
+    if strict_access && !warnings.is_empty() {
+        return Err(anyhow::anyhow!(
+            "access.json has {} warning(s); not starting because of --strict-access",
+            warnings.len()
+        ));
+    }
+    let shutdown = Arc::new(Shutdown::new(Duration::from_secs(cfg.shutdown_grace_secs)));
+    install_signal_handler(shutdown.clone())?;
+    let api = Arc::new(HttpNotion::new(&cfg.api_key, &cfg.api_base_url)?);
+    let mut agent = Agent::new(cfg, access_ctrl, api)?
+        .watch_access(ACCESS_PATH)
+        .strict_access(strict_access);
+    // Settings taken from environment variables have no file to follow.
+    if storage_default_path().exists() {
+        agent = agent.watch_config(storage_default_path());
+    }
 
     println!("[*] NotionSSH is Loading - waiting for commands. Press Ctrl+C to stop.");
 
-    loop {
-        // Scan for commands
-        let mut tasks = Vec::new();
-        match fetch_all_children(&client, &page_id) {
-            Ok(blocks) => {
-                for b in blocks.iter() {
-                    if let Some(t) = parse_command_from_block(b) {
-                        if is_block_processed(&client, &t.block_id).unwrap_or(false) {
-                            continue;
-                        }
-                        tasks.push(t);
-                    }
-                }
-            }
-            Err(err) => {
-                eprintln!("Failed to fetch children: {err:#}");
-            }
-        }
-
-        if !tasks.is_empty() {
-            for task in tasks {
-                let requester_email = lookup_user_email(
-                    &client,
-                    task.created_by_id.as_deref().unwrap_or("")
-                )
-                .unwrap_or_else(|| "unknown".to_string());
-
-                // Permission check
-                let (out, status) = if is_allowed(&access_ctrl, &requester_email, &task.command) {
-                    execute_command(&task.command)?
-                } else {
-                    (
-                        "
-                        permission denied to execute the command. Please retry with an authorized account.
-                        ".to_string(),
-                        false,
-                    )
-                };
+    agent.run_until(&shutdown)?;
+    println!("[*] NotionSSH stopped.");
+    Ok(())
+}
 
-                write_command_log(&requester_email, &task.command)?;
-                write_audit_log(
-                    &task.command,
-                    &requester_email,
-                    &task.created_time,
-                    &os_name(),
-                    if status { "success" } else { "failed" },
-                )?;
+/// `notionSSH access <subcommand>`: inspects `.notionSSH/access.json` (or
+/// the file given with `--file`) without starting the agent. `lint` fails
+/// when there are warnings, so it can gate deployments.
+pub fn run_access_command(args: &[String]) -> Result<()> {
+    use anyhow::anyhow;
+    use std::path::PathBuf;
 
-                append_result_children(
-                    &client,
-                    &task.block_id,
-                    &task.command,
-                    &out,
-                    &requester_email,
-                )?;
+    const USAGE: &str = "usage: notionSSH access explain [--file <access.json>] <email> <command...>\n       \
+                         notionSSH access lint [--file <access.json>]";
+    let mut path = PathBuf::from(access::ACCESS_PATH);
+    let mut rest = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--file" => path = iter.next().map(PathBuf::from).ok_or_else(|| anyhow!(USAGE))?,
+            _ => rest.push(arg.as_str()),
+        }
+    }
+    let af = access::load_from_path(&path)
+        .map_err(|e| anyhow!("cannot load {}: {e:#}", path.display()))?;
+    match rest.as_slice() {
+        ["lint"] => {
+            let warnings = access::lint_file(&path)?;
+            for w in &warnings {
+                println!("{w}");
+            }
+            if warnings.is_empty() {
+                println!("{}: no problems found", path.display());
+                Ok(())
+            } else {
+                Err(anyhow!("{}: {} warning(s)", path.display(), warnings.len()))
             }
         }
-
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        ["explain", email, command @ ..] if !command.is_empty() => {
+            println!("{}", access::explain(&af, email, &command.join(" ")));
+            Ok(())
+        }
+        _ => Err(anyhow!(USAGE)),
     }
 }
@@ -0,0 +1,218 @@
+use crate::access::{DeniedBy, PermRule, TimeWindow};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use std::collections::{HashMap, VecDeque};
+
+/// Starts older than this no longer count for any limit; a calendar day
+/// is at most 25 hours long.
+const RETENTION_HOURS: i64 = 26;
+
+/// When each requester's recent commands started, for rate limits and
+/// daily quotas. Kept in memory; a restart starts counting afresh.
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    starts: HashMap<String, VecDeque<DateTime<Utc>>>,
+}
+
+impl Usage {
+    pub fn record(&mut self, email: &str, at: DateTime<Utc>) {
+        let starts = self.starts.entry(email.to_ascii_lowercase()).or_default();
+        starts.push_back(at);
+        while starts.front().is_some_and(|t| at - *t > Duration::hours(RETENTION_HOURS)) {
+            starts.pop_front();
+        }
+    }
+
+    fn since(&self, email: &str, since: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        self.starts
+            .get(&email.to_ascii_lowercase())
+            .map(|s| s.iter().filter(|t| **t > since).copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Checks the time zone and time windows of a role.
+pub fn validate(rule: &PermRule) -> Result<()> {
+    if let Some(tz) = &rule.timezone {
+        tz.parse::<Tz>().map_err(|_| anyhow!("unknown timezone '{}'", tz))?;
+    }
+    for w in &rule.time_windows {
+        parse_time(&w.start)?;
+        parse_time(&w.end)?;
+        for d in &w.days {
+            parse_day(d)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the time windows, rate limit and daily quota of the first role
+/// in `chain` that sets each, to a command of `email` starting at `now`.
+/// `Err` carries the message for the requester.
+pub fn check(
+    chain: &[(&str, &PermRule)],
+    email: &str,
+    now: DateTime<Utc>,
+    usage: &Usage,
+) -> std::result::Result<(), (DeniedBy, String)> {
+    let tz_name = chain.iter().find_map(|(_, r)| r.timezone.as_deref());
+    let tz = tz_name.and_then(|t| t.parse::<Tz>().ok());
+    let zone = tz_name.unwrap_or("agent local time");
+
+    if let Some(windows) = chain.iter().map(|(_, r)| &r.time_windows).find(|w| !w.is_empty()) {
+        let local = local_time(now, tz);
+        if !windows.iter().any(|w| in_window(w, local)) {
+            let allowed: Vec<String> = windows.iter().map(describe).collect();
+            let message = format!(
+                "permission denied: this role may only run commands during {} ({}).",
+                allowed.join(", "),
+                zone
+            );
+            return Err((DeniedBy::TimeWindow, message));
+        }
+    }
+
+    if let Some(limit) = chain.iter().find_map(|(_, r)| r.rate_limit.as_ref()) {
+        for (max, span, unit) in [(limit.per_minute, 60, "minute"), (limit.per_hour, 3600, "hour")] {
+            let Some(max) = max.map(|m| m as usize) else { continue };
+            let span = Duration::seconds(span);
+            let recent = usage.since(email, now - span);
+            if recent.len() >= max {
+                // The start that has to age out before another is allowed.
+                let wait = recent
+                    .get(recent.len() - max)
+                    .map(|t| (*t + span - now).num_seconds().max(1))
+                    .unwrap_or(span.num_seconds());
+                let message = format!(
+                    "rate limit exceeded: at most {} commands per {}. Try again in {} s.",
+                    max, unit, wait
+                );
+                return Err((DeniedBy::RateLimit, message));
+            }
+        }
+    }
+
+    if let Some(quota) = chain.iter().find_map(|(_, r)| r.daily_quota) {
+        let today = local_time(now, tz).date();
+        let used = usage
+            .since(email, now - Duration::hours(RETENTION_HOURS))
+            .into_iter()
+            .filter(|t| local_time(*t, tz).date() == today)
+            .count();
+        if used >= quota as usize {
+            let message = format!(
+                "daily quota reached: at most {} commands per day. It resets at midnight ({}).",
+                quota, zone
+            );
+            return Err((DeniedBy::DailyQuota, message));
+        }
+    }
+    Ok(())
+}
+
+fn local_time(t: DateTime<Utc>, tz: Option<Tz>) -> NaiveDateTime {
+    match tz {
+        Some(tz) => t.with_timezone(&tz).naive_local(),
+        None => t.with_timezone(&Local).naive_local(),
+    }
+}
+
+fn in_window(w: &TimeWindow, local: NaiveDateTime) -> bool {
+    let (Ok(start), Ok(end)) = (parse_time(&w.start), parse_time(&w.end)) else { return false };
+    let on = |day: Weekday| w.days.is_empty() || w.days.iter().any(|d| parse_day(d).ok() == Some(day));
+    let (day, time) = (local.weekday(), local.time());
+    if start <= end {
+        on(day) && start <= time && time < end
+    } else {
+        // Past midnight: the tail end belongs to the previous day's window.
+        (on(day) && time >= start) || (on(day.pred()) && time < end)
+    }
+}
+
+fn describe(w: &TimeWindow) -> String {
+    let days = if w.days.is_empty() { "every day".to_string() } else { w.days.join(",") };
+    format!("{} {}-{}", days, w.start, w.end)
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| anyhow!("invalid time '{}', expected HH:MM", s))
+}
+
+fn parse_day(s: &str) -> Result<Weekday> {
+    match s.to_ascii_lowercase().as_str() {
+        "mon" => Ok(Weekday::Mon),
+        "tue" => Ok(Weekday::Tue),
+        "wed" => Ok(Weekday::Wed),
+        "thu" => Ok(Weekday::Thu),
+        "fri" => Ok(Weekday::Fri),
+        "sat" => Ok(Weekday::Sat),
+        "sun" => Ok(Weekday::Sun),
+        _ => Err(anyhow!("invalid day '{}', expected mon, tue, ... sun", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::RateLimit;
+    use chrono::TimeZone;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    #[test]
+    fn time_windows_use_the_role_time_zone() {
+        let rule = PermRule {
+            timezone: Some("Asia/Seoul".into()),
+            time_windows: vec![
+                TimeWindow {
+                    days: ["mon", "tue", "wed", "thu", "fri"].map(String::from).to_vec(),
+                    start: "09:00".into(),
+                    end: "18:00".into(),
+                },
+                TimeWindow { days: vec!["sat".into()], start: "22:00".into(), end: "02:00".into() },
+            ],
+            ..Default::default()
+        };
+        validate(&rule).unwrap();
+        let chain = [("contractor", &rule)];
+        let usage = Usage::default();
+        // 2026-10-16 is a Friday; 01:00 UTC is 10:00 in Seoul.
+        assert!(check(&chain, "c", at(2026, 10, 16, 1, 0, 0), &usage).is_ok());
+        let (by, message) = check(&chain, "c", at(2026, 10, 16, 10, 0, 0), &usage).unwrap_err();
+        assert_eq!(by, DeniedBy::TimeWindow);
+        assert!(message.contains("mon,tue,wed,thu,fri 09:00-18:00, sat 22:00-02:00 (Asia/Seoul)"));
+        // Sunday 01:00 in Seoul still belongs to Saturday's window.
+        assert!(check(&chain, "c", at(2026, 10, 17, 16, 0, 0), &usage).is_ok());
+
+        let bad = PermRule { timezone: Some("Mars/Olympus".into()), ..Default::default() };
+        assert!(validate(&bad).is_err());
+    }
+
+    #[test]
+    fn rate_limits_and_quota_count_recent_starts() {
+        let rule = PermRule {
+            timezone: Some("UTC".into()),
+            rate_limit: Some(RateLimit { per_minute: Some(2), per_hour: None }),
+            daily_quota: Some(3),
+            ..Default::default()
+        };
+        let chain = [("default", &rule)];
+        let mut usage = Usage::default();
+        let t0 = at(2026, 10, 16, 23, 58, 0);
+        usage.record("U@example.com", t0);
+        usage.record("u@example.com", t0 + Duration::seconds(20));
+        let (by, message) = check(&chain, "u@example.com", t0 + Duration::seconds(30), &usage).unwrap_err();
+        assert_eq!(by, DeniedBy::RateLimit);
+        assert!(message.ends_with("Try again in 30 s."), "{message}");
+        assert!(check(&chain, "other@example.com", t0, &usage).is_ok());
+
+        usage.record("u@example.com", t0 + Duration::seconds(70));
+        let (by, _) = check(&chain, "u@example.com", t0 + Duration::seconds(100), &usage).unwrap_err();
+        assert_eq!(by, DeniedBy::DailyQuota);
+        // A new day in the role's time zone.
+        assert!(check(&chain, "u@example.com", t0 + Duration::seconds(150), &usage).is_ok());
+    }
+}
@@ -2,10 +2,37 @@ use anyhow::Result;
 use chrono::{Datelike, Local, Timelike};
 use std::fs::{self, OpenOptions};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 pub use crate::util::node_name;
 
+/// Where the command log directory, the audit log file and the saved
+/// full outputs live.
+#[derive(Debug, Clone)]
+pub struct LogPaths {
+    pub command_dir: PathBuf,
+    pub audit_file: PathBuf,
+    pub output_dir: PathBuf,
+}
+
+impl Default for LogPaths {
+    fn default() -> Self {
+        Self {
+            command_dir: PathBuf::from("./logs"),
+            audit_file: PathBuf::from("./log"),
+            output_dir: PathBuf::from(".notionSSH/outputs"),
+        }
+    }
+}
+
+impl LogPaths {
+    /// Same layout as the default, rooted at `dir` instead of the working directory.
+    pub fn under(dir: &Path) -> Self {
+        Self { command_dir: dir.join("logs"), audit_file: dir.join("log"), output_dir: dir.join("outputs") }
+    }
+}
+
 pub fn write_command_log(email: &str, command: &str) -> Result<()> {
     let logs_dir = Path::new("./logs");
     write_command_log_with_dir(logs_dir, email, command)
@@ -48,12 +75,83 @@ pub fn write_audit_log(command: &str, requester: &str, req_time_iso: &str, node:
 }
 
 pub fn write_audit_log_to(path: &Path, command: &str, requester: &str, req_time_iso: &str, node: &str, status: &str) -> Result<()> {
+    let entry = AuditEntry {
+        command: command.to_string(),
+        requester: requester.to_string(),
+        requested_at: req_time_iso.to_string(),
+        node: node.to_string(),
+        status: status.to_string(),
+        details: Vec::new(),
+    };
+    write_audit_entry(path, &entry)
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Default)]
+pub struct AuditEntry {
+    pub command: String,
+    pub requester: String,
+    pub requested_at: String,
+    pub node: String,
+    pub status: String,
+    /// `key=value` pairs written space-separated in a sixth column.
+    pub details: Vec<(String, String)>,
+}
+
+impl AuditEntry {
+    pub fn to_line(&self) -> String {
+        let mut line = format!(
+            "{} , {} , {} , {} , {}",
+            self.command, self.requester, self.requested_at, self.node, self.status
+        );
+        if !self.details.is_empty() {
+            let details: Vec<String> = self.details.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            line.push_str(" , ");
+            line.push_str(&details.join(" "));
+        }
+        line
+    }
+}
+
+pub fn write_audit_entry(path: &Path, entry: &AuditEntry) -> Result<()> {
     let mut f = OpenOptions::new().create(true).append(true).open(path)?;
-    let line = format!("{} , {} , {} , {} , {}\n", command, requester, req_time_iso, node, status);
-    f.write_all(line.as_bytes())?;
+    f.write_all(format!("{}\n", entry.to_line()).as_bytes())?;
     Ok(())
 }
 
+/// Writes the full output of the command in `block_id` to `dir` and returns
+/// the file path.
+pub fn save_output(dir: &Path, block_id: &str, output: &str) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let name = format!("{}-{}.log", Local::now().format("%Y%m%d-%H%M%S"), block_id);
+    let path = dir.join(name);
+    fs::write(&path, output)?;
+    Ok(path)
+}
+
+/// Removes saved outputs in `dir` last modified more than `max_age` ago and
+/// returns how many were deleted.
+pub fn prune_outputs(dir: &Path, max_age: Duration) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        let age = now.duration_since(meta.modified()?).unwrap_or_default();
+        if age > max_age {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -77,5 +175,27 @@ mod tests {
         write_audit_log_to(&audit_path, "cmd", "req", "2025-01-01T00:00:00Z", "node1", "success").unwrap();
         let s = fs::read_to_string(&audit_path).unwrap();
         assert!(s.contains("cmd , req , 2025-01-01T00:00:00Z , node1 , success"));
+
+        let entry = AuditEntry {
+            command: "grep x f".into(),
+            status: "failed".into(),
+            details: vec![("exit_code".into(), "1".into()), ("signal".into(), "none".into())],
+            ..Default::default()
+        };
+        assert!(entry.to_line().ends_with(" , failed , exit_code=1 signal=none"));
+    }
+
+    #[test]
+    fn outputs_saved_and_pruned() {
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().join("outputs");
+        let path = save_output(&out_dir, "block-1", "full output").unwrap();
+        assert!(path.file_name().unwrap().to_str().unwrap().ends_with("-block-1.log"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "full output");
+
+        assert_eq!(prune_outputs(&out_dir, Duration::from_secs(3600)).unwrap(), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(prune_outputs(&out_dir, Duration::from_millis(1)).unwrap(), 1);
+        assert!(!path.exists());
     }
 }
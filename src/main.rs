@@ -1,7 +1,12 @@
 fn main() {
     // Enable UTF-8 output on Windows consoles before any printing.
     notionSSH::util::enable_windows_utf8();
-    if let Err(e) = notionSSH::run() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("access") => notionSSH::run_access_command(&args[1..]),
+        _ => notionSSH::run(args.iter().any(|a| a == "--strict-access")),
+    };
+    if let Err(e) = result {
         eprintln!("error: {e:#}");
         std::process::exit(1);
     }
@@ -7,6 +7,11 @@ pub struct CommandTask {
     pub command: String,
     pub created_time: String,
     pub created_by_id: Option<String>,
+    /// Who last changed the block; differs from `created_by_id` when
+    /// someone else edited the command after it was written.
+    pub last_edited_by_id: Option<String>,
+    /// Checkbox state of a to_do block; `None` for paragraphs.
+    pub checked: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -2,12 +2,58 @@ use crate::model::PaginatedBlocks;
 use crate::parser::{children_contains_marker, EXEC_MARKER};
 use crate::util::os_name;
 use anyhow::{anyhow, Result};
-use reqwest::blocking::Client;
+use reqwest::blocking::{multipart, Client, RequestBuilder, Response};
+use reqwest::StatusCode;
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
 use serde_json::{json, Value};
 
 pub const NOTION_VERSION: &str = "2022-06-28";
 pub const STATUS_MARKER: &str = "notionSSH-status";
+pub const DEFAULT_API_BASE_URL: &str = "https://api.notion.com/v1";
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+/// Longest stretch of output shown while a command is still running.
+const PROGRESS_TAIL_CHARS: usize = 1500;
+/// Notion rejects rich_text items longer than this (counted in UTF-16 units).
+const RICH_TEXT_MAX_LEN: usize = 2000;
+/// rich_text items per result code block; longer output continues in the
+/// next code block.
+const SEGMENTS_PER_CODE_BLOCK: usize = 10;
+/// Notion accepts at most 100 children per append request...
+const MAX_CHILDREN_PER_REQUEST: usize = 100;
+/// ...and bodies up to 500KB, so batches are also cut by serialized size.
+const MAX_APPEND_BYTES: usize = 400_000;
+
+/// The subset of the Notion REST API the agent depends on.
+///
+/// `HttpNotion` talks to the real service (or a compatible proxy); tests can
+/// substitute `fake::FakeNotion`, which keeps blocks in memory.
+pub trait NotionApi: Send + Sync {
+    /// `GET /blocks/{id}/children`
+    fn list_children(
+        &self,
+        block_id: &str,
+        start_cursor: Option<&str>,
+        page_size: u32,
+    ) -> Result<PaginatedBlocks>;
+    /// `PATCH /blocks/{id}/children`; returns the created blocks.
+    fn append_children(&self, block_id: &str, children: Vec<Value>) -> Result<Vec<Value>>;
+    /// `PATCH /blocks/{id}`; returns the updated block.
+    fn update_block(&self, block_id: &str, patch: Value) -> Result<Value>;
+    /// `GET /users/{id}`; `Ok(None)` when Notion does not know the user.
+    fn retrieve_user(&self, user_id: &str) -> Result<Option<Value>>;
+    /// `GET /users/me`: the bot user this integration acts as.
+    fn retrieve_me(&self) -> Result<Value>;
+    /// `POST /file_uploads` followed by `POST /file_uploads/{id}/send`;
+    /// returns the file upload id to reference from a `file` block.
+    fn upload_file(&self, filename: &str, content_type: &str, data: Vec<u8>) -> Result<String>;
+}
+
+/// A file uploaded through `NotionApi::upload_file`, ready to be attached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedFile {
+    pub id: String,
+    pub name: String,
+}
 
 pub fn build_client(api_key: &str) -> Result<Client> {
     let mut headers = reqwest::header::HeaderMap::new();
@@ -17,26 +63,169 @@ pub fn build_client(api_key: &str) -> Result<Client> {
     Ok(client)
 }
 
-pub fn fetch_all_children(client: &Client, block_id: &str) -> Result<Vec<Value>> {
-    let mut results = Vec::new();
-    let mut cursor: Option<String> = None;
-    loop {
-        let mut url = format!(
-            "https://api.notion.com/v1/blocks/{}/children?page_size=100",
-            block_id
-        );
-        if let Some(c) = &cursor {
+/// `NotionApi` over HTTPS. `base_url` defaults to `DEFAULT_API_BASE_URL` but
+/// may point at a self-hosted proxy.
+pub struct HttpNotion {
+    client: Client,
+    base_url: String,
+}
+
+impl HttpNotion {
+    pub fn new(api_key: &str, base_url: &str) -> Result<Self> {
+        Ok(Self::with_client(build_client(api_key)?, base_url))
+    }
+
+    pub fn with_client(client: Client, base_url: &str) -> Self {
+        Self { client, base_url: base_url.trim_end_matches('/').to_string() }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    /// Sends `req`, waiting out `429 Too Many Requests` responses as told by
+    /// the `Retry-After` header.
+    fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let Some(this_try) = req.try_clone() else { return Ok(req.send()?) };
+            let resp = this_try.send()?;
+            if resp.status() != StatusCode::TOO_MANY_REQUESTS || attempt >= MAX_RATE_LIMIT_RETRIES {
+                return Ok(resp);
+            }
+            attempt += 1;
+            let wait = resp
+                .headers()
+                .get("retry-after")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(1);
+            std::thread::sleep(std::time::Duration::from_secs(wait.min(60)));
+        }
+    }
+}
+
+impl NotionApi for HttpNotion {
+    fn list_children(
+        &self,
+        block_id: &str,
+        start_cursor: Option<&str>,
+        page_size: u32,
+    ) -> Result<PaginatedBlocks> {
+        let mut url = self.url(&format!("blocks/{}/children?page_size={}", block_id, page_size));
+        if let Some(c) = start_cursor {
             url.push_str(&format!("&start_cursor={}", c));
         }
-        let resp = client.get(&url).send()?;
+        let resp = self.send(self.client.get(&url))?;
         let status = resp.status();
         if !status.is_success() {
             return Err(anyhow!("Notion API error fetching children: {}", status));
         }
-        let body: PaginatedBlocks = resp.json()?;
-        for r in body.results {
-            results.push(r);
+        Ok(resp.json()?)
+    }
+
+    fn append_children(&self, block_id: &str, children: Vec<Value>) -> Result<Vec<Value>> {
+        let payload = json!({ "children": children });
+        let resp = self.send(
+            self.client
+                .patch(self.url(&format!("blocks/{}/children", block_id)))
+                .header(CONTENT_TYPE, "application/json")
+                .body(payload.to_string()),
+        )?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().unwrap_or_default();
+            return Err(anyhow!("Failed to append Notion children: {} - {}", status, text));
+        }
+        let body: Value = resp.json()?;
+        Ok(body
+            .get("results")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn update_block(&self, block_id: &str, patch: Value) -> Result<Value> {
+        let resp = self.send(
+            self.client
+                .patch(self.url(&format!("blocks/{}", block_id)))
+                .header(CONTENT_TYPE, "application/json")
+                .body(patch.to_string()),
+        )?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let txt = resp.text().unwrap_or_default();
+            return Err(anyhow!("Failed to update Notion block: {} - {}", status, txt));
+        }
+        Ok(resp.json()?)
+    }
+
+    fn retrieve_user(&self, user_id: &str) -> Result<Option<Value>> {
+        let resp = self.send(self.client.get(self.url(&format!("users/{}", user_id))))?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().unwrap_or_default();
+            return Err(anyhow!("Failed to retrieve Notion user: {} - {}", status, text));
         }
+        Ok(Some(resp.json()?))
+    }
+
+    fn retrieve_me(&self) -> Result<Value> {
+        let resp = self.send(self.client.get(self.url("users/me")))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().unwrap_or_default();
+            return Err(anyhow!("Failed to retrieve the integration's bot user: {} - {}", status, text));
+        }
+        Ok(resp.json()?)
+    }
+
+    fn upload_file(&self, filename: &str, content_type: &str, data: Vec<u8>) -> Result<String> {
+        let payload = json!({ "filename": filename, "content_type": content_type });
+        let resp = self.send(
+            self.client
+                .post(self.url("file_uploads"))
+                .header(CONTENT_TYPE, "application/json")
+                .body(payload.to_string()),
+        )?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().unwrap_or_default();
+            return Err(anyhow!("Failed to create Notion file upload: {} - {}", status, text));
+        }
+        let body: Value = resp.json()?;
+        let id = body
+            .get("id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Notion did not return a file upload id"))?
+            .to_string();
+
+        let part = multipart::Part::bytes(data)
+            .file_name(filename.to_string())
+            .mime_str(content_type)?;
+        let resp = self.send(
+            self.client
+                .post(self.url(&format!("file_uploads/{}/send", id)))
+                .multipart(multipart::Form::new().part("file", part)),
+        )?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().unwrap_or_default();
+            return Err(anyhow!("Failed to send Notion file upload: {} - {}", status, text));
+        }
+        Ok(id)
+    }
+}
+
+pub fn fetch_all_children(api: &dyn NotionApi, block_id: &str) -> Result<Vec<Value>> {
+    let mut results = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let body = api.list_children(block_id, cursor.as_deref(), 100)?;
+        results.extend(body.results);
         if body.has_more {
             cursor = body.next_cursor;
         } else {
@@ -46,101 +235,247 @@ pub fn fetch_all_children(client: &Client, block_id: &str) -> Result<Vec<Value>>
     Ok(results)
 }
 
-pub fn is_block_processed(client: &Client, block_id: &str) -> Result<bool> {
-    let url = format!(
-        "https://api.notion.com/v1/blocks/{}/children?page_size=50",
-        block_id
-    );
-    let resp = client.get(&url).send()?;
-    if !resp.status().is_success() {
-        return Ok(false);
+/// Long results span several code blocks with the marker in the last one,
+/// so every page of children is checked.
+/// A block whose children cannot be listed counts as not processed.
+pub fn is_block_processed(api: &dyn NotionApi, block_id: &str) -> Result<bool> {
+    match fetch_all_children(api, block_id) {
+        Ok(children) => Ok(children_contains_marker(&children)),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Splits `text` into pieces Notion accepts as single rich_text items,
+/// never cutting inside a character.
+pub fn split_rich_text(text: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut units = 0;
+    for c in text.chars() {
+        if units + c.len_utf16() > RICH_TEXT_MAX_LEN {
+            segments.push(std::mem::take(&mut current));
+            units = 0;
+        }
+        units += c.len_utf16();
+        current.push(c);
     }
-    let body: PaginatedBlocks = resp.json()?;
-    Ok(children_contains_marker(&body.results))
+    if !current.is_empty() || segments.is_empty() {
+        segments.push(current);
+    }
+    segments
+}
+
+fn code_block_from_segments(segments: &[String]) -> Value {
+    let rich: Vec<Value> = segments
+        .iter()
+        .map(|t| json!({"type": "text", "text": {"content": t}}))
+        .collect();
+    json!({
+        "object": "block",
+        "type": "code",
+        "code": {
+            "rich_text": rich,
+            "language": "plain text"
+        }
+    })
+}
+
+fn code_block(text: &str) -> Value {
+    let mut segments = split_rich_text(text);
+    segments.truncate(SEGMENTS_PER_CODE_BLOCK);
+    code_block_from_segments(&segments)
 }
 
-pub fn lookup_user_email(client: &Client, user_id: &str) -> Option<String> {
-    if user_id.is_empty() {
-        return None;
+fn file_block(file: &UploadedFile) -> Value {
+    json!({
+        "object": "block",
+        "type": "file",
+        "file": {
+            "type": "file_upload",
+            "file_upload": {"id": file.id},
+            "name": file.name
+        }
+    })
+}
+
+/// Groups `children` into append requests within Notion's count and size
+/// limits, keeping their order.
+fn batch_children(children: Vec<Value>) -> Vec<Vec<Value>> {
+    let mut batches: Vec<Vec<Value>> = Vec::new();
+    let mut current = Vec::new();
+    let mut bytes = 0;
+    for child in children {
+        let size = child.to_string().len();
+        if !current.is_empty()
+            && (current.len() >= MAX_CHILDREN_PER_REQUEST || bytes + size > MAX_APPEND_BYTES)
+        {
+            batches.push(std::mem::take(&mut current));
+            bytes = 0;
+        }
+        bytes += size;
+        current.push(child);
     }
-    let url = format!("https://api.notion.com/v1/users/{}", user_id);
-    let resp = client.get(&url).send().ok()?;
-    if !resp.status().is_success() {
-        return None;
+    if !current.is_empty() {
+        batches.push(current);
     }
-    let v: Value = resp.json().ok()?;
-    v.get("person")
-        .and_then(|p| p.get("email"))
-        .and_then(|e| e.as_str())
+    batches
+}
+
+/// Appends `children` in as many requests as needed; returns the created
+/// blocks.
+fn append_batched(api: &dyn NotionApi, block_id: &str, children: Vec<Value>) -> Result<Vec<Value>> {
+    let mut created = Vec::new();
+    for batch in batch_children(children) {
+        created.extend(api.append_children(block_id, batch)?);
+    }
+    Ok(created)
+}
+
+/// Appends terminal output of an interactive session under its block as
+/// code blocks, without `EXEC_MARKER`; returns the ids of the new blocks.
+pub fn append_session_output(api: &dyn NotionApi, block_id: &str, text: &str) -> Result<Vec<String>> {
+    let children: Vec<Value> = split_rich_text(text)
+        .chunks(SEGMENTS_PER_CODE_BLOCK)
+        .map(code_block_from_segments)
+        .collect();
+    let created = append_batched(api, block_id, children)?;
+    Ok(created
+        .iter()
+        .filter_map(|b| b.get("id").and_then(|v| v.as_str()).map(|s| s.to_string()))
+        .collect())
+}
+
+/// Code block text shown while a command is still running. It deliberately
+/// carries no `EXEC_MARKER`; only the final result does.
+pub fn build_progress_text(cmd: &str, partial: &str, email: &str, elapsed_secs: u64) -> String {
+    let skipped = partial.chars().count().saturating_sub(PROGRESS_TAIL_CHARS);
+    let mut text = format!("$ {}\n", cmd);
+    if skipped > 0 {
+        text.push_str(&format!("... [{} earlier characters not shown]\n", skipped));
+    }
+    text.extend(partial.chars().skip(skipped));
+    if !partial.is_empty() && !partial.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(&format!(
+        "---\nrunning\u{2026} {}s | executed_by={} | node={}",
+        elapsed_secs,
+        email,
+        os_name()
+    ));
+    text
+}
+
+/// Appends a "running…" code block under `block_id` and returns its id so
+/// it can be updated while the command runs.
+pub fn start_streaming_result(api: &dyn NotionApi, block_id: &str, cmd: &str, email: &str) -> Result<String> {
+    let created = api.append_children(block_id, vec![code_block(&build_progress_text(cmd, "", email, 0))])?;
+    created
+        .first()
+        .and_then(|b| b.get("id"))
+        .and_then(|v| v.as_str())
         .map(|s| s.to_string())
+        .ok_or_else(|| anyhow!("Notion did not return the created result block"))
+}
+
+pub fn update_streaming_result(
+    api: &dyn NotionApi,
+    code_block_id: &str,
+    cmd: &str,
+    partial: &str,
+    email: &str,
+    elapsed_secs: u64,
+) -> Result<()> {
+    let block = code_block(&build_progress_text(cmd, partial, email, elapsed_secs));
+    api.update_block(code_block_id, json!({ "code": block["code"] }))?;
+    Ok(())
 }
 
-pub fn build_result_payload(cmd: &str, output: &str, email: &str) -> Value {
+/// Replaces the running code block with the first part of the final result
+/// and appends the remaining code blocks (the last one carrying
+/// `EXEC_MARKER`) and the metadata paragraph after it.
+pub fn finish_streaming_result(
+    api: &dyn NotionApi,
+    block_id: &str,
+    code_block_id: &str,
+    cmd: &str,
+    output: &str,
+    email: &str,
+    attachment: Option<&UploadedFile>,
+) -> Result<()> {
+    let payload = build_result_payload(cmd, output, email, attachment);
+    let mut children = payload
+        .get("children")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let code = children.remove(0);
+    api.update_block(code_block_id, json!({ "code": code["code"] }))?;
+    append_batched(api, block_id, children).map(|_| ())
+}
+
+/// Result blocks for a finished command: the output split over as many code
+/// blocks as needed, followed by a metadata paragraph. The footer with
+/// `EXEC_MARKER` always lands, unsplit, in the last code block. An
+/// `attachment` is placed between the code blocks and the paragraph.
+pub fn build_result_payload(cmd: &str, output: &str, email: &str, attachment: Option<&UploadedFile>) -> Value {
     use chrono::{Local, SecondsFormat};
     let now = Local::now().to_rfc3339_opts(SecondsFormat::Secs, true);
     let machine = os_name();
     let mut body_text = String::new();
     body_text.push_str("$ ");
     body_text.push_str(cmd);
-    body_text.push_str("\n");
+    body_text.push('\n');
     body_text.push_str(output);
-    body_text.push_str("\n---\n");
-    body_text.push_str(&format!(
-        "executed_by={} | node={} | {}\n# {}",
+    let footer = format!(
+        "\n---\nexecuted_by={} | node={} | {}\n# {}",
         email, machine, now, EXEC_MARKER
-    ));
+    );
 
-    json!({
-        "children": [
-            {
-                "object": "block",
-                "type": "code",
-                "code": {
-                    "rich_text": [{
-                        "type": "text",
-                        "text": {"content": body_text}
-                    }],
-                    "language": "plain text"
-                }
-            },
-            {
-                "object": "block",
-                "type": "paragraph",
-                "paragraph": {
-                    "rich_text": [{
-                        "type": "text",
-                        "text": {"content": format!("email: {} | machine: {}", email, machine)}
-                    }]
-                }
-            }
-        ]
-    })
+    let mut segments = split_rich_text(&body_text);
+    let last = segments.last_mut().expect("split_rich_text returns at least one segment");
+    if last.encode_utf16().count() + footer.encode_utf16().count() <= RICH_TEXT_MAX_LEN {
+        last.push_str(&footer);
+    } else {
+        segments.push(footer);
+    }
+    let mut children: Vec<Value> = segments
+        .chunks(SEGMENTS_PER_CODE_BLOCK)
+        .map(code_block_from_segments)
+        .collect();
+    children.extend(attachment.map(file_block));
+    children.push(json!({
+        "object": "block",
+        "type": "paragraph",
+        "paragraph": {
+            "rich_text": [{
+                "type": "text",
+                "text": {"content": format!("email: {} | machine: {}", email, machine)}
+            }]
+        }
+    }));
+    json!({ "children": children })
 }
 
 pub fn append_result_children(
-    client: &Client,
+    api: &dyn NotionApi,
     block_id: &str,
     cmd: &str,
     output: &str,
     email: &str,
+    attachment: Option<&UploadedFile>,
 ) -> Result<()> {
-    let payload = build_result_payload(cmd, output, email);
-    let url = format!("https://api.notion.com/v1/blocks/{}/children", block_id);
-    let resp = client
-        .patch(&url)
-        .header(CONTENT_TYPE, "application/json")
-        .body(payload.to_string())
-        .send()?;
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let text = resp.text().unwrap_or_default();
-        return Err(anyhow!("Failed to append Notion children: {} - {}", status, text));
-    }
-    Ok(())
+    let payload = build_result_payload(cmd, output, email, attachment);
+    let children = payload
+        .get("children")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    append_batched(api, block_id, children).map(|_| ())
 }
 
-pub fn find_status_block(client: &Client, page_id: &str) -> Result<Option<String>> {
-    let children = fetch_all_children(client, page_id)?;
+pub fn find_status_block(api: &dyn NotionApi, page_id: &str) -> Result<Option<String>> {
+    let children = fetch_all_children(api, page_id)?;
     for child in children.iter() {
         if child.get("type").and_then(|v| v.as_str()) == Some("paragraph") {
             if let Some(arr) = child
@@ -171,52 +506,36 @@ pub fn find_status_block(client: &Client, page_id: &str) -> Result<Option<String
     Ok(None)
 }
 
-pub fn ensure_status_block(client: &Client, page_id: &str) -> Result<String> {
-    if let Some(id) = find_status_block(client, page_id)? { return Ok(id); }
+pub fn ensure_status_block(api: &dyn NotionApi, page_id: &str) -> Result<String> {
+    if let Some(id) = find_status_block(api, page_id)? { return Ok(id); }
     let text = "[*] NotionSSH is Loading - waiting for commands. Press Ctrl+C to stop. # notionSSH-status";
-    let payload = json!({
-        "children": [
-            {
+    let created = api
+        .append_children(
+            page_id,
+            vec![json!({
                 "object": "block",
                 "type": "paragraph",
                 "paragraph": {
                     "rich_text": [{"type": "text", "text": {"content": text}}]
                 }
-            }
-        ]
-    });
-    let url = format!("https://api.notion.com/v1/blocks/{}/children", page_id);
-    let resp = client
-        .patch(&url)
-        .header(CONTENT_TYPE, "application/json")
-        .body(payload.to_string())
-        .send()?;
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let txt = resp.text().unwrap_or_default();
-        return Err(anyhow!("Failed to create status block: {} - {}", status, txt));
+            })],
+        )
+        .map_err(|e| anyhow!("Failed to create status block: {e:#}"))?;
+    if let Some(id) = created.first().and_then(|b| b.get("id")).and_then(|v| v.as_str()) {
+        return Ok(id.to_string());
     }
-    let new_id = find_status_block(client, page_id)?.ok_or_else(|| anyhow!("Status block not found after creation"))?;
+    let new_id = find_status_block(api, page_id)?.ok_or_else(|| anyhow!("Status block not found after creation"))?;
     Ok(new_id)
 }
 
-pub fn update_status_block(client: &Client, status_block_id: &str, text: &str) -> Result<()> {
+pub fn update_status_block(api: &dyn NotionApi, status_block_id: &str, text: &str) -> Result<()> {
     let payload = json!({
         "paragraph": {
             "rich_text": [{"type": "text", "text": {"content": text}}]
         }
     });
-    let url = format!("https://api.notion.com/v1/blocks/{}", status_block_id);
-    let resp = client
-        .patch(&url)
-        .header(CONTENT_TYPE, "application/json")
-        .body(payload.to_string())
-        .send()?;
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let txt = resp.text().unwrap_or_default();
-        return Err(anyhow!("Failed to update status block: {} - {}", status, txt));
-    }
+    api.update_block(status_block_id, payload)
+        .map_err(|e| anyhow!("Failed to update status block: {e:#}"))?;
     Ok(())
 }
 
@@ -226,7 +545,7 @@ mod tests {
 
     #[test]
     fn payload_contains_marker_and_metadata() {
-        let v = build_result_payload("echo hi", "hello", "u@example.com");
+        let v = build_result_payload("echo hi", "hello", "u@example.com", None);
         let children = v.get("children").unwrap().as_array().unwrap();
         assert_eq!(children.len(), 2);
         let code = &children[0];
@@ -266,5 +585,55 @@ mod tests {
         assert!(mcontent.contains("email: u@example.com"));
         assert!(mcontent.contains("machine:"));
     }
+
+    #[test]
+    fn progress_text_shows_tail_without_marker() {
+        let partial = "x".repeat(PROGRESS_TAIL_CHARS) + "tail-end";
+        let text = build_progress_text("make", &partial, "u@example.com", 12);
+        assert!(text.starts_with("$ make\n... [8 earlier characters not shown]"));
+        assert!(text.contains("tail-end\n---\nrunning\u{2026} 12s"));
+        assert!(!text.contains(EXEC_MARKER));
+    }
+
+    #[test]
+    fn large_output_is_split_into_segments_and_blocks() {
+        let output = "\u{d55c}\u{ae00}".repeat(25_000);
+        let v = build_result_payload("cat big", &output, "u@example.com", None);
+        let children = v["children"].as_array().unwrap();
+        let (para, codes) = children.split_last().unwrap();
+        assert_eq!(para["type"], "paragraph");
+        assert_eq!(codes.len(), 3);
+
+        let mut joined = String::new();
+        for code in codes {
+            let rich = code["code"]["rich_text"].as_array().unwrap();
+            assert!(rich.len() <= SEGMENTS_PER_CODE_BLOCK);
+            for r in rich {
+                let t = r["text"]["content"].as_str().unwrap();
+                assert!(t.encode_utf16().count() <= RICH_TEXT_MAX_LEN);
+                joined.push_str(t);
+            }
+        }
+        assert!(joined.starts_with(&format!("$ cat big\n{}", output)));
+        assert!(!children_contains_marker(&codes[..2]));
+        assert!(children_contains_marker(&codes[2..]));
+    }
+
+    #[test]
+    fn attachment_goes_before_metadata() {
+        let file = UploadedFile { id: "up-1".into(), name: "out.log".into() };
+        let v = build_result_payload("dmesg", "preview", "u@example.com", Some(&file));
+        let children = v["children"].as_array().unwrap();
+        let types: Vec<&str> = children.iter().map(|c| c["type"].as_str().unwrap()).collect();
+        assert_eq!(types, vec!["code", "file", "paragraph"]);
+        assert_eq!(children[1]["file"]["file_upload"]["id"], "up-1");
+    }
+
+    #[test]
+    fn batches_respect_child_limit() {
+        let children: Vec<Value> = (0..250).map(|i| code_block(&i.to_string())).collect();
+        let sizes: Vec<usize> = batch_children(children).iter().map(|b| b.len()).collect();
+        assert_eq!(sizes, vec![100, 100, 50]);
+    }
 }
 
@@ -4,8 +4,9 @@ use serde_json::Value;
 
 pub const EXEC_MARKER: &str = "notionSSH-executed";
 
-pub fn parse_command_from_block(block: &Value) -> Option<CommandTask> {
-    let Some(obj_type) = block.get("type").and_then(|v| v.as_str()) else { return None };
+/// Plain text of a paragraph or to_do block.
+pub fn block_text(block: &Value) -> Option<String> {
+    let obj_type = block.get("type").and_then(|v| v.as_str())?;
     if obj_type != "paragraph" && obj_type != "to_do" { return None; }
 
     let rich = block.get(obj_type)?.get("rich_text")?.as_array()?;
@@ -21,9 +22,29 @@ pub fn parse_command_from_block(block: &Value) -> Option<CommandTask> {
             text.push_str(t);
         }
     }
-    let text = text.trim();
+    Some(text)
+}
+
+pub fn parse_command_from_block(block: &Value) -> Option<CommandTask> {
     let re = Regex::new(r"^!\((?P<cmd>.+)\)$").unwrap();
-    let caps = re.captures(text)?;
+    task_from_block(block, &re)
+}
+
+/// `!!(session <program>)`: an interactive session whose child blocks are
+/// typed into the program. The task's command is the program.
+pub fn parse_session_from_block(block: &Value) -> Option<CommandTask> {
+    let re = Regex::new(r"^!!\(session\s+(?P<cmd>.+)\)$").unwrap();
+    task_from_block(block, &re)
+}
+
+/// `!!(close)` written under a session block ends it.
+pub fn is_session_close(text: &str) -> bool {
+    text.trim() == "!!(close)"
+}
+
+fn task_from_block(block: &Value, re: &Regex) -> Option<CommandTask> {
+    let text = block_text(block)?;
+    let caps = re.captures(text.trim())?;
     let cmd = caps.name("cmd")?.as_str().trim().to_string();
     let block_id = block.get("id")?.as_str()?.to_string();
     let created_time = block
@@ -36,8 +57,16 @@ pub fn parse_command_from_block(block: &Value) -> Option<CommandTask> {
         .and_then(|v| v.get("id"))
         .and_then(|v| v.as_str())
         .map(|s| s.to_string());
+    let last_edited_by_id = block
+        .get("last_edited_by")
+        .and_then(|v| v.get("id"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let checked = block
+        .get("to_do")
+        .map(|t| t.get("checked").and_then(|v| v.as_bool()).unwrap_or(false));
 
-    Some(CommandTask { block_id, command: cmd, created_time, created_by_id })
+    Some(CommandTask { block_id, command: cmd, created_time, created_by_id, last_edited_by_id, checked })
 }
 
 pub fn children_contains_marker(children: &[Value]) -> bool {
@@ -81,12 +110,27 @@ mod tests {
             "type": "paragraph",
             "created_time": "2025-01-01T00:00:00.000Z",
             "created_by": {"id": "user_123"},
+            "last_edited_by": {"id": "user_456"},
             "paragraph": {"rich_text": [{"plain_text": "!(docker ps)"}]}
         });
         let task = parse_command_from_block(&block).expect("should parse");
         assert_eq!(task.block_id, "abc");
         assert_eq!(task.command, "docker ps");
         assert_eq!(task.created_by_id.as_deref(), Some("user_123"));
+        assert_eq!(task.last_edited_by_id.as_deref(), Some("user_456"));
+        assert_eq!(task.checked, None);
+    }
+
+    #[test]
+    fn parse_to_do_keeps_checked_state() {
+        let block = json!({
+            "id": "t1",
+            "type": "to_do",
+            "to_do": {"rich_text": [{"plain_text": "!(uptime)"}], "checked": true}
+        });
+        let task = parse_command_from_block(&block).unwrap();
+        assert_eq!(task.command, "uptime");
+        assert_eq!(task.checked, Some(true));
     }
 
     #[test]
@@ -99,6 +143,18 @@ mod tests {
         assert!(parse_command_from_block(&block).is_none());
     }
 
+    #[test]
+    fn parse_session_block() {
+        let block = json!({
+            "id": "s1",
+            "type": "paragraph",
+            "paragraph": {"rich_text": [{"plain_text": "!!(session psql -h db)"}]}
+        });
+        assert_eq!(parse_session_from_block(&block).unwrap().command, "psql -h db");
+        assert!(parse_command_from_block(&block).is_none());
+        assert!(is_session_close(" !!(close) "));
+    }
+
     #[test]
     fn children_marker_detection() {
         let ok = json!({
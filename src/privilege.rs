@@ -0,0 +1,142 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+#[cfg(unix)]
+use anyhow::{anyhow, bail};
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+#[cfg(unix)]
+use std::process::Command;
+
+#[cfg(unix)]
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// A local account a command is run as, resolved from the system user and
+/// group databases before spawning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixIdentity {
+    pub user: String,
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, including `gid`.
+    pub groups: Vec<u32>,
+    pub home: PathBuf,
+}
+
+/// Looks up `user` (and `group`, defaulting to the user's primary group).
+#[cfg(unix)]
+pub fn resolve(user: &str, group: Option<&str>) -> Result<UnixIdentity> {
+    let c_user = CString::new(user)?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found: *mut libc::passwd = std::ptr::null_mut();
+    let rc = unsafe { libc::getpwnam_r(c_user.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if rc != 0 || found.is_null() {
+        bail!("unknown Unix user '{}'", user);
+    }
+    let home = unsafe { CStr::from_ptr(pwd.pw_dir) }.to_string_lossy().into_owned();
+    let gid = match group {
+        Some(name) => group_id(name)?,
+        None => pwd.pw_gid,
+    };
+    let mut groups = supplementary_groups(&c_user, gid);
+    if !groups.contains(&gid) {
+        groups.insert(0, gid);
+    }
+    Ok(UnixIdentity { user: user.to_string(), uid: pwd.pw_uid, gid, groups, home: PathBuf::from(home) })
+}
+
+#[cfg(not(unix))]
+pub fn resolve(_user: &str, _group: Option<&str>) -> Result<UnixIdentity> {
+    Err(anyhow::anyhow!("running commands as another user is only supported on Unix"))
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> Result<u32> {
+    let c_name = CString::new(name)?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found: *mut libc::group = std::ptr::null_mut();
+    let rc = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if rc != 0 || found.is_null() {
+        return Err(anyhow!("unknown Unix group '{}'", name));
+    }
+    Ok(grp.gr_gid)
+}
+
+#[cfg(target_os = "linux")]
+fn supplementary_groups(user: &CStr, gid: u32) -> Vec<u32> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut n = groups.len() as libc::c_int;
+        let rc = unsafe { libc::getgrouplist(user.as_ptr(), gid, groups.as_mut_ptr(), &mut n) };
+        if rc >= 0 {
+            groups.truncate(n as usize);
+            return groups;
+        }
+        groups.resize((n as usize).max(groups.len() * 2), 0);
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn supplementary_groups(_user: &CStr, gid: u32) -> Vec<u32> {
+    vec![gid]
+}
+
+/// Makes `command` run as `id`: a fresh login-like environment, the user's
+/// home as working directory, and groups/gid/uid switched in the child right
+/// before exec. The switch is skipped when the agent already runs as `id`
+/// without root, since dropping to yourself needs no privileges.
+#[cfg(unix)]
+pub fn apply(command: &mut Command, id: &UnixIdentity) {
+    use std::os::unix::process::CommandExt;
+
+    let path = std::env::var("PATH").unwrap_or_else(|_| DEFAULT_PATH.to_string());
+    command
+        .env_clear()
+        .env("HOME", &id.home)
+        .env("USER", &id.user)
+        .env("LOGNAME", &id.user)
+        .env("PATH", path);
+    for key in ["LANG", "LC_ALL", "TERM", "TZ"] {
+        if let Ok(v) = std::env::var(key) {
+            command.env(key, v);
+        }
+    }
+    if id.home.is_dir() {
+        command.current_dir(&id.home);
+    }
+
+    let (uid, gid, groups) = (id.uid, id.gid, id.groups.clone());
+    // Only async-signal-safe calls between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            let euid = libc::geteuid();
+            if euid == uid && euid != 0 {
+                return Ok(());
+            }
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(uid) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_root_and_rejects_unknown() {
+        let root = resolve("root", None).unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(root.groups.contains(&0));
+        assert!(resolve("no_such_user_zzz", None).is_err());
+        assert!(resolve("root", Some("no_such_group_zzz")).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use crate::executor::ExecOptions;
+use anyhow::Result;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+#[cfg(unix)]
+use crate::executor::{apply_options, shell_attempts, shell_script};
+#[cfg(unix)]
+use anyhow::anyhow;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::process::{Child, Command, Stdio};
+#[cfg(unix)]
+use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use std::time::Instant;
+
+/// Terminal size reported to interactive programs.
+#[cfg(unix)]
+const COLUMNS: u16 = 120;
+#[cfg(unix)]
+const ROWS: u16 = 40;
+
+/// An interactive program running under a pseudo-terminal, started through
+/// the platform shell with the same account, sandbox, directory and
+/// environment as a regular command.
+#[cfg(unix)]
+pub struct PtyProcess {
+    master: File,
+    child: Child,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+#[cfg(unix)]
+impl PtyProcess {
+    pub fn spawn(program: &str, opts: &ExecOptions) -> Result<Self> {
+        let script = shell_script(format!("exec {}", program), opts);
+        let mut last_err: Option<anyhow::Error> = None;
+        for (prog, args) in shell_attempts(&script) {
+            let (master, slave) = open_pty()?;
+            let mut command = Command::new(&prog);
+            command
+                .args(&args)
+                .stdin(Stdio::from(slave.try_clone()?))
+                .stdout(Stdio::from(slave.try_clone()?))
+                .stderr(Stdio::from(slave));
+            // A session of its own with the terminal as controlling tty; this
+            // also makes the shell a process group leader for `kill`.
+            unsafe {
+                use std::os::unix::process::CommandExt;
+                command.pre_exec(|| {
+                    if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            apply_options(&mut command, opts)?;
+            // Ask programs for plain output; it is rendered as text anyway.
+            command.env("TERM", "dumb");
+            let spawned = command.spawn();
+            // Close our copies of the slave so reads fail once the program exits.
+            drop(command);
+            match spawned {
+                Ok(child) => {
+                    let output = Arc::new(Mutex::new(Vec::new()));
+                    let mut reader = master.try_clone()?;
+                    let buf = output.clone();
+                    std::thread::spawn(move || {
+                        let mut chunk = [0u8; 8192];
+                        // Linux reports EIO once every slave fd is closed.
+                        while let Ok(n) = reader.read(&mut chunk) {
+                            if n == 0 {
+                                break;
+                            }
+                            buf.lock().unwrap().extend_from_slice(&chunk[..n]);
+                        }
+                    });
+                    return Ok(Self { master, child, output });
+                }
+                Err(e) => last_err = Some(e.into()),
+            }
+        }
+        Err(anyhow!("Failed to start session with available shells (last error: {:?})", last_err))
+    }
+
+    /// Types `text` followed by Enter, one line at a time.
+    pub fn send_line(&mut self, text: &str) -> Result<()> {
+        for line in text.lines() {
+            self.master.write_all(line.as_bytes())?;
+            self.master.write_all(b"\n")?;
+        }
+        self.master.flush()?;
+        Ok(())
+    }
+
+    /// Waits until the program has printed nothing for `quiet` (or `max`
+    /// has passed, or it exited) and returns what it printed since the
+    /// last call.
+    pub fn read_until_quiet(&mut self, quiet: Duration, max: Duration) -> Vec<u8> {
+        let started = Instant::now();
+        let mut last_len = self.output.lock().unwrap().len();
+        let mut last_change = Instant::now();
+        while started.elapsed() < max && last_change.elapsed() < quiet {
+            std::thread::sleep(Duration::from_millis(50));
+            let len = self.output.lock().unwrap().len();
+            if len != last_len {
+                last_len = len;
+                last_change = Instant::now();
+            } else if self.has_exited() {
+                break;
+            }
+        }
+        std::mem::take(&mut *self.output.lock().unwrap())
+    }
+
+    fn has_exited(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(Some(_)))
+    }
+
+    pub fn try_wait(&mut self) -> Option<ExitStatus> {
+        self.child.try_wait().ok().flatten()
+    }
+
+    /// Kills the program and everything it started.
+    pub fn kill(&mut self) -> Option<ExitStatus> {
+        unsafe {
+            libc::kill(-(self.child.id() as libc::pid_t), libc::SIGKILL);
+        }
+        let _ = self.child.kill();
+        self.child.wait().ok()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for PtyProcess {
+    fn drop(&mut self) {
+        if self.try_wait().is_none() {
+            self.kill();
+        }
+    }
+}
+
+/// Interactive sessions need a pseudo-terminal, which only exists on Unix.
+#[cfg(not(unix))]
+pub struct PtyProcess;
+
+#[cfg(not(unix))]
+impl PtyProcess {
+    pub fn spawn(_program: &str, _opts: &ExecOptions) -> Result<Self> {
+        Err(anyhow::anyhow!("interactive sessions are only supported on Unix"))
+    }
+
+    pub fn send_line(&mut self, _text: &str) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn read_until_quiet(&mut self, _quiet: Duration, _max: Duration) -> Vec<u8> {
+        Vec::new()
+    }
+
+    pub fn try_wait(&mut self) -> Option<ExitStatus> {
+        None
+    }
+
+    pub fn kill(&mut self) -> Option<ExitStatus> {
+        None
+    }
+}
+
+/// Opens a master/slave pair, both close-on-exec; the child gets the slave
+/// through its stdio.
+#[cfg(unix)]
+fn open_pty() -> Result<(File, File)> {
+    use std::os::fd::FromRawFd;
+
+    unsafe {
+        let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if master < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let master = File::from_raw_fd(master);
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&master);
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let size = libc::winsize { ws_row: ROWS, ws_col: COLUMNS, ws_xpixel: 0, ws_ypixel: 0 };
+        libc::ioctl(fd, libc::TIOCSWINSZ as _, &size);
+        let name = slave_name(fd)?;
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&name)?;
+        Ok((master, slave))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn slave_name(fd: libc::c_int) -> Result<String> {
+    let mut buf = [0 as libc::c_char; 128];
+    if unsafe { libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn slave_name(fd: libc::c_int) -> Result<String> {
+    // ptsname returns a static buffer.
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _guard = LOCK.lock().unwrap();
+    let name = unsafe { libc::ptsname(fd) };
+    if name.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { std::ffi::CStr::from_ptr(name) }.to_string_lossy().into_owned())
+}
+
+/// Turns raw terminal output into plain text: escape sequences are dropped,
+/// carriage returns and backspaces rewrite the current line.
+pub fn render_terminal(raw: &[u8]) -> String {
+    let text = String::from_utf8_lossy(raw);
+    let mut out = String::new();
+    let mut line: Vec<char> = Vec::new();
+    let mut col: usize = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: parameters up to a final byte in @..~
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: up to BEL or ESC \
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\n' => {
+                out.extend(line.drain(..));
+                out.push('\n');
+                col = 0;
+            }
+            '\r' => col = 0,
+            '\x08' => col = col.saturating_sub(1),
+            '\t' => {
+                let next = (col / 8 + 1) * 8;
+                while col < next {
+                    put(&mut line, &mut col, ' ');
+                }
+            }
+            c if c.is_control() => {}
+            c => put(&mut line, &mut col, c),
+        }
+    }
+    out.extend(line);
+    out
+}
+
+fn put(line: &mut Vec<char>, col: &mut usize, c: char) {
+    if *col < line.len() {
+        line[*col] = c;
+    } else {
+        line.push(c);
+    }
+    *col += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terminal_output_is_rendered_as_text() {
+        let raw = b"\x1b[1mpostgres\x1b[0m=# select 1;\r\n?column?\r\n----\r\n  1\r\n\x1b]0;title\x07abcd\x08\x08XY\rZ";
+        assert_eq!(render_terminal(raw), "postgres=# select 1;\n?column?\n----\n  1\nZbXY");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_session_round_trip() {
+        let mut pty = PtyProcess::spawn("cat", &ExecOptions::default()).unwrap();
+        pty.send_line("hello pty").unwrap();
+        // Login shells may take a while to source profiles (and print to the
+        // terminal) before the program starts.
+        let mut out = String::new();
+        let until = std::time::Instant::now() + Duration::from_secs(15);
+        while out.matches("hello pty\n").count() < 2 && std::time::Instant::now() < until {
+            out.push_str(&render_terminal(&pty.read_until_quiet(Duration::from_millis(300), Duration::from_secs(1))));
+        }
+        // Echoed by the terminal, then printed by cat.
+        assert_eq!(out.matches("hello pty\n").count(), 2, "{out:?}");
+        assert!(pty.kill().is_some());
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Notices when a file is written by polling its modification time and size.
+#[derive(Debug, Clone)]
+pub struct FileWatch {
+    path: PathBuf,
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl FileWatch {
+    /// Starts watching `path` from its current state.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let stamp = stamp(&path);
+        Self { path, stamp }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// True once per change (including the file appearing or disappearing).
+    /// The new state is recorded before the caller reads the file, so a
+    /// write that lands while it is being read is seen on the next call.
+    pub fn changed(&mut self) -> bool {
+        let now = stamp(&self.path);
+        if now == self.stamp {
+            return false;
+        }
+        self.stamp = now;
+        true
+    }
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_each_write_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.json");
+        let mut watch = FileWatch::new(&path);
+        assert!(!watch.changed());
+        fs::write(&path, "{}").unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+        fs::write(&path, "{ }").unwrap();
+        assert!(watch.changed());
+        fs::remove_file(&path).unwrap();
+        assert!(watch.changed());
+    }
+}
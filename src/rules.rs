@@ -0,0 +1,318 @@
+use crate::shell::{basename, Invocation};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+/// Prefix marking a `match` pattern as a regular expression.
+const REGEX_PREFIX: &str = "re:";
+
+/// One entry of a role's `allow` or `deny` list: either a plain pattern
+/// string or an object with constraints.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RuleItem {
+    /// `"*"`, `"ls"`, `"systemctl restart app-*"` or `"re:^git (status|log)$"`.
+    Pattern(String),
+    Spec(RuleSpec),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleSpec {
+    /// Same syntax as a plain pattern string.
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Globs for the arguments following those in `match`, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Directory prefixes for path arguments. In `allow`, every path
+    /// argument must be inside one; in `deny`, the rule applies when any is.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<String>,
+    /// Flags this rule is about. In `allow`, they are not allowed; in
+    /// `deny`, the rule applies when any of them is given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forbid_flags: Vec<String>,
+    /// Why the rule exists, recorded when it denies a command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Which list a rule item comes from; `paths` and `forbid_flags` read
+/// differently in each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    Allow,
+    Deny,
+}
+
+/// How a rule item relates to an invocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleMatch {
+    No,
+    Yes,
+    /// The pattern matched but the constraints of an `allow` item did not
+    /// hold, e.g. a forbidden flag was given.
+    Constrained,
+}
+
+impl From<&str> for RuleItem {
+    fn from(s: &str) -> Self {
+        RuleItem::Pattern(s.to_string())
+    }
+}
+
+impl From<String> for RuleItem {
+    fn from(s: String) -> Self {
+        RuleItem::Pattern(s)
+    }
+}
+
+impl RuleItem {
+    pub fn pattern(&self) -> &str {
+        match self {
+            RuleItem::Pattern(p) => p,
+            RuleItem::Spec(spec) => &spec.pattern,
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            RuleItem::Pattern(_) => None,
+            RuleItem::Spec(spec) => spec.reason.as_deref(),
+        }
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        matches!(self, RuleItem::Pattern(p) if p == "*")
+    }
+
+    /// Checks that patterns and globs compile.
+    pub fn validate(&self) -> Result<()> {
+        let pattern = self.pattern();
+        if let Some(re) = pattern.strip_prefix(REGEX_PREFIX) {
+            anchored(re).map_err(|e| anyhow!("invalid regex in rule '{}': {}", pattern, e))?;
+        } else {
+            for word in pattern.split_whitespace() {
+                glob(word).map_err(|e| anyhow!("invalid glob in rule '{}': {}", pattern, e))?;
+            }
+        }
+        if let RuleItem::Spec(spec) = self {
+            if pattern.trim().is_empty() {
+                return Err(anyhow!("rule object without 'match'"));
+            }
+            for arg in &spec.args {
+                glob(arg).map_err(|e| anyhow!("invalid glob '{}' in rule '{}': {}", arg, pattern, e))?;
+            }
+            if let Some(p) = spec.paths.iter().find(|p| !p.starts_with('/')) {
+                return Err(anyhow!("path '{}' in rule '{}' must be absolute", p, pattern));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, inv: &Invocation, kind: RuleKind) -> RuleMatch {
+        let (pattern, extra_args): (&str, &[String]) = match self {
+            RuleItem::Pattern(p) => (p, &[]),
+            RuleItem::Spec(spec) => (&spec.pattern, &spec.args),
+        };
+        if !pattern_matches(pattern, extra_args, inv) {
+            return RuleMatch::No;
+        }
+        let RuleItem::Spec(spec) = self else { return RuleMatch::Yes };
+        let paths = path_args(inv, matched_args(&spec.pattern, &spec.args));
+        let inside = |p: &PathBuf| spec.paths.iter().any(|prefix| p.starts_with(prefix));
+        let flagged = inv.args.iter().any(|a| spec.forbid_flags.iter().any(|f| has_flag(a, f)));
+        match kind {
+            RuleKind::Allow => {
+                let paths_ok = spec.paths.is_empty() || paths.iter().all(|p| p.as_ref().is_some_and(inside));
+                if paths_ok && !flagged {
+                    RuleMatch::Yes
+                } else {
+                    RuleMatch::Constrained
+                }
+            }
+            RuleKind::Deny => {
+                // A relative path might point anywhere, so it counts as inside.
+                let paths_hit =
+                    spec.paths.is_empty() || paths.iter().any(|p| p.as_ref().is_none_or(inside));
+                let flags_hit = spec.forbid_flags.is_empty() || flagged;
+                if paths_hit && flags_hit {
+                    RuleMatch::Yes
+                } else {
+                    RuleMatch::No
+                }
+            }
+        }
+    }
+}
+
+/// `*` matches everything; `re:` patterns are matched against the whole
+/// invocation (`name arg1 arg2 ...`); otherwise the first word is a glob for
+/// the program name and the rest, followed by `extra_args`, are globs for the
+/// leading arguments.
+fn pattern_matches(pattern: &str, extra_args: &[String], inv: &Invocation) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    if let Some(re) = pattern.strip_prefix(REGEX_PREFIX) {
+        return anchored(re).is_ok_and(|re| re.is_match(&inv.to_string()));
+    }
+    let mut words = pattern.split_whitespace();
+    let Some(program) = words.next() else { return false };
+    if !glob_matches(&basename(program).to_ascii_lowercase(), &inv.name().to_ascii_lowercase()) {
+        return false;
+    }
+    let mut args = inv.args.iter();
+    words
+        .chain(extra_args.iter().map(|s| s.as_str()))
+        .all(|g| args.next().is_some_and(|a| glob_matches(g, a)))
+}
+
+fn anchored(re: &str) -> std::result::Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", re))
+}
+
+/// `*`, `?` and `[...]` globs; `*` also matches `/`.
+fn glob(pattern: &str) -> std::result::Result<Regex, regex::Error> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                if chars.next_if(|c| *c == '!' || *c == '^').is_some() {
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re)
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    if !pattern.contains(['*', '?', '[']) {
+        return pattern == text;
+    }
+    glob(pattern).is_ok_and(|re| re.is_match(text))
+}
+
+/// How many leading arguments the words of `pattern` and `args` consume,
+/// e.g. `run` in `docker run`; those are not paths.
+fn matched_args(pattern: &str, args: &[String]) -> usize {
+    if pattern.starts_with(REGEX_PREFIX) {
+        return 0;
+    }
+    pattern.split_whitespace().count().saturating_sub(1) + args.len()
+}
+
+/// Every argument after the first `skip` that is not a flag, plus
+/// `--opt=value` values that look like paths, lexically normalized; `None`
+/// for relative ones. Those include bare words such as `shadow`, whose
+/// target depends on a working directory that an earlier `cd` in the same
+/// command line may have changed.
+fn path_args(inv: &Invocation, skip: usize) -> Vec<Option<PathBuf>> {
+    let mut options = true;
+    let mut paths = Vec::new();
+    for a in inv.args.iter().skip(skip) {
+        let value = match a.split_once('=') {
+            _ if !options || !a.starts_with('-') || a == "-" => a.as_str(),
+            _ if a == "--" => {
+                options = false;
+                continue;
+            }
+            Some((_, value)) if value.contains('/') || value.starts_with('.') || value.starts_with('~') => value,
+            _ => continue,
+        };
+        paths.push(value.starts_with('/').then(|| normalize(Path::new(value))));
+    }
+    paths
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::from("/");
+    for c in path.components() {
+        match c {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::Normal(part) => out.push(part),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// `--flag`, `--flag=value`, or a short `-x` anywhere in a cluster like `-xvf`.
+fn has_flag(arg: &str, flag: &str) -> bool {
+    if arg == flag || arg.strip_prefix(flag).is_some_and(|rest| rest.starts_with('=')) {
+        return true;
+    }
+    match flag.strip_prefix('-') {
+        Some(short) if short.len() == 1 && !short.starts_with('-') => {
+            arg.starts_with('-') && !arg.starts_with("--") && arg[1..].contains(short)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::analyze;
+
+    fn check(item: &RuleItem, cmd: &str, kind: RuleKind) -> RuleMatch {
+        item.matches(&analyze(cmd).unwrap()[0], kind)
+    }
+
+    #[test]
+    fn patterns_globs_and_regexes() {
+        let glob: RuleItem = "systemctl restart app-*".into();
+        assert_eq!(check(&glob, "systemctl restart app-web", RuleKind::Allow), RuleMatch::Yes);
+        assert_eq!(check(&glob, "/bin/systemctl restart db", RuleKind::Allow), RuleMatch::No);
+        let re: RuleItem = r"re:git (status|log)( --oneline)?".into();
+        assert_eq!(check(&re, "git log --oneline", RuleKind::Allow), RuleMatch::Yes);
+        assert_eq!(check(&re, "git log -p", RuleKind::Allow), RuleMatch::No);
+        assert!(RuleItem::from("re:(").validate().is_err());
+    }
+
+    #[test]
+    fn specs_constrain_flags_and_paths() {
+        let docker: RuleItem = serde_json::from_str(
+            r#"{"match": "docker", "args": ["run"], "forbid_flags": ["--privileged", "-v"], "reason": "no host access"}"#,
+        )
+        .unwrap();
+        assert_eq!(check(&docker, "docker run --rm alpine", RuleKind::Allow), RuleMatch::Yes);
+        assert_eq!(check(&docker, "docker run --privileged alpine", RuleKind::Allow), RuleMatch::Constrained);
+        assert_eq!(check(&docker, "docker run -itv /:/host alpine", RuleKind::Allow), RuleMatch::Constrained);
+        assert_eq!(check(&docker, "docker ps", RuleKind::Allow), RuleMatch::No);
+        assert_eq!(check(&docker, "docker run --privileged x", RuleKind::Deny), RuleMatch::Yes);
+        assert_eq!(check(&docker, "docker run x", RuleKind::Deny), RuleMatch::No);
+        assert_eq!(docker.reason(), Some("no host access"));
+
+        let logs: RuleItem = serde_json::from_str(r#"{"match": "tail", "paths": ["/var/log"]}"#).unwrap();
+        assert_eq!(check(&logs, "tail -n5 /var/log/syslog", RuleKind::Allow), RuleMatch::Yes);
+        assert_eq!(check(&logs, "tail -n 5 /var/log/syslog", RuleKind::Allow), RuleMatch::Constrained);
+        assert_eq!(check(&logs, "tail /var/log/../../etc/shadow", RuleKind::Allow), RuleMatch::Constrained);
+        assert_eq!(check(&logs, "tail ./syslog", RuleKind::Allow), RuleMatch::Constrained);
+        assert_eq!(check(&logs, "tail /var/logs/x", RuleKind::Deny), RuleMatch::No);
+        assert_eq!(check(&logs, "tail x/y", RuleKind::Deny), RuleMatch::Yes);
+        let invs = analyze("cd /etc; tail shadow").unwrap();
+        assert_eq!(logs.matches(&invs[1], RuleKind::Allow), RuleMatch::Constrained);
+        assert_eq!(check(&logs, "tail -- -/var/log", RuleKind::Allow), RuleMatch::Constrained);
+    }
+}
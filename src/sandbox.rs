@@ -0,0 +1,560 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+/// Isolation applied to a command instead of running it directly on the
+/// host. Profiles are defined under `sandbox_profiles` in access.json and
+/// picked per role with `sandbox`. Every profile gets a private `/dev` with
+/// only null, zero, urandom and tty. Linux only.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SandboxProfile {
+    /// New network namespace: only an unconfigured loopback interface.
+    pub isolate_network: bool,
+    /// New PID namespace with its own /proc: host processes are invisible and
+    /// everything the command starts dies with it.
+    pub isolate_pids: bool,
+    /// Remount every filesystem read-only except `writable_paths`.
+    pub read_only_root: bool,
+    pub writable_paths: Vec<String>,
+    /// `RLIMIT_CPU`, in seconds of CPU time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_time_secs: Option<u64>,
+    /// `RLIMIT_AS`, in bytes of address space.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    /// `RLIMIT_FSIZE`, largest file the command may write.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_size_bytes: Option<u64>,
+    /// `RLIMIT_NPROC`; counted per user, so pair it with `run_as`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_processes: Option<u64>,
+    /// Fail syscalls a shell command has no business making (mount, ptrace,
+    /// module loading, namespace changes, ...) with `EPERM`.
+    pub seccomp: bool,
+}
+
+impl Default for SandboxProfile {
+    fn default() -> Self {
+        Self {
+            isolate_network: true,
+            isolate_pids: true,
+            read_only_root: true,
+            writable_paths: vec!["/tmp".to_string()],
+            cpu_time_secs: None,
+            memory_bytes: None,
+            file_size_bytes: None,
+            max_processes: None,
+            seccomp: true,
+        }
+    }
+}
+
+pub fn supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Arranges for `command` to enter the sandbox between fork and exec.
+/// Must be called before any privilege drop is registered, since mounting
+/// needs root, and followed by `lock_down` after it. Any setup failure makes
+/// the spawn fail rather than run the command unconfined.
+#[cfg(target_os = "linux")]
+pub fn apply(command: &mut Command, profile: &SandboxProfile) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let plan = linux::Plan::new(profile)?;
+    unsafe {
+        command.pre_exec(move || plan.enter());
+    }
+    Ok(())
+}
+
+/// Arranges for `command` to give up every capability, so that even as
+/// root it cannot undo the sandbox (e.g. remount `/` read-write), and to
+/// install the seccomp filter. Registered after any privilege drop, which
+/// still needs the capabilities to switch users.
+#[cfg(target_os = "linux")]
+pub fn lock_down(command: &mut Command, profile: &SandboxProfile) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let lock = linux::Lockdown::new(profile)?;
+    unsafe {
+        command.pre_exec(move || lock.enter());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_command: &mut Command, _profile: &SandboxProfile) -> Result<()> {
+    Err(anyhow::anyhow!("sandboxed execution is only supported on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lock_down(_command: &mut Command, _profile: &SandboxProfile) -> Result<()> {
+    Err(anyhow::anyhow!("sandboxed execution is only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::SandboxProfile;
+    use anyhow::Result;
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::path::Path;
+    use std::ptr;
+
+    /// Syscalls the seccomp filter fails with `EPERM`.
+    const BLOCKED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_userfaultfd,
+        // The newer mount API.
+        libc::SYS_fsopen,
+        libc::SYS_fsconfig,
+        libc::SYS_fsmount,
+        libc::SYS_fspick,
+        libc::SYS_move_mount,
+        libc::SYS_open_tree,
+        libc::SYS_mount_setattr,
+    ];
+
+    /// `clone` flags that create namespaces; `clone` calls with any of them
+    /// fail with `EPERM`.
+    const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWNS
+        | libc::CLONE_NEWCGROUP
+        | libc::CLONE_NEWUTS
+        | libc::CLONE_NEWIPC
+        | libc::CLONE_NEWUSER
+        | libc::CLONE_NEWPID
+        | libc::CLONE_NEWNET;
+
+    /// Highest capability number tried when emptying the bounding set.
+    const MAX_CAP: libc::c_int = 63;
+    const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    /// Device nodes bind-mounted from the host into the private `/dev`,
+    /// by name and path.
+    const DEV_NODES: [(&CStr, &CStr); 4] = [
+        (c"null", c"/dev/null"),
+        (c"zero", c"/dev/zero"),
+        (c"urandom", c"/dev/urandom"),
+        (c"tty", c"/dev/tty"),
+    ];
+
+    /// Symlinks created in the private `/dev`, as (target, link).
+    const DEV_LINKS: [(&CStr, &CStr); 4] = [
+        (c"/proc/self/fd", c"/dev/fd"),
+        (c"/proc/self/fd/0", c"/dev/stdin"),
+        (c"/proc/self/fd/1", c"/dev/stdout"),
+        (c"/proc/self/fd/2", c"/dev/stderr"),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Everything the child needs, prepared before fork so that `enter`
+    /// only makes syscalls.
+    pub(super) struct Plan {
+        unshare_flags: libc::c_int,
+        new_pid_ns: bool,
+        read_only_root: bool,
+        writable: Vec<CString>,
+        /// Existing mount points to remount read-only, parents first.
+        read_only: Vec<CString>,
+        /// The `DEV_NODES` the host has.
+        dev_nodes: Vec<(&'static CStr, &'static CStr)>,
+        rlimits: [Option<u64>; 4],
+    }
+
+    /// What the child does after any user switch: drop its capabilities,
+    /// forbid gaining new ones and install the seccomp filter.
+    pub(super) struct Lockdown {
+        seccomp: Option<Vec<libc::sock_filter>>,
+    }
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    impl Plan {
+        pub(super) fn new(profile: &SandboxProfile) -> Result<Self> {
+            let mut unshare_flags = libc::CLONE_NEWNS | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS;
+            if profile.isolate_network {
+                unshare_flags |= libc::CLONE_NEWNET;
+            }
+            if profile.isolate_pids {
+                unshare_flags |= libc::CLONE_NEWPID;
+            }
+            let writable: Vec<String> = profile
+                .writable_paths
+                .iter()
+                .filter(|p| Path::new(p).exists())
+                .cloned()
+                .collect();
+            let read_only = if profile.read_only_root {
+                mount_points()?
+                    .into_iter()
+                    .filter(|m| !writable.iter().any(|w| is_within(m, w)))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            Ok(Self {
+                unshare_flags,
+                new_pid_ns: profile.isolate_pids,
+                read_only_root: profile.read_only_root,
+                writable: writable.into_iter().map(CString::new).collect::<Result<_, _>>()?,
+                read_only: read_only.into_iter().map(CString::new).collect::<Result<_, _>>()?,
+                dev_nodes: DEV_NODES
+                    .into_iter()
+                    .filter(|(_, path)| Path::new(path.to_str().unwrap_or_default()).exists())
+                    .collect(),
+                rlimits: [
+                    profile.cpu_time_secs,
+                    profile.memory_bytes,
+                    profile.file_size_bytes,
+                    profile.max_processes,
+                ],
+            })
+        }
+
+        /// Runs in the forked child. With a new PID namespace it forks once
+        /// more and only the inner child returns; see `fork_into_pid_namespace`.
+        pub(super) fn enter(&self) -> io::Result<()> {
+            unsafe {
+                check(libc::unshare(self.unshare_flags))?;
+                if self.new_pid_ns {
+                    fork_into_pid_namespace()?;
+                }
+                // Keep our mount changes from propagating back to the host.
+                check(libc::mount(
+                    ptr::null(),
+                    c"/".as_ptr(),
+                    ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    ptr::null(),
+                ))?;
+                if self.read_only_root {
+                    // Bind mounts made before the read-only pass stay writable.
+                    for w in &self.writable {
+                        check(libc::mount(
+                            w.as_ptr(),
+                            w.as_ptr(),
+                            ptr::null(),
+                            libc::MS_BIND | libc::MS_REC,
+                            ptr::null(),
+                        ))?;
+                    }
+                    for m in &self.read_only {
+                        remount_read_only(m)?;
+                    }
+                }
+                mount_private_dev(&self.dev_nodes, self.read_only_root)?;
+                if self.new_pid_ns {
+                    check(libc::mount(
+                        c"proc".as_ptr(),
+                        c"/proc".as_ptr(),
+                        c"proc".as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                        ptr::null(),
+                    ))?;
+                    if self.read_only_root {
+                        remount_read_only(c"/proc")?;
+                    }
+                }
+                let resources = [libc::RLIMIT_CPU, libc::RLIMIT_AS, libc::RLIMIT_FSIZE, libc::RLIMIT_NPROC];
+                for (resource, limit) in resources.into_iter().zip(self.rlimits) {
+                    if let Some(limit) = limit {
+                        let rl = libc::rlimit { rlim_cur: limit as libc::rlim_t, rlim_max: limit as libc::rlim_t };
+                        check(libc::setrlimit(resource, &rl))?;
+                    }
+                }
+                // Nothing exec'd from here on can regain a capability, not
+                // even as root. The ones held now stay until `Lockdown`, so
+                // a user switch registered in between still works.
+                for cap in 0..=MAX_CAP {
+                    if libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) == -1 {
+                        match io::Error::last_os_error().raw_os_error() {
+                            // Past the last capability this kernel knows.
+                            Some(libc::EINVAL) => break,
+                            _ => return Err(io::Error::last_os_error()),
+                        }
+                    }
+                }
+                check(libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0))?;
+            }
+            Ok(())
+        }
+    }
+
+    impl Lockdown {
+        pub(super) fn new(profile: &SandboxProfile) -> Result<Self> {
+            Ok(Self { seccomp: if profile.seccomp { Some(seccomp_filter()?) } else { None } })
+        }
+
+        pub(super) fn enter(&self) -> io::Result<()> {
+            unsafe {
+                let mut header = CapHeader { version: CAPABILITY_VERSION_3, pid: 0 };
+                let data = [CapData::default(); 2];
+                if libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+                if let Some(filter) = &self.seccomp {
+                    let prog = libc::sock_fprog {
+                        len: filter.len() as libc::c_ushort,
+                        filter: filter.as_ptr() as *mut libc::sock_filter,
+                    };
+                    check(libc::prctl(
+                        libc::PR_SET_SECCOMP,
+                        libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+                        &prog as *const libc::sock_fprog,
+                    ))?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn check(rc: libc::c_int) -> io::Result<()> {
+        if rc == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `unshare(CLONE_NEWPID)` only applies to children, so fork: the inner
+    /// child becomes PID 1 of the new namespace and goes on to exec the
+    /// command, while this process waits for it and exits the same way.
+    unsafe fn fork_into_pid_namespace() -> io::Result<()> {
+        let pid = libc::fork();
+        if pid == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if pid == 0 {
+            // Die with the relay, e.g. when the agent kills the process group.
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+            return Ok(());
+        }
+        // The spawning parent waits for exec by reading a close-on-exec pipe
+        // until EOF; drop our copy so it is not held up until we exit.
+        if libc::syscall(libc::SYS_close_range, 3u32, u32::MAX, 0u32) != 0 {
+            for fd in 3..1024 {
+                libc::close(fd);
+            }
+        }
+        let mut status = 0;
+        while libc::waitpid(pid, &mut status, 0) == -1 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                libc::_exit(127);
+            }
+        }
+        if libc::WIFSIGNALED(status) {
+            let sig = libc::WTERMSIG(status);
+            libc::signal(sig, libc::SIG_DFL);
+            libc::kill(libc::getpid(), sig);
+            libc::_exit(128 + sig);
+        }
+        libc::_exit(libc::WEXITSTATUS(status));
+    }
+
+    /// Replaces `/dev` with a tmpfs holding only `nodes`, bind-mounted from
+    /// the host, so that a command still running as root cannot reach disks
+    /// or other devices. The host's `/dev` stays reachable as the working
+    /// directory until the nodes are mounted.
+    unsafe fn mount_private_dev(nodes: &[(&CStr, &CStr)], read_only: bool) -> io::Result<()> {
+        let cwd = libc::open(c".".as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC);
+        if cwd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = check(libc::chdir(c"/dev".as_ptr())).and_then(|()| populate_dev(nodes, read_only));
+        let restored = check(libc::fchdir(cwd));
+        libc::close(cwd);
+        result.and(restored)
+    }
+
+    /// Mounts the tmpfs over `/dev` while the working directory is the
+    /// host's `/dev`, which the relative node names resolve against.
+    unsafe fn populate_dev(nodes: &[(&CStr, &CStr)], read_only: bool) -> io::Result<()> {
+        check(libc::mount(
+            c"tmpfs".as_ptr(),
+            c"/dev".as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            c"mode=755,size=64k".as_ptr().cast(),
+        ))?;
+        for (name, path) in nodes {
+            let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC, 0o644 as libc::c_uint);
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::close(fd);
+            check(libc::mount(name.as_ptr(), path.as_ptr(), ptr::null(), libc::MS_BIND, ptr::null()))?;
+        }
+        for (target, link) in DEV_LINKS {
+            check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+        }
+        if read_only {
+            remount_read_only(c"/dev")?;
+        }
+        Ok(())
+    }
+
+    /// Read-only bind remount that keeps the mount's nosuid/nodev/noexec flags.
+    unsafe fn remount_read_only(path: &CStr) -> io::Result<()> {
+        let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+        let mut st: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut st) == 0 {
+            for (st_flag, ms_flag) in [
+                (libc::ST_NOSUID, libc::MS_NOSUID),
+                (libc::ST_NODEV, libc::MS_NODEV),
+                (libc::ST_NOEXEC, libc::MS_NOEXEC),
+                (libc::ST_NOATIME, libc::MS_NOATIME),
+                (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+                (libc::ST_RELATIME, libc::MS_RELATIME),
+            ] {
+                if st.f_flag & st_flag != 0 {
+                    flags |= ms_flag;
+                }
+            }
+        }
+        let rc = libc::mount(ptr::null(), path.as_ptr(), ptr::null(), flags, ptr::null());
+        match check(rc) {
+            // Mount points that vanished since we listed them don't matter.
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            other => other,
+        }
+    }
+
+    fn is_within(path: &str, dir: &str) -> bool {
+        let dir = dir.trim_end_matches('/');
+        path == dir || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// Mount points from /proc/self/mountinfo, parents before children.
+    fn mount_points() -> Result<Vec<String>> {
+        let info = std::fs::read_to_string("/proc/self/mountinfo")?;
+        let mut points: Vec<String> = info
+            .lines()
+            .filter_map(|line| line.split(' ').nth(4))
+            .map(unescape_mount_path)
+            .collect();
+        points.sort_by(|a, b| (a.len(), a).cmp(&(b.len(), b)));
+        points.dedup();
+        Ok(points)
+    }
+
+    /// mountinfo escapes space, tab, newline and backslash as `\ooo`.
+    fn unescape_mount_path(raw: &str) -> String {
+        let bytes = raw.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'\\' && i + 3 < bytes.len()
+                && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+                let code = (bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0');
+                out.push(code);
+                i += 4;
+            } else {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn seccomp_filter() -> Result<Vec<libc::sock_filter>> {
+        fn stmt(code: u32, k: u32) -> libc::sock_filter {
+            libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+        }
+        fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+            libc::sock_filter { code: code as u16, jt, jf, k }
+        }
+        let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+        let ret = libc::BPF_RET | libc::BPF_K;
+        let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        // struct seccomp_data { int nr; __u32 arch; ... }
+        let mut filter = vec![
+            stmt(load, 4),
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(load, 0),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        {
+            // x32 syscalls reuse the numbers with this bit set.
+            filter.push(jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, 0x4000_0000, 0, 1));
+            filter.push(stmt(ret, deny));
+        }
+        // clone: check the flags (the low half of args[0], little-endian).
+        filter.push(jump(jeq, libc::SYS_clone as u32, 0, 4));
+        filter.push(stmt(load, 16));
+        filter.push(jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, NAMESPACE_FLAGS as u32, 0, 1));
+        filter.push(stmt(ret, deny));
+        filter.push(stmt(load, 0));
+        // clone3 passes its flags in memory the filter cannot read; ENOSYS
+        // makes libc fall back to clone.
+        filter.push(jump(jeq, libc::SYS_clone3 as u32, 0, 1));
+        filter.push(stmt(ret, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+        for nr in BLOCKED_SYSCALLS {
+            filter.push(jump(jeq, *nr as u32, 0, 1));
+            filter.push(stmt(ret, deny));
+        }
+        filter.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+        Ok(filter)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn seccomp_filter() -> Result<Vec<libc::sock_filter>> {
+        anyhow::bail!("seccomp filtering is not supported on this architecture")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mount_paths_are_unescaped_and_matched() {
+            assert_eq!(unescape_mount_path(r"/mnt/my\040disk"), "/mnt/my disk");
+            assert!(is_within("/tmp", "/tmp/"));
+            assert!(is_within("/tmp/a", "/tmp"));
+            assert!(!is_within("/tmpfs", "/tmp"));
+        }
+    }
+}
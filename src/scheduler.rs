@@ -0,0 +1,115 @@
+use std::collections::{HashMap, VecDeque};
+
+/// FIFO queues per user with a global and a per-user concurrency limit.
+///
+/// Items of one user start in the order they were queued; items of different
+/// users are started oldest-first as capacity allows.
+#[derive(Debug)]
+pub struct Scheduler<T> {
+    queues: HashMap<String, VecDeque<(u64, T)>>,
+    running: HashMap<String, usize>,
+    total_running: usize,
+    max_total: usize,
+    max_per_user: usize,
+    seq: u64,
+}
+
+impl<T> Scheduler<T> {
+    /// Limits of 0 are treated as 1.
+    pub fn new(max_total: usize, max_per_user: usize) -> Self {
+        Self {
+            queues: HashMap::new(),
+            running: HashMap::new(),
+            total_running: 0,
+            max_total: max_total.max(1),
+            max_per_user: max_per_user.max(1),
+            seq: 0,
+        }
+    }
+
+    /// Changes the limits; items already running are not affected.
+    pub fn set_limits(&mut self, max_total: usize, max_per_user: usize) {
+        self.max_total = max_total.max(1);
+        self.max_per_user = max_per_user.max(1);
+    }
+
+    pub fn enqueue(&mut self, user: &str, item: T) {
+        self.seq += 1;
+        self.queues.entry(user.to_string()).or_default().push_back((self.seq, item));
+    }
+
+    /// Takes the next startable item and counts it as running for its user.
+    pub fn start_next(&mut self) -> Option<(String, T)> {
+        if self.total_running >= self.max_total {
+            return None;
+        }
+        let user = self
+            .queues
+            .iter()
+            .filter(|(u, q)| {
+                !q.is_empty() && self.running.get(*u).copied().unwrap_or(0) < self.max_per_user
+            })
+            .min_by_key(|(_, q)| q.front().map(|(seq, _)| *seq))
+            .map(|(u, _)| u.clone())?;
+        let queue = self.queues.get_mut(&user)?;
+        let (_, item) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&user);
+        }
+        *self.running.entry(user.clone()).or_default() += 1;
+        self.total_running += 1;
+        Some((user, item))
+    }
+
+    /// Marks one running item of `user` as finished.
+    pub fn finish(&mut self, user: &str) {
+        if let Some(n) = self.running.get_mut(user) {
+            *n -= 1;
+            if *n == 0 {
+                self.running.remove(user);
+            }
+            self.total_running -= 1;
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queues.values().map(|q| q.len()).sum()
+    }
+
+    pub fn running(&self) -> usize {
+        self.total_running
+    }
+
+    /// Drops everything that has not started yet.
+    pub fn clear_queued(&mut self) {
+        self.queues.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respects_limits_and_per_user_order() {
+        let mut s = Scheduler::new(2, 1);
+        s.enqueue("a", 1);
+        s.enqueue("a", 2);
+        s.enqueue("b", 3);
+        s.enqueue("c", 4);
+
+        assert_eq!(s.start_next(), Some(("a".to_string(), 1)));
+        // "a" is at its per-user cap, so "b" goes next even though a2 is older.
+        assert_eq!(s.start_next(), Some(("b".to_string(), 3)));
+        // Global cap reached.
+        assert_eq!(s.start_next(), None);
+        assert_eq!(s.queued(), 2);
+
+        s.finish("a");
+        assert_eq!(s.start_next(), Some(("a".to_string(), 2)));
+        s.finish("b");
+        assert_eq!(s.start_next(), Some(("c".to_string(), 4)));
+        assert_eq!(s.running(), 2);
+        assert_eq!(s.queued(), 0);
+    }
+}
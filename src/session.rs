@@ -0,0 +1,304 @@
+use crate::privilege::UnixIdentity;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Variables that change on every run and are never carried over.
+const VOLATILE_VARS: &[&str] = &["PWD", "OLDPWD", "SHLVL", "_"];
+/// Separates the environment before and after the command in the state
+/// written to fd 3; no real variable name can start with `=`.
+const STATE_SEPARATOR: &str = "=";
+/// Variables that decide which programs and libraries run, or run code of
+/// their own when a shell starts. Restricted roles cannot carry them over.
+const PROTECTED_VARS: &[&str] = &["PATH", "BASH_ENV", "ENV", "SHELLOPTS", "BASHOPTS", "IFS"];
+
+/// Whether `name` is in `PROTECTED_VARS` or configures the dynamic loader
+/// (`LD_PRELOAD`, `LD_LIBRARY_PATH`, ...).
+pub fn is_protected_var(name: &str) -> bool {
+    PROTECTED_VARS.contains(&name) || name.starts_with("LD_") || name.starts_with("DYLD_")
+}
+
+/// Shell state carried from one command of a requester to the next.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    /// `None` until the requester changes directory; the role's
+    /// `working_dir` applies until then.
+    pub cwd: Option<PathBuf>,
+    /// Variables exported by earlier commands.
+    pub env: BTreeMap<String, String>,
+}
+
+impl Session {
+    pub fn apply(&mut self, change: &StateChange) {
+        if let Some(cwd) = &change.cwd {
+            self.cwd = Some(cwd.clone());
+        }
+        for (k, v) in &change.set {
+            self.env.insert(k.clone(), v.clone());
+        }
+        for k in &change.unset {
+            self.env.remove(k);
+        }
+    }
+}
+
+/// Sessions by requester email, shared between workers.
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    inner: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+    pub fn get(&self, email: &str) -> Session {
+        self.inner.lock().unwrap().get(email).cloned().unwrap_or_default()
+    }
+
+    pub fn update(&self, email: &str, f: impl FnOnce(&mut Session)) {
+        f(self.inner.lock().unwrap().entry(email.to_string()).or_default());
+    }
+}
+
+/// What a command changed: the directory it ended in and the variables it
+/// exported or unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateChange {
+    pub cwd: Option<PathBuf>,
+    pub set: Vec<(String, String)>,
+    pub unset: Vec<String>,
+}
+
+/// Commands the agent handles itself instead of spawning a shell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Builtin {
+    /// `cd` or `cd <dir>`.
+    Cd(Option<String>),
+    /// `export NAME=value ...`.
+    Export(Vec<(String, String)>),
+}
+
+/// Recognizes a bare `cd <dir>` or `export NAME=value ...`. Anything using
+/// shell syntax (expansions, operators, redirections) is left to the shell,
+/// whose state is captured afterwards anyway.
+pub fn parse_builtin(cmd: &str) -> Option<Builtin> {
+    if cmd.contains(|c: char| ";&|<>$`(){}*?\\\n".contains(c)) {
+        return None;
+    }
+    let words = split_words(cmd)?;
+    let (first, args) = words.split_first()?;
+    match first.as_str() {
+        "cd" if args.len() <= 1 => {
+            let target = args.first().cloned();
+            (target.as_deref() != Some("-")).then_some(Builtin::Cd(target))
+        }
+        "export" if !args.is_empty() => args
+            .iter()
+            .map(|a| {
+                let (k, v) = a.split_once('=')?;
+                is_var_name(k).then(|| (k.to_string(), v.to_string()))
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Builtin::Export),
+        _ => None,
+    }
+}
+
+/// Resolves the target of `cd` against `cwd`, expanding `~` to `home`. With
+/// `run_as`, the directory must be one that account can enter.
+pub fn resolve_cd(target: Option<&str>, cwd: &Path, home: &Path, run_as: Option<&UnixIdentity>) -> Result<PathBuf> {
+    let path = match target {
+        None | Some("~") => home.to_path_buf(),
+        Some(t) => match t.strip_prefix("~/") {
+            Some(rest) => home.join(rest),
+            None => cwd.join(t),
+        },
+    };
+    let resolved = path
+        .canonicalize()
+        .map_err(|e| anyhow!("cd: {}: {}", path.display(), e))?;
+    if !resolved.is_dir() {
+        return Err(anyhow!("cd: {}: not a directory", resolved.display()));
+    }
+    if let Some(id) = run_as {
+        if !can_enter(&resolved, id) {
+            return Err(anyhow!("cd: {}: permission denied for {}", resolved.display(), id.user));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Whether `id` may search `dir` and each directory above it.
+#[cfg(unix)]
+fn can_enter(dir: &Path, id: &UnixIdentity) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    if id.uid == 0 {
+        return true;
+    }
+    dir.ancestors().all(|d| {
+        let Ok(meta) = d.metadata() else { return false };
+        let mode = meta.mode();
+        let bit = if meta.uid() == id.uid {
+            0o100
+        } else if id.groups.contains(&meta.gid()) || meta.gid() == id.gid {
+            0o010
+        } else {
+            0o001
+        };
+        mode & bit != 0
+    })
+}
+
+#[cfg(not(unix))]
+fn can_enter(_dir: &Path, _id: &UnixIdentity) -> bool {
+    true
+}
+
+/// Wraps `cmd` so the shell reports its state on fd 3: the environment
+/// before the command, `STATE_SEPARATOR`, then the directory and
+/// environment after it, all NUL-separated. The command itself does not
+/// see fd 3; one that calls `exit` skips the second half and leaves the
+/// session unchanged.
+pub fn wrap_command(cmd: &str) -> String {
+    format!(
+        "{{ env -0; printf '%s\\000' '{sep}'; }} >&3 2>/dev/null\n\
+         {{ {cmd}\n}} 3>&-\n\
+         __nssh_rc=$?\n\
+         {{ printf '%s\\000' \"$(pwd)\"; env -0; }} >&3 2>/dev/null\n\
+         exit $__nssh_rc",
+        sep = STATE_SEPARATOR,
+        cmd = cmd
+    )
+}
+
+/// Parses what `wrap_command` wrote to fd 3.
+pub fn parse_state(raw: &[u8]) -> Option<StateChange> {
+    let text = String::from_utf8_lossy(raw);
+    let mut records = text.split('\0');
+    let before: HashMap<&str, &str> = records
+        .by_ref()
+        .take_while(|r| *r != STATE_SEPARATOR)
+        .filter_map(|r| r.split_once('='))
+        .collect();
+    let cwd = records.next().filter(|c| !c.is_empty())?;
+    let after: HashMap<&str, &str> = records.filter_map(|r| r.split_once('=')).collect();
+
+    let keep = |k: &&str| is_var_name(k) && !VOLATILE_VARS.contains(k);
+    let mut set: Vec<(String, String)> = after
+        .iter()
+        .filter(|(k, v)| keep(k) && before.get(*k) != Some(*v))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let mut unset: Vec<String> = before
+        .keys()
+        .filter(|k| keep(k) && !after.contains_key(*k))
+        .map(|k| k.to_string())
+        .collect();
+    set.sort();
+    unset.sort();
+    Some(StateChange { cwd: Some(PathBuf::from(cwd)), set, unset })
+}
+
+/// Single-quotes `s` for a POSIX shell.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn is_var_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Whitespace splitting with single and double quotes; `None` when a quote
+/// is left open.
+fn split_words(s: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    for c in s.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return None;
+    }
+    if in_word {
+        words.push(current);
+    }
+    Some(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtins_are_recognized() {
+        assert_eq!(parse_builtin("cd /srv/app"), Some(Builtin::Cd(Some("/srv/app".into()))));
+        assert_eq!(parse_builtin("cd"), Some(Builtin::Cd(None)));
+        assert_eq!(parse_builtin("cd 'My Dir'"), Some(Builtin::Cd(Some("My Dir".into()))));
+        assert_eq!(
+            parse_builtin("export A=1 B=\"x y\""),
+            Some(Builtin::Export(vec![("A".into(), "1".into()), ("B".into(), "x y".into())]))
+        );
+        assert_eq!(parse_builtin("cd /srv && make"), None);
+        assert_eq!(parse_builtin("export PATH=$PATH:/opt/bin"), None);
+        assert_eq!(parse_builtin("export 1A=x"), None);
+        assert_eq!(parse_builtin("cd -"), None);
+        assert_eq!(parse_builtin("ls"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cd_checks_the_run_as_account() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let private = dir.path().join("private");
+        std::fs::create_dir(&private).unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let owner = std::fs::metadata(&private).map(|m| std::os::unix::fs::MetadataExt::uid(&m)).unwrap();
+        let nobody = UnixIdentity {
+            user: "nobody".into(),
+            uid: owner + 1,
+            gid: 65534,
+            groups: vec![65534],
+            home: PathBuf::from("/"),
+        };
+
+        assert!(resolve_cd(Some("private"), dir.path(), Path::new("/"), None).is_ok());
+        let err = resolve_cd(Some("private"), dir.path(), Path::new("/"), Some(&nobody)).unwrap_err();
+        assert!(err.to_string().contains("permission denied for nobody"));
+        assert!(resolve_cd(Some("."), dir.path(), Path::new("/"), Some(&nobody)).is_ok());
+        assert!(is_protected_var("LD_PRELOAD") && is_protected_var("PATH") && !is_protected_var("LANG"));
+    }
+
+    #[test]
+    fn state_diff_tracks_cwd_and_exports() {
+        let raw = b"HOME=/root\0OLD=1\0PWD=/\0=\0/srv/app\0HOME=/root\0NEW=a=b\0PWD=/srv/app\0";
+        let change = parse_state(raw).unwrap();
+        assert_eq!(change.cwd, Some(PathBuf::from("/srv/app")));
+        assert_eq!(change.set, vec![("NEW".to_string(), "a=b".to_string())]);
+        assert_eq!(change.unset, vec!["OLD".to_string()]);
+
+        // The command exited before reporting its final state.
+        assert_eq!(parse_state(b"HOME=/root\0=\0"), None);
+    }
+}
@@ -0,0 +1,1247 @@
+use std::fmt;
+
+/// Nested substitutions, subshell scripts and wrappers deeper than this are
+/// not analyzed.
+const MAX_DEPTH: usize = 8;
+
+/// Words that may precede a command without being the program themselves.
+const PREFIX_KEYWORDS: &[&str] = &["if", "then", "else", "elif", "do", "while", "until", "!", "{"];
+/// Words that close a compound command.
+const END_KEYWORDS: &[&str] = &["fi", "done", "}"];
+/// Constructs whose structure is not analyzed.
+const UNSUPPORTED_KEYWORDS: &[&str] = &["for", "case", "select", "function", "coproc", "[[", "(("];
+/// Shells whose `-c` argument is a script of its own.
+const SHELLS: &[&str] = &["sh", "bash", "dash", "zsh", "ksh", "mksh", "ash", "fish"];
+
+/// One program invocation found in a command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The program as written, e.g. `/bin/rm`.
+    pub program: String,
+    /// Arguments with quotes removed. Arguments built from expansions keep
+    /// their literal parts only.
+    pub args: Vec<String>,
+}
+
+impl Invocation {
+    /// The program without its directory, e.g. `rm` for `/bin/rm`.
+    pub fn name(&self) -> &str {
+        basename(&self.program)
+    }
+}
+
+/// The program name followed by its arguments, separated by single spaces.
+impl fmt::Display for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a command line could not be analyzed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unanalyzable(pub String);
+
+impl fmt::Display for Unanalyzable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unanalyzable {}
+
+pub fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Finds every program `cmd` would run when handed to a POSIX shell:
+/// commands separated by `;`, `&`, `&&`, `||` and pipes, inside subshells,
+/// command and process substitutions, behind environment assignments and
+/// redirections, and behind wrappers such as `sudo`, `env`, `xargs`,
+/// `find -exec` or `sh -c`. Anything whose program cannot be known without
+/// running it (`$(which rm)`, `$CMD`, globs, heredocs, `for`/`case`) is
+/// reported as `Unanalyzable`.
+pub fn analyze(cmd: &str) -> Result<Vec<Invocation>, Unanalyzable> {
+    analyze_at(cmd, 0)
+}
+
+fn analyze_at(cmd: &str, depth: usize) -> Result<Vec<Invocation>, Unanalyzable> {
+    if depth > MAX_DEPTH {
+        return Err(unanalyzable("commands are nested too deeply"));
+    }
+    let (tokens, nested) = Lexer::new(cmd).run()?;
+    let mut found = Vec::new();
+    for script in nested {
+        found.extend(analyze_at(&script, depth + 1)?);
+    }
+    let mut current: Vec<Word> = Vec::new();
+    for token in tokens {
+        match token {
+            Token::Word(w) => current.push(w),
+            Token::Separator => {
+                found.extend(simple_command(std::mem::take(&mut current), depth)?);
+            }
+            Token::Open => {
+                if !current.is_empty() {
+                    return Err(unanalyzable("unexpected '(' (function definitions are not supported)"));
+                }
+            }
+        }
+    }
+    found.extend(simple_command(current, depth)?);
+    Ok(found)
+}
+
+fn unanalyzable(reason: impl Into<String>) -> Unanalyzable {
+    Unanalyzable(reason.into())
+}
+
+/// A shell word after quote removal.
+#[derive(Debug, Clone, Default)]
+struct Word {
+    text: String,
+    /// Contains an expansion whose value is only known at run time.
+    dynamic: bool,
+    /// Contains an unquoted glob or brace character.
+    glob: bool,
+    /// Any part of it was quoted or escaped.
+    quoted: bool,
+}
+
+impl Word {
+    fn is_plain(&self, s: &str) -> bool {
+        !self.quoted && self.text == s
+    }
+
+    fn is_assignment(&self) -> bool {
+        match self.text.split_once('=') {
+            Some((name, _)) => {
+                let mut chars = name.chars();
+                chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+                    && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+            }
+            None => false,
+        }
+    }
+}
+
+enum Token {
+    Word(Word),
+    /// `;`, `&`, `&&`, `|`, `||`, `|&`, newline or `)`.
+    Separator,
+    /// `(` opening a subshell.
+    Open,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    /// Scripts of command and process substitutions.
+    nested: Vec<String>,
+}
+
+impl Lexer {
+    fn new(src: &str) -> Self {
+        Self { chars: src.chars().collect(), pos: 0, nested: Vec::new() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn run(mut self) -> Result<(Vec<Token>, Vec<String>), Unanalyzable> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\\' if self.peek_at(1) == Some('\n') => self.pos += 2,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                ';' => {
+                    if self.peek_at(1) == Some(';') {
+                        return Err(unanalyzable("'case' is not supported"));
+                    }
+                    self.pos += 1;
+                    tokens.push(Token::Separator);
+                }
+                '\n' | ')' => {
+                    self.pos += 1;
+                    tokens.push(Token::Separator);
+                }
+                '(' => {
+                    self.pos += 1;
+                    tokens.push(Token::Open);
+                }
+                '|' => {
+                    self.pos += if matches!(self.peek_at(1), Some('|') | Some('&')) { 2 } else { 1 };
+                    tokens.push(Token::Separator);
+                }
+                '&' if self.peek_at(1) == Some('>') => self.redirection()?,
+                '&' => {
+                    self.pos += if self.peek_at(1) == Some('&') { 2 } else { 1 };
+                    tokens.push(Token::Separator);
+                }
+                '<' | '>' if self.peek_at(1) == Some('(') => {
+                    // Process substitution: an argument produced by a command.
+                    self.pos += 2;
+                    let inner = self.balanced(')')?;
+                    self.nested.push(inner);
+                    tokens.push(Token::Word(Word { dynamic: true, ..Default::default() }));
+                }
+                '<' | '>' => self.redirection()?,
+                _ => {
+                    let word = self.word()?;
+                    let fd_number = !word.quoted
+                        && !word.text.is_empty()
+                        && word.text.chars().all(|c| c.is_ascii_digit())
+                        && matches!(self.peek(), Some('<') | Some('>'));
+                    // `2>file`: the digits name a descriptor, not an argument.
+                    if !fd_number {
+                        tokens.push(Token::Word(word));
+                    }
+                }
+            }
+        }
+        Ok((tokens, self.nested))
+    }
+
+    /// Skips a redirection operator and its target.
+    fn redirection(&mut self) -> Result<(), Unanalyzable> {
+        let mut op = String::new();
+        while let Some(c) = self.peek().filter(|c| matches!(c, '<' | '>' | '&' | '|' | '-')) {
+            if c == '-' && !op.starts_with("<<") {
+                break;
+            }
+            op.push(c);
+            self.pos += 1;
+        }
+        if op.starts_with("<<") && !op.starts_with("<<<") {
+            return Err(unanalyzable("heredocs are not supported"));
+        }
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(c) if !is_meta(c) => {
+                self.word()?;
+                Ok(())
+            }
+            _ => Err(unanalyzable(format!("'{}' without a target", op))),
+        }
+    }
+
+    fn word(&mut self) -> Result<Word, Unanalyzable> {
+        let mut word = Word::default();
+        while let Some(c) = self.peek() {
+            if is_meta(c) {
+                break;
+            }
+            self.pos += 1;
+            match c {
+                '\'' => {
+                    word.quoted = true;
+                    loop {
+                        match self.peek() {
+                            Some('\'') => break,
+                            Some(c) => {
+                                word.text.push(c);
+                                self.pos += 1;
+                            }
+                            None => return Err(unanalyzable("unterminated quote")),
+                        }
+                    }
+                    self.pos += 1;
+                }
+                '"' => {
+                    word.quoted = true;
+                    loop {
+                        match self.peek() {
+                            Some('"') => break,
+                            Some('\\') => {
+                                self.pos += 1;
+                                match self.peek() {
+                                    Some(c @ ('$' | '`' | '"' | '\\')) => word.text.push(c),
+                                    Some('\n') => {}
+                                    Some(c) => {
+                                        word.text.push('\\');
+                                        word.text.push(c);
+                                    }
+                                    None => return Err(unanalyzable("unterminated quote")),
+                                }
+                                self.pos += 1;
+                            }
+                            Some('$') => {
+                                self.pos += 1;
+                                self.dollar(&mut word)?;
+                            }
+                            Some('`') => {
+                                self.pos += 1;
+                                self.backtick(&mut word)?;
+                            }
+                            Some(c) => {
+                                word.text.push(c);
+                                self.pos += 1;
+                            }
+                            None => return Err(unanalyzable("unterminated quote")),
+                        }
+                    }
+                    self.pos += 1;
+                }
+                '\\' => {
+                    word.quoted = true;
+                    match self.peek() {
+                        Some('\n') => {}
+                        Some(c) => word.text.push(c),
+                        None => {}
+                    }
+                    self.pos += 1;
+                }
+                '$' => self.dollar(&mut word)?,
+                '`' => self.backtick(&mut word)?,
+                '*' | '?' | '[' | '{' => {
+                    word.glob = true;
+                    word.text.push(c);
+                }
+                c => word.text.push(c),
+            }
+        }
+        Ok(word)
+    }
+
+    /// Handles what follows a `$`.
+    fn dollar(&mut self, word: &mut Word) -> Result<(), Unanalyzable> {
+        match self.peek() {
+            Some('(') if self.peek_at(1) == Some('(') => Err(unanalyzable("arithmetic expansion is not supported")),
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.balanced(')')?;
+                self.nested.push(inner);
+                word.dynamic = true;
+                Ok(())
+            }
+            Some('{') => {
+                self.pos += 1;
+                let inner = self.balanced('}')?;
+                if inner.contains("$(") || inner.contains('`') {
+                    return Err(unanalyzable("command substitution inside ${...} is not supported"));
+                }
+                word.dynamic = true;
+                Ok(())
+            }
+            Some('\'') => {
+                // $'...' may spell anything with escapes.
+                self.pos += 1;
+                while let Some(c) = self.peek() {
+                    self.pos += 1;
+                    match c {
+                        '\\' => self.pos += 1,
+                        '\'' => break,
+                        _ => {}
+                    }
+                }
+                word.dynamic = true;
+                Ok(())
+            }
+            Some(c) if c == '_' || c.is_ascii_alphanumeric() => {
+                while self.peek().is_some_and(|c| c == '_' || c.is_ascii_alphanumeric()) {
+                    self.pos += 1;
+                }
+                word.dynamic = true;
+                Ok(())
+            }
+            Some('@' | '*' | '#' | '?' | '-' | '$' | '!') => {
+                self.pos += 1;
+                word.dynamic = true;
+                Ok(())
+            }
+            _ => {
+                word.text.push('$');
+                Ok(())
+            }
+        }
+    }
+
+    fn backtick(&mut self, word: &mut Word) -> Result<(), Unanalyzable> {
+        let mut inner = String::new();
+        loop {
+            match self.peek() {
+                Some('`') => break,
+                Some('\\') => {
+                    self.pos += 1;
+                    if let Some(c) = self.peek() {
+                        if !matches!(c, '`' | '\\' | '$') {
+                            inner.push('\\');
+                        }
+                        inner.push(c);
+                    }
+                }
+                Some(c) => inner.push(c),
+                None => return Err(unanalyzable("unterminated backquote")),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        self.nested.push(inner);
+        word.dynamic = true;
+        Ok(())
+    }
+
+    /// Text up to the `close` matching an opening bracket just consumed,
+    /// skipping quoted parts.
+    fn balanced(&mut self, close: char) -> Result<String, Unanalyzable> {
+        let open = if close == ')' { '(' } else { '{' };
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '\\' => self.pos += 1,
+                '\'' => {
+                    while self.peek().is_some_and(|c| c != '\'') {
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                }
+                '"' => {
+                    while let Some(c) = self.peek() {
+                        self.pos += 1;
+                        match c {
+                            '\\' => self.pos += 1,
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                c if c == open => depth += 1,
+                c if c == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(self.chars[start..self.pos - 1].iter().collect());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(unanalyzable(format!("missing '{}'", close)))
+    }
+}
+
+fn is_meta(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | ';' | '&' | '|' | '(' | ')' | '<' | '>')
+}
+
+/// Invocations of one simple command, after dropping keywords and
+/// assignments and unwrapping wrappers.
+fn simple_command(words: Vec<Word>, depth: usize) -> Result<Vec<Invocation>, Unanalyzable> {
+    let mut start = 0;
+    while let Some(w) = words.get(start) {
+        if let Some(k) = UNSUPPORTED_KEYWORDS.iter().find(|k| w.is_plain(k)) {
+            return Err(unanalyzable(format!("'{}' is not supported", k)));
+        }
+        let keyword = PREFIX_KEYWORDS.iter().chain(END_KEYWORDS).any(|k| w.is_plain(k));
+        if !keyword && !w.is_assignment() {
+            break;
+        }
+        start += 1;
+    }
+    invocations(&words[start..], depth)
+}
+
+fn invocations(argv: &[Word], depth: usize) -> Result<Vec<Invocation>, Unanalyzable> {
+    if depth > MAX_DEPTH {
+        return Err(unanalyzable("commands are nested too deeply"));
+    }
+    let Some((program, args)) = argv.split_first() else { return Ok(Vec::new()) };
+    if program.dynamic || program.glob || program.text.is_empty() {
+        return Err(unanalyzable(format!(
+            "cannot tell which program '{}' runs without running it",
+            if program.text.is_empty() { "$..." } else { &program.text }
+        )));
+    }
+    let mut found = vec![Invocation {
+        program: program.text.clone(),
+        args: args.iter().map(|w| w.text.clone()).collect(),
+    }];
+    match unwrap(argv)? {
+        Inner::None => {}
+        Inner::Command(at) => found.extend(invocations(&argv[at..], depth + 1)?),
+        Inner::Script(script) => found.extend(analyze_at(&script, depth + 1)?),
+        Inner::Commands(ranges) => {
+            for (from, to) in ranges {
+                found.extend(invocations(&argv[from..to], depth + 1)?);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// What a wrapper program runs in turn.
+enum Inner {
+    None,
+    /// The words from this index on form a command.
+    Command(usize),
+    /// A shell script given as an argument.
+    Script(String),
+    /// Commands embedded in the arguments (`find -exec ... ;`).
+    Commands(Vec<(usize, usize)>),
+}
+
+/// How a program reads its options, the way getopt_long does: short options
+/// cluster (`-lc`) and take their argument from the rest of the word or the
+/// next word (`-uroot`, `-u root`), long options may be abbreviated and take
+/// theirs as `--name=value` or `--name value`. Options not listed here make
+/// the command line unanalyzable.
+#[derive(Default)]
+struct Opts {
+    /// Short options without an argument.
+    flags: &'static str,
+    /// Short options with an argument.
+    with_arg: &'static str,
+    /// Short options whose argument, if any, is the rest of the word.
+    optional: &'static str,
+    long_flags: &'static [&'static str],
+    long_with_arg: &'static [&'static str],
+    /// Long options whose argument, if any, follows `=`.
+    long_optional: &'static [&'static str],
+    /// Options may also follow operands, as with GNU getopt's default
+    /// ordering. Otherwise the first operand ends the options.
+    permute: bool,
+}
+
+/// The options and operands `Opts::parse` found.
+struct Parsed {
+    /// Each option by the name it is listed under in `Opts`, with its
+    /// argument.
+    options: Vec<(String, Option<Word>)>,
+    /// Indexes of the operands.
+    operands: Vec<usize>,
+}
+
+impl Parsed {
+    fn has(&self, names: &[&str]) -> bool {
+        self.options.iter().any(|(name, _)| names.contains(&name.as_str()))
+    }
+
+    fn values<'a>(&'a self, names: &'a [&'a str]) -> impl Iterator<Item = &'a Word> + 'a {
+        self.options
+            .iter()
+            .filter(move |(name, _)| names.contains(&name.as_str()))
+            .filter_map(|(_, value)| value.as_ref())
+    }
+
+    /// Index of the `n`th operand, or one past any word if there is none.
+    fn operand(&self, n: usize) -> usize {
+        self.operands.get(n).copied().unwrap_or(usize::MAX)
+    }
+}
+
+impl Opts {
+    fn parse(&self, argv: &[Word], from: usize) -> Result<Parsed, Unanalyzable> {
+        let program = basename(&argv[0].text);
+        let mut parsed = Parsed { options: Vec::new(), operands: Vec::new() };
+        let mut i = from;
+        while let Some(w) = argv.get(i) {
+            i += 1;
+            if w.dynamic || w.glob {
+                return Err(unanalyzable(format!("the arguments of '{}' are built at run time", program)));
+            }
+            let text = w.text.as_str();
+            if text == "--" {
+                parsed.operands.extend(i..argv.len());
+                break;
+            }
+            if let Some(long) = text.strip_prefix("--") {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (long, None),
+                };
+                let Some(name) = self.long_name(name) else {
+                    return Err(unanalyzable(format!("'{}' does not take '{}'", program, text)));
+                };
+                let value = if self.long_with_arg.contains(&name) && value.is_none() {
+                    i += 1;
+                    Some(self.argument(argv, i - 1, program, text)?)
+                } else if self.long_flags.contains(&name) && value.is_some() {
+                    return Err(unanalyzable(format!("'{}' does not take '{}'", program, text)));
+                } else {
+                    value.map(|v| part_of(w, v))
+                };
+                parsed.options.push((name.to_string(), value));
+            } else if text.len() > 1 && text.starts_with('-') {
+                let cluster = &text[1..];
+                for (at, c) in cluster.char_indices() {
+                    let rest = &cluster[at + c.len_utf8()..];
+                    if self.flags.contains(c) {
+                        parsed.options.push((c.to_string(), None));
+                        continue;
+                    }
+                    let value = if self.with_arg.contains(c) && rest.is_empty() {
+                        i += 1;
+                        Some(self.argument(argv, i - 1, program, text)?)
+                    } else if self.with_arg.contains(c) || self.optional.contains(c) {
+                        Some(part_of(w, rest)).filter(|_| !rest.is_empty())
+                    } else {
+                        return Err(unanalyzable(format!("'{}' does not take '-{}'", program, c)));
+                    };
+                    parsed.options.push((c.to_string(), value));
+                    break;
+                }
+            } else {
+                parsed.operands.push(i - 1);
+                if !self.permute {
+                    parsed.operands.extend(i..argv.len());
+                    break;
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// The listed long option `name` is or abbreviates.
+    fn long_name(&self, name: &str) -> Option<&'static str> {
+        let all = || self.long_flags.iter().chain(self.long_with_arg).chain(self.long_optional).copied();
+        if let Some(exact) = all().find(|long| *long == name) {
+            return Some(exact);
+        }
+        let mut matches = all().filter(|long| !name.is_empty() && long.starts_with(name));
+        match (matches.next(), matches.next()) {
+            (Some(only), None) => Some(only),
+            _ => None,
+        }
+    }
+
+    fn argument(&self, argv: &[Word], at: usize, program: &str, option: &str) -> Result<Word, Unanalyzable> {
+        argv.get(at)
+            .cloned()
+            .ok_or_else(|| unanalyzable(format!("'{}' of '{}' needs an argument", option, program)))
+    }
+}
+
+/// `text`, a piece of `word`, as a word of its own.
+fn part_of(word: &Word, text: &str) -> Word {
+    Word { text: text.to_string(), ..word.clone() }
+}
+
+fn skip_assignments(argv: &[Word], mut i: usize) -> usize {
+    while argv.get(i).is_some_and(|w| w.is_assignment()) {
+        i += 1;
+    }
+    i
+}
+
+/// Single-letter options POSIX shells take on their command line.
+const SHELL_FLAGS: &str = "abcefhiklmnprstuvxBCDEHIPTV";
+/// Long options of bash. `--rcfile` and `--init-file` take a file.
+const SHELL_LONG_FLAGS: &[&str] = &[
+    "login", "noprofile", "norc", "posix", "noediting", "restricted", "verbose", "version", "help", "debugger",
+    "dump-strings", "dump-po-strings", "pretty-print",
+];
+const SHELL_LONG_WITH_ARG: &[&str] = &["rcfile", "init-file"];
+
+/// The script of `sh -c`: the first operand after the options, which may
+/// also follow `-c` (`bash -c -e -- 'rm x'`). Shells take the argument of
+/// `-o` and `-O` from the next word even inside a cluster (`-eo pipefail`).
+fn shell_script(argv: &[Word]) -> Result<Inner, Unanalyzable> {
+    let shell = basename(&argv[0].text);
+    let mut command = false;
+    let mut i = 1;
+    while let Some(w) = argv.get(i) {
+        if w.dynamic || w.glob {
+            return Err(unanalyzable(format!("the arguments of '{}' are built at run time", shell)));
+        }
+        let text = w.text.as_str();
+        if text == "--" || text == "-" {
+            i += 1;
+            break;
+        }
+        if let Some(long) = text.strip_prefix("--") {
+            let name = long.split_once('=').map_or(long, |(name, _)| name);
+            if SHELL_LONG_WITH_ARG.contains(&name) {
+                i += if long.contains('=') { 1 } else { 2 };
+                continue;
+            }
+            if !SHELL_LONG_FLAGS.contains(&long) {
+                return Err(unanalyzable(format!("'{}' does not take '{}'", shell, text)));
+            }
+            i += 1;
+            continue;
+        }
+        let Some(cluster) = text.strip_prefix(['-', '+']).filter(|c| !c.is_empty()) else { break };
+        i += 1;
+        for c in cluster.chars() {
+            match c {
+                'o' | 'O' => i += 1,
+                'c' => command = true,
+                c if SHELL_FLAGS.contains(c) => {}
+                c => return Err(unanalyzable(format!("'{}' does not take '-{}'", shell, c))),
+            }
+        }
+    }
+    if !command {
+        return Ok(Inner::None);
+    }
+    script_at(argv, i)
+}
+
+fn script_at(argv: &[Word], at: usize) -> Result<Inner, Unanalyzable> {
+    match argv.get(at) {
+        Some(script) if script.dynamic => Err(unanalyzable("the script passed with -c is built at run time")),
+        Some(script) => Ok(Inner::Script(script.text.clone())),
+        None => Ok(Inner::None),
+    }
+}
+
+/// The arguments of the options `names` as one script, for programs that
+/// run each with a shell.
+fn option_scripts(parsed: &Parsed, names: &[&str]) -> Result<Vec<String>, Unanalyzable> {
+    parsed
+        .values(names)
+        .map(|w| match w.dynamic {
+            true => Err(unanalyzable("the script passed with -c is built at run time")),
+            false => Ok(w.text.clone()),
+        })
+        .collect()
+}
+
+fn scripts(scripts: Vec<String>) -> Inner {
+    if scripts.is_empty() {
+        Inner::None
+    } else {
+        Inner::Script(scripts.join("\n"))
+    }
+}
+
+fn command_at(argv: &[Word], at: usize) -> Inner {
+    if at < argv.len() {
+        Inner::Command(at)
+    } else {
+        Inner::None
+    }
+}
+
+const SUDO: Opts = Opts {
+    flags: "AbEeHiKklNnPSsVv",
+    with_arg: "aCDghpRrtTUu",
+    optional: "",
+    long_flags: &[
+        "askpass", "background", "edit", "set-home", "help", "login", "remove-timestamp", "reset-timestamp", "list",
+        "no-update", "non-interactive", "preserve-groups", "stdin", "shell", "validate", "version", "bell",
+    ],
+    long_with_arg: &[
+        "auth-type", "close-from", "chdir", "chroot", "group", "host", "prompt", "role", "type", "other-user",
+        "user", "command-timeout",
+    ],
+    long_optional: &["preserve-env"],
+    permute: false,
+};
+
+const ENV: Opts = Opts {
+    flags: "0iv",
+    with_arg: "aCSu",
+    optional: "",
+    long_flags: &["ignore-environment", "null", "debug", "list-signal-handling", "help", "version"],
+    long_with_arg: &["argv0", "chdir", "split-string", "unset"],
+    long_optional: &["block-signal", "default-signal", "ignore-signal"],
+    permute: false,
+};
+
+const SU: Opts = Opts {
+    flags: "flmPphV",
+    with_arg: "cgGsuw",
+    optional: "",
+    long_flags: &["fast", "login", "preserve-environment", "pty", "help", "version"],
+    long_with_arg: &["command", "session-command", "group", "supp-group", "shell", "user", "whitelist-environment"],
+    long_optional: &[],
+    permute: true,
+};
+
+const XARGS: Opts = Opts {
+    flags: "0prtx",
+    with_arg: "adEILnPs",
+    optional: "eil",
+    long_flags: &["null", "interactive", "no-run-if-empty", "verbose", "show-limits", "exit", "open-tty", "help", "version"],
+    long_with_arg: &["arg-file", "delimiter", "max-args", "max-chars", "max-procs", "process-slot-var"],
+    long_optional: &["eof", "replace", "max-lines"],
+    permute: false,
+};
+
+const SYSTEMD_RUN: Opts = Opts {
+    flags: "dGhPqrStv",
+    with_arg: "EHMpu",
+    optional: "",
+    long_flags: &[
+        "help", "version", "user", "system", "scope", "remain-after-exit", "send-sighup", "same-dir", "pty", "pipe",
+        "quiet", "collect", "shell", "no-block", "wait", "no-ask-password", "expand-environment", "ignore-failure",
+        "slice-inherit", "on-clock-change", "on-timezone-change",
+    ],
+    long_with_arg: &[
+        "host", "machine", "setenv", "property", "unit", "description", "slice", "service-type", "uid", "gid",
+        "working-directory", "nice", "on-active", "on-boot", "on-startup", "on-unit-active", "on-unit-inactive",
+        "on-calendar", "path-property", "socket-property", "timer-property", "json", "background", "capsule",
+    ],
+    long_optional: &[],
+    permute: false,
+};
+
+const STRACE: Opts = Opts {
+    flags: "AcCdDfFhiknqrtTvVwxyYzZ",
+    with_arg: "abeEIoOpPsSuUX",
+    optional: "",
+    long_flags: &[
+        "follow-forks", "output-separately", "output-append-mode", "summary-only", "summary", "summary-wall-clock",
+        "seccomp-bpf", "stack-trace", "syscall-number", "instruction-pointer", "no-abbrev", "successful-only",
+        "failed-only", "kill-on-exit", "debug", "help", "version",
+    ],
+    long_with_arg: &[
+        "trace", "trace-path", "signal", "abbrev", "verbose", "raw", "read", "write", "fault", "inject", "status",
+        "output", "attach", "user", "env", "string-limit", "summary-sort-by", "columns",
+    ],
+    long_optional: &[
+        "decode-fds", "decode-pids", "timestamps", "absolute-timestamps", "relative-timestamps", "syscall-times",
+        "quiet", "silent", "silence", "daemonize",
+    ],
+    permute: false,
+};
+
+fn unwrap(argv: &[Word]) -> Result<Inner, Unanalyzable> {
+    let name = basename(&argv[0].text);
+    let inner = match name {
+        "sudo" => command_at(argv, skip_assignments(argv, SUDO.parse(argv, 1)?.operand(0))),
+        "doas" => command_at(argv, Opts { flags: "Lns", with_arg: "aCu", ..Opts::default() }.parse(argv, 1)?.operand(0)),
+        "pkexec" => {
+            let opts = Opts {
+                long_flags: &["disable-internal-agent", "keep-cwd", "help", "version"],
+                long_with_arg: &["user"],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(0))
+        }
+        "env" => {
+            let parsed = ENV.parse(argv, 1)?;
+            if parsed.has(&["S", "split-string"]) {
+                return Err(unanalyzable("'env -S' is not supported"));
+            }
+            let mut at = parsed.operand(0);
+            // A lone `-` is `-i`.
+            if argv.get(at).is_some_and(|w| w.text == "-") {
+                at += 1;
+            }
+            command_at(argv, skip_assignments(argv, at))
+        }
+        "nice" => {
+            // `nice -5 cmd` and `nice --5 cmd` predate `-n`.
+            let legacy = argv.get(1).is_some_and(|w| {
+                w.text.strip_prefix('-').is_some_and(|n| n.trim_start_matches(['-', '+']).parse::<u32>().is_ok())
+            });
+            let opts = Opts {
+                with_arg: "n",
+                long_flags: &["help", "version"],
+                long_with_arg: &["adjustment"],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, if legacy { 2 } else { 1 })?.operand(0))
+        }
+        "nohup" => command_at(argv, Opts { long_flags: &["help", "version"], ..Opts::default() }.parse(argv, 1)?.operand(0)),
+        "setsid" => {
+            let opts = Opts { flags: "cfwhV", long_flags: &["ctty", "fork", "wait", "help", "version"], ..Opts::default() };
+            command_at(argv, opts.parse(argv, 1)?.operand(0))
+        }
+        "builtin" => command_at(argv, Opts::default().parse(argv, 1)?.operand(0)),
+        "unbuffer" => command_at(argv, Opts { flags: "p", ..Opts::default() }.parse(argv, 1)?.operand(0)),
+        "busybox" => {
+            let opts = Opts { long_flags: &["list", "list-full", "install", "help"], ..Opts::default() };
+            let parsed = opts.parse(argv, 1)?;
+            if parsed.options.is_empty() {
+                command_at(argv, parsed.operand(0))
+            } else {
+                Inner::None
+            }
+        }
+        "exec" => command_at(argv, Opts { flags: "cl", with_arg: "a", ..Opts::default() }.parse(argv, 1)?.operand(0)),
+        "command" => command_at(argv, Opts { flags: "pvV", ..Opts::default() }.parse(argv, 1)?.operand(0)),
+        "time" => {
+            let opts = Opts {
+                flags: "apqvV",
+                with_arg: "fo",
+                long_flags: &["append", "portability", "quiet", "verbose", "help", "version"],
+                long_with_arg: &["format", "output"],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(0))
+        }
+        "stdbuf" => {
+            let opts = Opts {
+                with_arg: "eio",
+                long_flags: &["help", "version"],
+                long_with_arg: &["error", "input", "output"],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(0))
+        }
+        "ionice" => {
+            let opts = Opts {
+                flags: "thV",
+                with_arg: "cnpPu",
+                long_flags: &["ignore", "help", "version"],
+                long_with_arg: &["class", "classdata", "pid", "pgid", "uid"],
+                ..Opts::default()
+            };
+            let parsed = opts.parse(argv, 1)?;
+            // The operands are process IDs.
+            if parsed.has(&["p", "P", "u", "pid", "pgid", "uid"]) {
+                Inner::None
+            } else {
+                command_at(argv, parsed.operand(0))
+            }
+        }
+        "timeout" => {
+            let opts = Opts {
+                flags: "fpv",
+                with_arg: "ks",
+                long_flags: &["foreground", "preserve-status", "verbose", "help", "version"],
+                long_with_arg: &["kill-after", "signal"],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(1))
+        }
+        "taskset" => {
+            let opts = Opts {
+                flags: "acphV",
+                long_flags: &["all-tasks", "cpu-list", "pid", "help", "version"],
+                ..Opts::default()
+            };
+            let parsed = opts.parse(argv, 1)?;
+            if parsed.has(&["p", "pid"]) {
+                Inner::None
+            } else {
+                command_at(argv, parsed.operand(1))
+            }
+        }
+        "chrt" => {
+            let opts = Opts {
+                flags: "abdefhimoprRvV",
+                with_arg: "DPT",
+                long_flags: &[
+                    "all-tasks", "batch", "deadline", "fifo", "idle", "other", "rr", "ext", "reset-on-fork", "max",
+                    "pid", "verbose", "help", "version",
+                ],
+                long_with_arg: &["sched-runtime", "sched-period", "sched-deadline"],
+                ..Opts::default()
+            };
+            let parsed = opts.parse(argv, 1)?;
+            let mut at = parsed.operand(0);
+            if argv.get(at).is_some_and(|w| w.text.parse::<u32>().is_ok()) {
+                at = parsed.operand(1);
+            }
+            if parsed.has(&["p", "pid", "m", "max"]) {
+                Inner::None
+            } else {
+                command_at(argv, at)
+            }
+        }
+        "chroot" => {
+            let opts = Opts {
+                long_flags: &["skip-chdir", "help", "version"],
+                long_with_arg: &["userspec", "groups"],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(1))
+        }
+        "unshare" => {
+            let opts = Opts {
+                flags: "cCfhimnprTuUV",
+                with_arg: "GRSw",
+                long_flags: &["fork", "map-root-user", "map-current-user", "map-auto", "keep-caps", "help", "version"],
+                long_with_arg: &[
+                    "propagation", "setgroups", "root", "wd", "setuid", "setgid", "map-user", "map-group",
+                    "map-users", "map-groups", "monotonic", "boottime", "load-interp",
+                ],
+                long_optional: &[
+                    "mount", "uts", "ipc", "net", "pid", "user", "cgroup", "time", "kill-child", "mount-proc",
+                    "mount-binfmt",
+                ],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(0))
+        }
+        "nsenter" => {
+            let opts = Opts {
+                flags: "acehFVZ",
+                with_arg: "GSt",
+                optional: "CimnprTuUw",
+                long_flags: &[
+                    "all", "preserve-credentials", "no-fork", "follow-context", "env", "join-cgroup", "keep-caps",
+                    "user-parent", "help", "version",
+                ],
+                long_with_arg: &["target", "setuid", "setgid", "wdns"],
+                long_optional: &["mount", "uts", "ipc", "net", "pid", "cgroup", "user", "time", "root", "wd"],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(0))
+        }
+        "setpriv" => {
+            let opts = Opts {
+                flags: "dhV",
+                long_flags: &[
+                    "dump", "nnp", "no-new-privs", "clear-groups", "keep-groups", "init-groups", "reset-env", "help",
+                    "version",
+                ],
+                long_with_arg: &[
+                    "inh-caps", "ambient-caps", "bounding-set", "ruid", "euid", "rgid", "egid", "reuid", "regid",
+                    "groups", "securebits", "pdeathsig", "selinux-label", "apparmor-profile", "landlock-access",
+                    "landlock-rule", "ptracer",
+                ],
+                ..Opts::default()
+            };
+            command_at(argv, opts.parse(argv, 1)?.operand(0))
+        }
+        "systemd-run" => command_at(argv, SYSTEMD_RUN.parse(argv, 1)?.operand(0)),
+        "strace" => command_at(argv, STRACE.parse(argv, 1)?.operand(0)),
+        "xargs" => command_at(argv, XARGS.parse(argv, 1)?.operand(0)),
+        "flock" => {
+            let opts = Opts {
+                flags: "eFhnosuVx",
+                with_arg: "cEw",
+                long_flags: &["shared", "exclusive", "unlock", "nonblock", "nb", "close", "no-fork", "verbose", "help", "version"],
+                long_with_arg: &["command", "conflict-exit-code", "timeout", "wait"],
+                ..Opts::default()
+            };
+            let parsed = opts.parse(argv, 1)?;
+            let after_file = parsed.operand(1);
+            if parsed.has(&["c", "command"]) {
+                scripts(option_scripts(&parsed, &["c", "command"])?)
+            } else if argv.get(after_file).is_some_and(|w| w.text == "-c" || w.text == "--command") {
+                script_at(argv, after_file + 1)?
+            } else {
+                command_at(argv, after_file)
+            }
+        }
+        "watch" => {
+            let opts = Opts {
+                flags: "bcCeghprtvwx",
+                with_arg: "nq",
+                optional: "d",
+                long_flags: &[
+                    "beep", "color", "no-color", "errexit", "chgexit", "precise", "no-rerun", "no-title", "no-wrap",
+                    "no-linewrap", "exec", "help", "version",
+                ],
+                long_with_arg: &["interval", "equexit"],
+                long_optional: &["differences"],
+                ..Opts::default()
+            };
+            let parsed = opts.parse(argv, 1)?;
+            let at = parsed.operand(0);
+            if parsed.has(&["x", "exec"]) {
+                command_at(argv, at)
+            } else {
+                let words = argv.get(at..).unwrap_or_default();
+                if words.iter().any(|w| w.dynamic) {
+                    return Err(unanalyzable("the command passed to watch is built at run time"));
+                }
+                let script: Vec<&str> = words.iter().map(|w| w.text.as_str()).collect();
+                Inner::Script(script.join(" "))
+            }
+        }
+        // `script -c` and `sg` run their argument with a shell.
+        "script" => {
+            let opts = Opts {
+                flags: "aefhqV",
+                with_arg: "BcEImoOT",
+                optional: "t",
+                long_flags: &["append", "return", "flush", "force", "quiet", "help", "version"],
+                long_with_arg: &[
+                    "log-io", "command", "echo", "log-in", "logging-format", "output-limit", "log-out", "log-timing",
+                ],
+                long_optional: &["timing"],
+                permute: true,
+            };
+            scripts(option_scripts(&opts.parse(argv, 1)?, &["c", "command"])?)
+        }
+        "sg" => {
+            // `sg [-] group [-c] command`
+            let mut at = 1;
+            if argv.get(at).is_some_and(|w| w.text == "-") {
+                at += 1;
+            }
+            at += 1;
+            if argv.get(at).is_some_and(|w| w.text == "-c") {
+                at += 1;
+            }
+            script_at(argv, at)?
+        }
+        // They run text or files that are only read at run time, or later.
+        "eval" | "source" | "." => return Err(unanalyzable(format!("'{}' is not supported", name))),
+        "trap" => {
+            let opts = Opts { flags: "lpP", ..Opts::default() };
+            if !opts.parse(argv, 1)?.operands.is_empty() {
+                return Err(unanalyzable("'trap' with a command is not supported"));
+            }
+            Inner::None
+        }
+        "su" | "runuser" => {
+            let parsed = SU.parse(argv, 1)?;
+            if let Some(shell) = parsed.values(&["s", "shell"]).last() {
+                if !SHELLS.contains(&basename(&shell.text)) || shell.dynamic {
+                    return Err(unanalyzable(format!("'{}' with a shell other than sh or bash is not supported", name)));
+                }
+            }
+            let mut found = option_scripts(&parsed, &["c", "command", "session-command"])?;
+            if name == "runuser" && parsed.has(&["u", "user"]) && found.is_empty() {
+                // `runuser -u user command`
+                return Ok(command_at(argv, parsed.operand(0)));
+            }
+            let mut operands = parsed.operands.iter().map(|&i| &argv[i]).peekable();
+            // `su - user` asks for a login shell.
+            operands.next_if(|w| w.text == "-");
+            // Words after the user name are handed to the user's shell.
+            operands.next();
+            let shell_args: Vec<Word> = std::iter::once(part_of(&argv[0], "sh")).chain(operands.cloned()).collect();
+            if let Inner::Script(script) = shell_script(&shell_args)? {
+                found.push(script);
+            }
+            scripts(found)
+        }
+        "find" => {
+            let mut ranges = Vec::new();
+            let mut i = 1;
+            while i < argv.len() {
+                if matches!(argv[i].text.as_str(), "-exec" | "-execdir" | "-ok" | "-okdir") {
+                    let from = i + 1;
+                    let mut to = from;
+                    while to < argv.len() && argv[to].text != ";" && argv[to].text != "+" {
+                        to += 1;
+                    }
+                    ranges.push((from, to));
+                    i = to;
+                }
+                i += 1;
+            }
+            Inner::Commands(ranges)
+        }
+        "fish" => {
+            let opts = Opts {
+                flags: "hilNnPv",
+                with_arg: "cCdDfop",
+                long_flags: &[
+                    "help", "interactive", "login", "no-config", "no-execute", "private", "version",
+                    "print-rusage-self", "print-debug-categories",
+                ],
+                long_with_arg: &[
+                    "command", "init-command", "debug", "debug-stack-frames", "features", "debug-output", "profile",
+                    "profile-startup",
+                ],
+                permute: true,
+                ..Opts::default()
+            };
+            scripts(option_scripts(&opts.parse(argv, 1)?, &["c", "command", "C", "init-command"])?)
+        }
+        shell if SHELLS.contains(&shell) => shell_script(argv)?,
+        _ => Inner::None,
+    };
+    Ok(inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn programs(cmd: &str) -> Vec<String> {
+        analyze(cmd).unwrap().into_iter().map(|i| i.name().to_string()).collect()
+    }
+
+    #[test]
+    fn finds_every_program() {
+        assert_eq!(programs("ls; rm -rf /"), ["ls", "rm"]);
+        assert_eq!(programs("echo x && sudo reboot"), ["echo", "sudo", "reboot"]);
+        assert_eq!(programs("cat a | grep b || (cd /tmp; /bin/rm x) &"), ["cat", "grep", "cd", "rm"]);
+        assert_eq!(programs("echo $(whoami) `id -u`"), ["whoami", "id", "echo"]);
+        assert_eq!(programs("FOO=1 BAR=\"a b\" make >out.log 2>&1 </dev/null"), ["make"]);
+        assert_eq!(programs("diff <(sort a) <(sort b)"), ["sort", "sort", "diff"]);
+        assert_eq!(programs("if test -f x; then rm x; fi"), ["test", "rm"]);
+        assert_eq!(programs("sudo -u app env A=1 nice -n 5 python3 x.py"), ["sudo", "env", "nice", "python3"]);
+        assert_eq!(programs("bash -lc 'ls && rm -rf /'"), ["bash", "ls", "rm"]);
+        assert_eq!(programs("bash -c -- 'rm -rf /'"), ["bash", "rm"]);
+        assert_eq!(programs("sh -c -e -o pipefail 'rm x' name"), ["sh", "rm"]);
+        assert_eq!(programs("sudo -iu root rm x"), ["sudo", "rm"]);
+        assert_eq!(programs("sudo -uroot rm x"), ["sudo", "rm"]);
+        assert_eq!(programs("env -iu HOME rm x"), ["env", "rm"]);
+        assert_eq!(programs("trap -p"), ["trap"]);
+        assert_eq!(programs("find . -name '*.tmp' -exec rm {} \\;"), ["find", "rm"]);
+        assert_eq!(programs("ls | xargs -n1 rm"), ["ls", "xargs", "rm"]);
+        assert_eq!(programs("timeout 5 ping host"), ["timeout", "ping"]);
+        assert_eq!(programs("# just a comment"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reads_wrapper_options_like_the_wrapper() {
+        assert_eq!(programs("bash --rcfile /dev/null -c 'rm -rf /'"), ["bash", "rm"]);
+        assert_eq!(programs("bash --init-file=/dev/null -O extglob -eo pipefail -c 'rm x'"), ["bash", "rm"]);
+        assert_eq!(programs("su -lc 'rm x'"), ["su", "rm"]);
+        assert_eq!(programs("su --command='rm x'"), ["su", "rm"]);
+        assert_eq!(programs("su --comm 'rm x' root"), ["su", "rm"]);
+        assert_eq!(programs("su - root -c 'rm x'"), ["su", "rm"]);
+        assert_eq!(programs("su root -- -c 'rm x'"), ["su", "rm"]);
+        assert_eq!(programs("runuser -u app -- rm x"), ["runuser", "rm"]);
+        assert_eq!(programs("fish --command 'rm x'"), ["fish", "rm"]);
+        assert_eq!(programs("fish -C 'rm x'"), ["fish", "rm"]);
+        assert_eq!(programs("script -qc 'rm x' /dev/null"), ["script", "rm"]);
+        assert_eq!(programs("script /dev/null -c 'rm x'"), ["script", "rm"]);
+        assert_eq!(programs("sg wheel 'rm x'"), ["sg", "rm"]);
+        assert_eq!(programs("sg - wheel -c 'rm x'"), ["sg", "rm"]);
+        assert_eq!(programs("unshare -rm --mount-proc rm x"), ["unshare", "rm"]);
+        assert_eq!(programs("nsenter -t 1 -m --net=/run/netns/x rm x"), ["nsenter", "rm"]);
+        assert_eq!(programs("pkexec --user root rm x"), ["pkexec", "rm"]);
+        assert_eq!(programs("setpriv --reuid 0 --init-groups rm x"), ["setpriv", "rm"]);
+        assert_eq!(programs("chrt -f 10 rm x"), ["chrt", "rm"]);
+        assert_eq!(programs("systemd-run --scope -p CPUQuota=5% rm x"), ["systemd-run", "rm"]);
+        assert_eq!(programs("strace -f -e trace=open rm x"), ["strace", "rm"]);
+        assert_eq!(programs("env - rm x"), ["env", "rm"]);
+        assert_eq!(programs("nice -5 rm x"), ["nice", "rm"]);
+    }
+
+    #[test]
+    fn keeps_arguments_without_quotes() {
+        let found = analyze("'/usr/bin/rm' -r\"f\" /").unwrap();
+        assert_eq!(found[0].program, "/usr/bin/rm");
+        assert_eq!(found[0].name(), "rm");
+        assert_eq!(found[0].args, ["-rf", "/"]);
+    }
+
+    #[test]
+    fn rejects_what_cannot_be_known() {
+        for cmd in [
+            "$(which rm) -rf /",
+            "$CMD x",
+            "/bin/r? -rf /",
+            "cat <<EOF\nx\nEOF",
+            "for f in *; do rm $f; done",
+            "sh -c \"$SCRIPT\"",
+            "echo 'unterminated",
+            "echo $((1 + 2))",
+            "f() { rm x; }; f",
+            "eval rm x",
+            "source ./x.sh",
+            ". ./x.sh",
+            "trap 'rm x' EXIT",
+            "bash --bogus -c 'rm x'",
+            "bash -Z -c 'rm x'",
+            "bash $FLAGS 'rm x'",
+            "sudo -Z rm x",
+            "sudo --pre rm x",
+            "su -s /usr/bin/python3 -c 'rm x'",
+            "script -c \"$SCRIPT\"",
+        ] {
+            assert!(analyze(cmd).is_err(), "{cmd}");
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::clock::Clock;
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Cooperative shutdown flag shared between the signal handler and the agent.
+///
+/// Once requested, the agent stops picking up new tasks; the command that is
+/// already running may continue until `grace` has elapsed, after which it is
+/// killed so its (partial) result can still be recorded.
+#[derive(Debug)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+    grace: Duration,
+    clock: Clock,
+}
+
+impl Shutdown {
+    pub fn new(grace: Duration) -> Self {
+        Self::with_clock(grace, Clock::system())
+    }
+
+    /// Measures the grace period on `clock` instead of wall time.
+    pub fn with_clock(grace: Duration, clock: Clock) -> Self {
+        Self { requested: AtomicBool::new(false), deadline: Mutex::new(None), grace, clock }
+    }
+
+    /// Marks shutdown as requested. Returns `true` on the first call only.
+    pub fn request(&self) -> bool {
+        let first = !self.requested.swap(true, Ordering::SeqCst);
+        if first {
+            *self.deadline.lock().unwrap() = Some(self.clock.now() + self.grace);
+        }
+        first
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// True once shutdown was requested and the grace period is over.
+    pub fn deadline_passed(&self) -> bool {
+        match *self.deadline.lock().unwrap() {
+            Some(d) => self.clock.now() >= d,
+            None => false,
+        }
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+}
+
+/// Routes SIGINT/SIGTERM (Ctrl+C / Ctrl+Break on Windows) to `shutdown`.
+/// A second signal while already shutting down exits immediately.
+pub fn install_signal_handler(shutdown: Arc<Shutdown>) -> Result<()> {
+    ctrlc::set_handler(move || {
+        if shutdown.request() {
+            eprintln!(
+                "[*] Shutdown requested - finishing the running command (up to {}s). Press Ctrl+C again to force quit.",
+                shutdown.grace().as_secs()
+            );
+        } else {
+            eprintln!("[!] Forced shutdown.");
+            std::process::exit(130);
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_sets_deadline_once() {
+        let s = Shutdown::new(Duration::from_secs(0));
+        assert!(!s.is_requested());
+        assert!(!s.deadline_passed());
+        assert!(s.request());
+        assert!(!s.request());
+        assert!(s.is_requested());
+        assert!(s.deadline_passed());
+
+        let clock = Clock::manual();
+        let long = Shutdown::with_clock(Duration::from_secs(3600), clock.clone());
+        long.request();
+        assert!(!long.deadline_passed());
+        clock.advance(Duration::from_secs(3600));
+        assert!(long.deadline_passed());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::notion::STATUS_MARKER;
+use crate::util::{node_name, os_name};
+use chrono::{DateTime, Local, SecondsFormat};
+use std::time::{Duration, Instant};
+
+const MAX_ERROR_CHARS: usize = 300;
+
+/// Health counters rendered into the `notionSSH-status` block.
+#[derive(Debug, Clone)]
+pub struct NodeStatus {
+    started: Instant,
+    pub last_poll: Option<DateTime<Local>>,
+    pub commands_executed: u64,
+    pub last_error: Option<String>,
+    pub queue_depth: usize,
+    /// Last time access.json or storage.json was reloaded, and what happened.
+    pub last_reload: Option<String>,
+}
+
+impl Default for NodeStatus {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            last_poll: None,
+            commands_executed: 0,
+            last_error: None,
+            queue_depth: 0,
+            last_reload: None,
+        }
+    }
+}
+
+impl NodeStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn record_error(&mut self, err: &anyhow::Error) {
+        let text = format!("{err:#}");
+        let text: String = text.chars().take(MAX_ERROR_CHARS).collect();
+        self.last_error = Some(format!("{} ({})", text, now_rfc3339()));
+    }
+
+    pub fn record_reload(&mut self, what: &str) {
+        self.last_reload = Some(format!("{} ({})", what, now_rfc3339()));
+    }
+
+    pub fn render_online(&self) -> String {
+        self.render("[+] NotionSSH online")
+    }
+
+    pub fn render_offline(&self) -> String {
+        self.render(&format!("[-] NotionSSH went offline at {}", now_rfc3339()))
+    }
+
+    fn render(&self, headline: &str) -> String {
+        let last_poll = self
+            .last_poll
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_else(|| "never".to_string());
+        format!(
+            "{} - node={} | os={} | version={}\n\
+             uptime={} | last_poll={} | executed={} | queue={}\n\
+             last_error={}\n\
+             last_reload={}\n\
+             # {}",
+            headline,
+            node_name(),
+            os_name(),
+            env!("CARGO_PKG_VERSION"),
+            format_uptime(self.uptime()),
+            last_poll,
+            self.commands_executed,
+            self.queue_depth,
+            self.last_error.as_deref().unwrap_or("none"),
+            self.last_reload.as_deref().unwrap_or("none"),
+            STATUS_MARKER
+        )
+    }
+}
+
+fn now_rfc3339() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub fn format_uptime(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (h, m, s) = (rem / 3600, (rem % 3600) / 60, rem % 60);
+    if days > 0 {
+        format!("{}d {:02}h {:02}m {:02}s", days, h, m, s)
+    } else {
+        format!("{:02}h {:02}m {:02}s", h, m, s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uptime_format() {
+        assert_eq!(format_uptime(Duration::from_secs(3723)), "01h 02m 03s");
+        assert_eq!(format_uptime(Duration::from_secs(90_061)), "1d 01h 01m 01s");
+    }
+
+    #[test]
+    fn render_contains_counters_and_marker() {
+        let mut st = NodeStatus::new();
+        st.commands_executed = 3;
+        st.queue_depth = 2;
+        st.record_error(&anyhow::anyhow!("boom"));
+        let text = st.render_online();
+        assert!(text.starts_with("[+] NotionSSH online"));
+        assert!(text.contains("executed=3"));
+        assert!(text.contains("queue=2"));
+        assert!(text.contains("last_poll=never"));
+        assert!(text.contains("last_error=boom"));
+        assert!(text.contains(&format!("version={}", env!("CARGO_PKG_VERSION"))));
+        assert!(text.ends_with(STATUS_MARKER));
+        assert!(st.render_offline().contains("went offline"));
+    }
+}
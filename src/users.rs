@@ -0,0 +1,172 @@
+use crate::access::{listed_user_id, lists_email, AccessFile, BOT_PREFIX, UNKNOWN_REQUESTER};
+use crate::notion::NotionApi;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserKind {
+    Person,
+    /// An integration, such as this agent.
+    Bot,
+}
+
+/// A Notion user as returned by `GET /users/{id}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotionUser {
+    pub id: String,
+    pub name: Option<String>,
+    /// Missing for bots, and for people when the integration may not read
+    /// emails.
+    pub email: Option<String>,
+    pub kind: UserKind,
+}
+
+impl NotionUser {
+    pub fn from_json(v: &Value) -> Option<Self> {
+        let kind = match v["type"].as_str()? {
+            "person" => UserKind::Person,
+            "bot" => UserKind::Bot,
+            _ => return None,
+        };
+        Some(Self {
+            id: v["id"].as_str()?.to_string(),
+            name: v["name"].as_str().map(|s| s.to_string()),
+            email: v["person"]["email"].as_str().map(|s| s.to_string()),
+            kind,
+        })
+    }
+}
+
+/// Notion users looked up recently, so that each requester is fetched once
+/// per `ttl` rather than once per block. Users that cannot be retrieved are
+/// remembered too; failed requests are not.
+#[derive(Debug)]
+pub struct UserCache {
+    ttl_secs: AtomicU64,
+    entries: Mutex<HashMap<String, (Instant, Option<NotionUser>)>>,
+    /// Keys already reported as listing a person under their email too.
+    warned: Mutex<HashSet<String>>,
+    /// The bot user the agent posts as.
+    own_id: OnceLock<String>,
+}
+
+impl UserCache {
+    /// A zero `ttl` disables caching.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl_secs: AtomicU64::new(ttl.as_secs()),
+            entries: Mutex::new(HashMap::new()),
+            warned: Mutex::new(HashSet::new()),
+            own_id: OnceLock::new(),
+        }
+    }
+
+    /// Applies to entries already cached as well.
+    pub fn set_ttl(&self, ttl: Duration) {
+        self.ttl_secs.store(ttl.as_secs(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self, api: &dyn NotionApi, id: &str) -> Result<Option<NotionUser>> {
+        if id.is_empty() {
+            return Ok(None);
+        }
+        let ttl = Duration::from_secs(self.ttl_secs.load(Ordering::Relaxed));
+        if let Some((at, user)) = self.entries.lock().unwrap_or_else(|e| e.into_inner()).get(id) {
+            if at.elapsed() < ttl {
+                return Ok(user.clone());
+            }
+        }
+        // Not locked while fetching, so a slow lookup does not hold up
+        // workers asking about other users.
+        let user = api.retrieve_user(id)?.as_ref().and_then(NotionUser::from_json);
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).insert(id.to_string(), (Instant::now(), user.clone()));
+        Ok(user)
+    }
+
+    /// ID of the bot user the agent itself posts as, asked once.
+    pub fn own_id(&self, api: &dyn NotionApi) -> Result<&str> {
+        if let Some(id) = self.own_id.get() {
+            return Ok(id);
+        }
+        let me = api.retrieve_me()?;
+        let id = me["id"].as_str().ok_or_else(|| anyhow!("Notion did not return the integration's user id"))?;
+        Ok(self.own_id.get_or_init(|| id.to_string()))
+    }
+
+    /// The name `id` goes by in permission checks and logs: the user ID as
+    /// written in access.json when listed there, otherwise the email,
+    /// "bot:<id>" for bots, or "unknown". Fails when Notion cannot be asked,
+    /// so that a lookup error is not taken for an unknown user.
+    pub fn requester(&self, api: &dyn NotionApi, af: &AccessFile, id: &str) -> Result<String> {
+        if let Some(key) = listed_user_id(af, id) {
+            self.warn_if_listed_twice(api, af, id, key);
+            return Ok(key.to_string());
+        }
+        Ok(match self.get(api, id)? {
+            Some(NotionUser { email: Some(email), kind: UserKind::Person, .. }) => email,
+            Some(NotionUser { kind: UserKind::Bot, id, .. }) => format!("{}{}", BOT_PREFIX, id),
+            _ => UNKNOWN_REQUESTER.to_string(),
+        })
+    }
+
+    /// Entries under a person's email do not apply once they are listed by
+    /// ID. access.json alone cannot tell that both name the same person, so
+    /// this is reported the first time they are seen.
+    fn warn_if_listed_twice(&self, api: &dyn NotionApi, af: &AccessFile, id: &str, key: &str) {
+        let Ok(Some(NotionUser { email: Some(email), .. })) = self.get(api, id) else { return };
+        if lists_email(af, &email) && self.warned.lock().unwrap_or_else(|e| e.into_inner()).insert(key.to_string()) {
+            eprintln!(
+                "[!] access.json: '{}' and '{}' are the same Notion user; only the entries under '{}' apply",
+                key, email, key
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeNotion;
+    use serde_json::json;
+
+    #[test]
+    fn requesters_by_email_id_or_kind_and_cached() {
+        let fake = FakeNotion::new();
+        fake.add_user("u1", "u@example.com");
+        fake.add_raw_user(json!({"object": "user", "id": "b1", "type": "bot", "name": "CI", "bot": {}}));
+        fake.add_raw_user(json!({"object": "user", "id": "g1", "type": "person", "name": "Guest", "person": {}}));
+        let mut af = AccessFile::default();
+        af.emails.insert("6F1C2B9E-0000-4000-8000-00000000ABCD".into(), "default".into());
+        af.emails.insert("AB-CD".into(), "default".into());
+        let users = UserCache::new(Duration::from_secs(60));
+        let requester = |id: &str| users.requester(&fake, &af, id).unwrap();
+
+        assert_eq!(requester("u1"), "u@example.com");
+        assert_eq!(requester("b1"), "bot:b1");
+        assert_eq!(requester("g1"), "unknown");
+        assert_eq!(requester("missing"), "unknown");
+        assert_eq!(requester("6f1c2b9e00004000800000000000abcd"), "6F1C2B9E-0000-4000-8000-00000000ABCD");
+        assert_eq!(requester("abcd"), "unknown");
+        assert_eq!(users.get(&fake, "b1").unwrap().unwrap().name.as_deref(), Some("CI"));
+
+        fake.add_user("u1", "changed@example.com");
+        assert_eq!(requester("u1"), "u@example.com");
+        users.set_ttl(Duration::ZERO);
+        assert_eq!(requester("u1"), "changed@example.com");
+    }
+
+    #[test]
+    fn failed_lookups_are_errors_and_not_cached() {
+        let fake = FakeNotion::new();
+        fake.add_user("u1", "u@example.com");
+        let users = UserCache::new(Duration::from_secs(60));
+
+        fake.fail_user_lookups(1);
+        assert!(users.requester(&fake, &AccessFile::default(), "u1").is_err());
+        assert_eq!(users.requester(&fake, &AccessFile::default(), "u1").unwrap(), "u@example.com");
+    }
+}
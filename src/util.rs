@@ -6,7 +6,7 @@ use url::Url;
 pub fn extract_page_id(page_url: &str) -> Result<String> {
     let mut s = page_url.trim().to_string();
     if let Ok(url) = Url::parse(page_url) {
-        if let Some(seg) = url.path_segments().and_then(|it| it.last()) {
+        if let Some(seg) = url.path_segments().and_then(|mut it| it.next_back()) {
             s = seg.to_string();
         }
     }
@@ -43,11 +43,10 @@ pub fn node_name() -> String {
     if let Ok(s) = env::var("HOSTNAME") {
         return s;
     }
-    let name = hostname::get()
+    hostname::get()
         .ok()
         .and_then(|os| os.into_string().ok())
-        .unwrap_or_else(|| "unknown".to_string());
-    name
+        .unwrap_or_else(|| "unknown".to_string())
 }
 
 pub fn os_name() -> String {
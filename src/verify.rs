@@ -77,7 +77,7 @@ fn tls_verify_and_pin(host: &str, port: u16, enable_ca_pubkey: bool) -> Result<(
 
     // Build rustls config with CA roots -> this performs chain verification during handshake.
     let mut roots = RootCertStore::empty();
-    roots.add_server_trust_anchors(TLS_SERVER_ROOTS.iter().map(|ta| {
+    roots.add_trust_anchors(TLS_SERVER_ROOTS.iter().map(|ta| {
         OwnedTrustAnchor::from_subject_spki_name_constraints(
             ta.subject,
             ta.spki,
@@ -298,8 +298,8 @@ fn write_ca_json_from_verify(verify_dir: &Path, out_json: &Path) -> Result<bool>
 
     if verify_file.exists() {
         let (_leaf, ca_der, ca_spki) = load_verify_format(&verify_file)?;
-        ca_der_list.extend(ca_der.into_iter());
-        ca_spki_list.extend(ca_spki.into_iter());
+        ca_der_list.extend(ca_der);
+        ca_spki_list.extend(ca_spki);
     }
     // Fallback: scan directory for CA pin files if verify file missing
     if ca_der_list.is_empty() && ca_spki_list.is_empty() && verify_dir.exists() {